@@ -149,7 +149,8 @@ export async function placeOrder(
       market,
       owner,
       ownerTokenAccount,
-      tokenProgram: TOKEN_PROGRAM_ID,
+      baseTokenProgram: TOKEN_PROGRAM_ID,
+      quoteTokenProgram: TOKEN_PROGRAM_ID,
     })
     .rpc({ skipPreflight: false, commitment: 'confirmed' });
 
@@ -172,7 +173,8 @@ export async function cancelOrder(
       market,
       owner,
       ownerTokenAccount,
-      tokenProgram: TOKEN_PROGRAM_ID,
+      baseTokenProgram: TOKEN_PROGRAM_ID,
+      quoteTokenProgram: TOKEN_PROGRAM_ID,
     })
     .rpc({ skipPreflight: false, commitment: 'confirmed' });
 
@@ -11,6 +11,9 @@ const PROGRAM_ID = new PublicKey('CMy5ru8L5nwnn4RK8TZJiCLs4FVkouV2PKPnuPCLFedB')
 const BASE_TOKEN_MINT = new PublicKey('yXJUy2a1YgKDJ5CfngRN7djwX3Dtbv85f9jUFCgutdj');
 const QUOTE_TOKEN_MINT = new PublicKey('4eYgX7VZj4eQ5Vf5MbmzCgAwcbhkP1rSMhR5jZmdZN5H');
 
+// Orders closed per cancel_all_orders transaction
+const ORDERS_PER_TX = 10;
+
 async function main() {
   console.log('🗑️  Closing all old orders...\n');
 
@@ -43,16 +46,13 @@ async function main() {
 
   console.log(`Market: ${marketPDA.toBase58()}\n`);
 
-  // Fetch all order accounts (same method as solver)
-  console.log('Fetching all order accounts...');
-  const allOrdersRaw = await (program.account as any).order.all();
-
-  // Filter by market
-  const marketOrders = allOrdersRaw.filter((acc: any) => {
-    const order = acc.account;
-    const orderMarket = new PublicKey(order.market);
-    return orderMarket.equals(marketPDA);
-  });
+  // The trader account indexes every order this wallet has not closed yet
+  const [traderPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from('trader'), marketPDA.toBuffer(), wallet.publicKey.toBuffer()],
+    program.programId
+  );
+  const trader = await (program.account as any).traderAccount.fetchNullable(traderPDA);
+  const marketOrders: PublicKey[] = trader ? trader.openOrders : [];
 
   console.log(`Found ${marketOrders.length} order accounts for this market\n`);
 
@@ -86,27 +86,33 @@ async function main() {
     program.programId
   );
 
-  // Close each order
+  // Cancel and close the orders in chunks that fit in one transaction; open
+  // orders are refunded in one transfer per mint, finished ones just closed
   let closed = 0;
-  for (const { publicKey } of marketOrders) {
+  for (let i = 0; i < marketOrders.length; i += ORDERS_PER_TX) {
+    const chunk = marketOrders.slice(i, i + ORDERS_PER_TX);
     try {
-      console.log(`Closing order ${publicKey.toBase58()}...`);
+      console.log(`Closing orders ${i + 1}-${i + chunk.length}...`);
       const tx = await program.methods
-        .cancelOrder()
+        .cancelAllOrders()
         .accounts({
-          order: publicKey,
           owner: wallet.publicKey,
           market: marketPDA,
+          traderAccount: traderPDA,
           userBaseAccount: userBaseAccount,
           userQuoteAccount: userQuoteAccount,
           baseVault: baseVaultPDA,
           quoteVault: quoteVaultPDA,
-          tokenProgram: TOKEN_PROGRAM_ID,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
         })
+        .remainingAccounts(
+          chunk.map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }))
+        )
         .rpc();
 
       console.log(`  ✅ Closed! TX: ${tx.slice(0, 8)}...`);
-      closed++;
+      closed += chunk.length;
 
       // Wait a bit to avoid rate limiting
       await new Promise(resolve => setTimeout(resolve, 500));
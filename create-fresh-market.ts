@@ -153,7 +153,8 @@ async function createFreshMarket() {
         baseVault: baseVaultPDA,
         quoteVault: quoteVaultPDA,
         admin: wallet.publicKey,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .rpc();
@@ -3,8 +3,17 @@
 //! This module contains the real Arcium/Arcis encrypted instruction that runs
 //! inside the MPC MXE (Multi-Party Execution Environment).
 //!
-//! The encrypted instruction delegates to the canonical plain matcher in matching.rs,
-//! ensuring algorithm consistency.
+//! The encrypted instruction runs the shared kernel from kernel.rs, the same
+//! code `matching::match_orders` executes over plain integers.
+//!
+//! # Batch capacities
+//!
+//! MPC cost grows with the size of the fixed arrays, so the circuit is compiled
+//! once per entry in [`BATCH_CAPACITIES`]. Each variant lives in its own module
+//! (`batch_8`, `batch_32`, ...) and exposes its own instruction
+//! (`match_orders_mpc_8`, `match_orders_mpc_32`, ...), which Arcium registers as
+//! a separate computation definition. The relayer picks the cheapest one with
+//! [`capacity_for`].
 //!
 //! # Compilation
 //!
@@ -12,321 +21,193 @@
 //! 1. Add arcis-imports dependency to Cargo.toml (path, git, or crates.io)
 //! 2. Build with: `cargo build --features arcis` or `arcium build`
 
-#[cfg(feature = "arcis")]
-use arcis_imports::*;
-
-// Types are defined inside the #[encrypted] module below
-// Re-export them here for use outside the module
-#[cfg(feature = "arcis")]
-pub use circuits::{OrdersInput, PlainOrder, MatchResult, PlainFill};
-
-#[cfg(not(feature = "arcis"))]
-#[derive(Debug, Clone, Copy)]
-pub struct PlainOrder {
-    pub index: u32,
-    pub side: u8,
-    pub amount_in: u64,
-    pub filled_amount_in: u64,
-    pub min_amount_out: u64,
-    pub created_at: i64,
-    pub status: u8,
-}
-
-#[cfg(not(feature = "arcis"))]
-#[derive(Debug, Clone, Copy)]
-pub struct PlainFill {
-    pub order_index: u32,
-    pub counterparty_index: u32,
-    pub amount_in: u64,
-    pub amount_out: u64,
-}
-
-#[cfg(not(feature = "arcis"))]
-#[derive(Debug, Clone)]
-pub struct OrdersInput {
-    pub orders: [PlainOrder; 100],
-    pub count: u32,
-}
-
-#[cfg(not(feature = "arcis"))]
-#[derive(Debug, Clone)]
-pub struct MatchResult {
-    pub fills: [PlainFill; 100],
-    pub count: u32,
-}
-
-/// Arcis encrypted module containing the MPC instruction
+/// Generates one matching circuit variant whose order and fill arrays hold
+/// `$capacity` entries.
+///
+/// The capacity is the only parameter: every array length and loop bound in
+/// the circuit body is derived from it.
+///
+/// The matching kernel comes from `with_match_orders_kernel!` (see kernel.rs)
+/// and is handed to the `@variant` arm as tokens. That arm pastes it into both
+/// the `#[encrypted]` module and the plaintext simulator, so Arcis only ever
+/// sees expanded code and the simulator runs exactly what the MXE runs.
 ///
 /// IMPORTANT: The #[encrypted] module has strict limitations:
 /// - Only `use arcis_imports::*` is allowed
 /// - No external imports or crate paths
 /// - No match statements
 /// - Types must be defined within or be simple ArcisType types
-///
-/// Since we cannot call external functions, we need to either:
-/// 1. Reimplement the matching logic here (loses single source of truth)
-/// 2. Use a bridge mechanism (if Arcium provides one)
-/// 3. Keep matching outside and only do encryption/decryption here
-#[cfg(feature = "arcis")]
-#[encrypted]
-mod circuits {
-    use arcis_imports::*;
-
-    // Define types inside the encrypted module
-    // NOTE: Vec doesn't implement ArcisType, so we use fixed-size arrays
-    pub struct OrdersInput {
-        pub orders: [PlainOrder; 100],
-        pub count: u32,  // Actual number of orders
-    }
+macro_rules! match_orders_circuit {
+    (@variant $variant:ident, $instruction:ident, $capacity:tt, $($kernel:tt)*) => {
+        #[doc = concat!(
+            "Matching circuit compiled for batches of up to ",
+            stringify!($capacity),
+            " orders."
+        )]
+        pub mod $variant {
+            #[cfg(feature = "arcis")]
+            use arcis_imports::*;
+
+            // Types are defined inside the circuits module below
+            // Re-export them here for use outside the module
+            pub use self::circuits::{$instruction, MatchResult, OrdersInput, PlainFill, PlainOrder};
+
+            /// Maximum number of orders (and fills) this variant can hold
+            pub const CAPACITY: usize = $capacity;
+
+            #[cfg(feature = "arcis")]
+            #[encrypted]
+            mod circuits {
+                use arcis_imports::*;
+
+                // Define types inside the encrypted module
+                // NOTE: Vec doesn't implement ArcisType, so we use fixed-size arrays
+                pub struct OrdersInput {
+                    pub orders: [PlainOrder; $capacity],
+                    pub count: u32,  // Actual number of orders
+                    pub timestamp: i64,  // Batch timestamp for expiry checks
+                }
 
-    #[derive(Copy, Clone)]
-    pub struct PlainOrder {
-        pub index: u32,
-        pub side: u8,
-        pub amount_in: u64,
-        pub filled_amount_in: u64,
-        pub min_amount_out: u64,
-        pub created_at: i64,
-        pub status: u8,
-    }
+                #[derive(Copy, Clone)]
+                pub struct PlainOrder {
+                    pub index: u32,
+                    pub side: u8,
+                    pub amount_in: u64,
+                    pub filled_amount_in: u64,
+                    pub min_amount_out: u64,
+                    pub created_at: i64,
+                    pub expires_at: i64,
+                    pub time_in_force: u8,
+                    pub status: u8,
+                }
 
-    pub struct MatchResult {
-        pub fills: [PlainFill; 100],
-        pub count: u32,  // Actual number of fills
-    }
+                pub struct MatchResult {
+                    pub fills: [PlainFill; $capacity],
+                    pub count: u32,  // Actual number of fills
+                }
 
-    #[derive(Copy, Clone)]
-    pub struct PlainFill {
-        pub order_index: u32,
-        pub counterparty_index: u32,
-        pub amount_in: u64,
-        pub amount_out: u64,
-    }
+                #[derive(Copy, Clone)]
+                pub struct PlainFill {
+                    pub order_index: u32,
+                    pub counterparty_index: u32,
+                    pub amount_in: u64,
+                    pub amount_out: u64,
+                }
 
-    // Let the #[encrypted] macro auto-derive ArcisType for all structs
-    // Arrays implement ArcisType, so this should work
+                // Let the #[encrypted] macro auto-derive ArcisType for all structs
+                // Arrays implement ArcisType, so this should work
 
-    /// Encrypted matching instruction
-    ///
-    /// This implements the canonical FIFO greedy matching algorithm
-    /// as described in MATCHING_SPEC.md. Due to Arcis #[encrypted] module
-    /// limitations (no external imports), the algorithm is reimplemented here.
-    ///
-    /// IMPORTANT: This must stay in sync with matching::match_orders.
-    /// Any algorithm changes must be applied to both implementations.
-    #[instruction]
-    pub fn match_orders_mpc(
-        input_ctxt: Enc<Shared, OrdersInput>
-    ) -> Enc<Shared, MatchResult> {
-        // Decrypt the input
-        let input = input_ctxt.to_arcis();
-
-        // Filter to OPEN orders only (status == 0)
-        let mut open_orders = [PlainOrder {
-            index: 0,
-            side: 0,
-            amount_in: 0,
-            filled_amount_in: 0,
-            min_amount_out: 0,
-            created_at: 0,
-            status: 0,
-        }; 100];
-        let mut open_count: u32 = 0;
-
-        for i in 0..100 {
-            if i < input.count {
-                let order = input.orders[i as usize];
-                if order.status == 0 {  // OrderStatus::Open
-                    open_orders[open_count as usize] = order;
-                    open_count = open_count + 1;
+                $($kernel)*
+
+                /// Encrypted matching instruction
+                #[instruction]
+                pub fn $instruction(
+                    input_ctxt: Enc<Shared, OrdersInput>
+                ) -> Enc<Shared, MatchResult> {
+                    // Decrypt the input
+                    let input = input_ctxt.to_arcis();
+
+                    let result = match_orders_kernel(input);
+
+                    // Encrypt and return
+                    input_ctxt.owner.from_arcis(result)
                 }
             }
-        }
 
-        // Split into bids and asks
-        let mut bids = [PlainOrder {
-            index: 0,
-            side: 0,
-            amount_in: 0,
-            filled_amount_in: 0,
-            min_amount_out: 0,
-            created_at: 0,
-            status: 0,
-        }; 100];
-        let mut bid_count: u32 = 0;
+            /// Plaintext simulator of the circuit
+            ///
+            /// Runs the exact kernel tokens compiled into the encrypted module,
+            /// so tests can compare it fill-for-fill with the independent
+            /// reference matcher in matching's tests.
+            #[cfg(not(feature = "arcis"))]
+            #[allow(
+                clippy::assign_op_pattern,
+                clippy::collapsible_if,
+                clippy::manual_swap,
+                clippy::needless_range_loop
+            )]
+            mod circuits {
+                #[derive(Debug, Clone, Copy)]
+                pub struct PlainOrder {
+                    pub index: u32,
+                    pub side: u8,
+                    pub amount_in: u64,
+                    pub filled_amount_in: u64,
+                    pub min_amount_out: u64,
+                    pub created_at: i64,
+                    pub expires_at: i64,
+                    pub time_in_force: u8,
+                    pub status: u8,
+                }
 
-        let mut asks = [PlainOrder {
-            index: 0,
-            side: 0,
-            amount_in: 0,
-            filled_amount_in: 0,
-            min_amount_out: 0,
-            created_at: 0,
-            status: 0,
-        }; 100];
-        let mut ask_count: u32 = 0;
-
-        for i in 0..100 {
-            if i < open_count {
-                let order = open_orders[i as usize];
-                if order.side == 0 {  // OrderSide::Bid
-                    bids[bid_count as usize] = order;
-                    bid_count = bid_count + 1;
-                } else {  // OrderSide::Ask (side == 1)
-                    asks[ask_count as usize] = order;
-                    ask_count = ask_count + 1;
+                #[derive(Debug, Clone, Copy)]
+                pub struct PlainFill {
+                    pub order_index: u32,
+                    pub counterparty_index: u32,
+                    pub amount_in: u64,
+                    pub amount_out: u64,
                 }
-            }
-        }
 
-        // Sort both by created_at ascending (FIFO) - bubble sort
-        // Bubble sort bids
-        for i in 0..100 {
-            if i < bid_count {
-                for j in 0..100 {
-                    if j < bid_count - 1 - i {
-                        if bids[j as usize].created_at > bids[(j + 1) as usize].created_at {
-                            let temp = bids[j as usize];
-                            bids[j as usize] = bids[(j + 1) as usize];
-                            bids[(j + 1) as usize] = temp;
-                        }
-                    }
+                #[derive(Debug, Clone)]
+                pub struct OrdersInput {
+                    pub orders: [PlainOrder; $capacity],
+                    pub count: u32,
+                    pub timestamp: i64,
                 }
-            }
-        }
 
-        // Bubble sort asks
-        for i in 0..100 {
-            if i < ask_count {
-                for j in 0..100 {
-                    if j < ask_count - 1 - i {
-                        if asks[j as usize].created_at > asks[(j + 1) as usize].created_at {
-                            let temp = asks[j as usize];
-                            asks[j as usize] = asks[(j + 1) as usize];
-                            asks[(j + 1) as usize] = temp;
-                        }
-                    }
+                #[derive(Debug, Clone)]
+                pub struct MatchResult {
+                    pub fills: [PlainFill; $capacity],
+                    pub count: u32,
                 }
-            }
-        }
 
-        // Track remaining size per order
-        let mut remaining = [0u64; 100];
-        for i in 0..100 {
-            if i < open_count {
-                let order = open_orders[i as usize];
-                let rem = if order.amount_in >= order.filled_amount_in {
-                    order.amount_in - order.filled_amount_in
-                } else {
-                    0
-                };
-                // Store at order.index position for fast lookup
-                remaining[order.index as usize] = rem;
-            }
-        }
+                $($kernel)*
 
-        // Greedy batch matcher
-        let mut fills = [PlainFill {
-            order_index: 0,
-            counterparty_index: 0,
-            amount_in: 0,
-            amount_out: 0,
-        }; 100];
-        let mut fill_count: u32 = 0;
-
-        let mut bid_idx: u32 = 0;
-        let mut ask_idx: u32 = 0;
-
-        // Use a fixed iteration count to avoid while loops
-        // 100 iterations should be enough for up to 100 orders
-        for _ in 0..100 {
-            if bid_idx < bid_count && ask_idx < ask_count {
-                let bid = bids[bid_idx as usize];
-                let ask = asks[ask_idx as usize];
-
-                let remaining_bid = remaining[bid.index as usize];
-                let remaining_ask = remaining[ask.index as usize];
-
-                // Skip if either order is fully filled
-                if remaining_bid == 0 {
-                    bid_idx = bid_idx + 1;
-                } else if remaining_ask == 0 {
-                    ask_idx = ask_idx + 1;
-                } else {
-                    // Match amount is the minimum of remaining sizes
-                    let match_amount = if remaining_bid < remaining_ask {
-                        remaining_bid
-                    } else {
-                        remaining_ask
-                    };
-
-                    // Basic guards
-                    if match_amount == 0 {
-                        bid_idx = bid_idx + 1;
-                        ask_idx = ask_idx + 1;
-                    } else if match_amount < bid.min_amount_out {
-                        // This bid can't be filled with current ask, skip to next bid
-                        bid_idx = bid_idx + 1;
-                    } else {
-                        // Create fill
-                        fills[fill_count as usize] = PlainFill {
-                            order_index: bid.index,
-                            counterparty_index: ask.index,
-                            amount_in: match_amount,
-                            amount_out: match_amount,
-                        };
-                        fill_count = fill_count + 1;
-
-                        // Update remaining amounts
-                        let new_bid_remaining = remaining_bid - match_amount;
-                        let new_ask_remaining = remaining_ask - match_amount;
-                        remaining[bid.index as usize] = new_bid_remaining;
-                        remaining[ask.index as usize] = new_ask_remaining;
-
-                        // Move to next order if fully filled
-                        if new_bid_remaining == 0 {
-                            bid_idx = bid_idx + 1;
-                        }
-                        if new_ask_remaining == 0 {
-                            ask_idx = ask_idx + 1;
-                        }
-                    }
+                /// Plaintext matching instruction
+                pub fn $instruction(input: OrdersInput) -> MatchResult {
+                    match_orders_kernel(input)
                 }
             }
         }
+    };
+    ($variant:ident, $instruction:ident, $capacity:tt) => {
+        with_match_orders_kernel!($capacity, match_orders_circuit! {
+            @variant $variant, $instruction, $capacity,
+        });
+    };
+}
 
-        let result = MatchResult {
-            fills,
-            count: fill_count,
-        };
+match_orders_circuit!(batch_8, match_orders_mpc_8, 8);
+match_orders_circuit!(batch_32, match_orders_mpc_32, 32);
+match_orders_circuit!(batch_128, match_orders_mpc_128, 128);
+match_orders_circuit!(batch_512, match_orders_mpc_512, 512);
 
-        // Encrypt and return
-        input_ctxt.owner.from_arcis(result)
-    }
-}
+/// Order capacities the matching circuit is compiled for, smallest first
+pub const BATCH_CAPACITIES: [usize; 4] = [
+    batch_8::CAPACITY,
+    batch_32::CAPACITY,
+    batch_128::CAPACITY,
+    batch_512::CAPACITY,
+];
 
-// Re-export the instruction from the encrypted module
-#[cfg(feature = "arcis")]
-pub use circuits::match_orders_mpc;
-
-#[cfg(not(feature = "arcis"))]
-#[allow(dead_code)]
-pub fn match_orders_mpc(_input: OrdersInput) -> MatchResult {
-    MatchResult {
-        fills: [PlainFill {
-            order_index: 0,
-            counterparty_index: 0,
-            amount_in: 0,
-            amount_out: 0,
-        }; 100],
-        count: 0,
-    }
+/// Returns the smallest compiled capacity that fits `order_count` orders
+///
+/// `None` means the book is larger than the biggest variant and must be split
+/// across several computations.
+pub fn capacity_for(order_count: usize) -> Option<usize> {
+    BATCH_CAPACITIES
+        .iter()
+        .copied()
+        .find(|&capacity| order_count <= capacity)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::batch_128::{OrdersInput, PlainOrder};
     use super::*;
-    use crate::matching::{self, Order, OrderSide, OrderStatus};
+    use crate::matching::tests::{reference_match_orders, NOW};
+    use crate::matching::{self, Order, OrderSide, OrderStatus, TimeInForce};
+    use proptest::prelude::*;
 
     /// Helper to convert PlainOrder to canonical Order
     fn plain_to_order(plain: &PlainOrder) -> Order {
@@ -337,11 +218,18 @@ mod tests {
             filled_amount_in: plain.filled_amount_in,
             min_amount_out: plain.min_amount_out,
             created_at: plain.created_at,
+            expires_at: plain.expires_at,
+            time_in_force: match plain.time_in_force {
+                1 => TimeInForce::ImmediateOrCancel,
+                2 => TimeInForce::FillOrKill,
+                _ => TimeInForce::GoodTillCancel,
+            },
             status: match plain.status {
                 0 => OrderStatus::Open,
                 1 => OrderStatus::PartiallyFilled,
                 2 => OrderStatus::Filled,
                 3 => OrderStatus::Cancelled,
+                4 => OrderStatus::Expired,
                 _ => OrderStatus::Open,
             },
         }
@@ -359,21 +247,32 @@ mod tests {
             filled_amount_in: order.filled_amount_in,
             min_amount_out: order.min_amount_out,
             created_at: order.created_at,
+            expires_at: order.expires_at,
+            time_in_force: match order.time_in_force {
+                TimeInForce::GoodTillCancel => 0,
+                TimeInForce::ImmediateOrCancel => 1,
+                TimeInForce::FillOrKill => 2,
+            },
             status: match order.status {
                 OrderStatus::Open => 0,
                 OrderStatus::PartiallyFilled => 1,
                 OrderStatus::Filled => 2,
                 OrderStatus::Cancelled => 3,
+                OrderStatus::Expired => 4,
             },
         }
     }
 
-    /// Test helper that runs both plain and "encrypted" versions and compares results
-    /// Since we can't actually run encrypted code in tests, we simulate by using
-    /// the non-encrypted types but going through the same conversion path
-    fn test_matching_equivalence(orders: &[Order]) {
-        // Run plain matcher
-        let plain_result = matching::match_orders(orders);
+    /// Test helper that runs the reference matcher and the circuit simulator
+    /// and asserts they produce the same fills
+    ///
+    /// The simulator executes the same kernel as the encrypted instruction,
+    /// only without the to_arcis/from_arcis encryption boundary. It is checked
+    /// against `reference_match_orders` rather than `matching::match_orders`,
+    /// which expands the same kernel and would agree with it by construction.
+    fn test_matching_equivalence(orders: &[Order], timestamp: i64) {
+        // Run the independent reference matcher
+        let plain_result = reference_match_orders(orders, timestamp);
 
         // Convert to PlainOrder format
         let mut plain_orders = [PlainOrder {
@@ -383,8 +282,10 @@ mod tests {
             filled_amount_in: 0,
             min_amount_out: 0,
             created_at: 0,
+            expires_at: 0,
+            time_in_force: 0,
             status: 0,
-        }; 100];
+        }; batch_128::CAPACITY];
 
         for (i, order) in orders.iter().enumerate() {
             plain_orders[i] = order_to_plain(order);
@@ -400,16 +301,87 @@ mod tests {
             assert_eq!(order.filled_amount_in, reconstructed.filled_amount_in);
             assert_eq!(order.min_amount_out, reconstructed.min_amount_out);
             assert_eq!(order.created_at, reconstructed.created_at);
+            assert_eq!(order.expires_at, reconstructed.expires_at);
+            assert_eq!(order.time_in_force, reconstructed.time_in_force);
             assert_eq!(order.status, reconstructed.status);
         }
 
-        // The real test would be:
-        // let input = OrdersInput { orders: plain_orders, count: orders.len() as u32 };
-        // let mpc_result = circuits::match_orders_mpc(input);
-        // assert_eq!(plain_result.fills.len(), mpc_result.count as usize);
-        // for i in 0..plain_result.fills.len() { ... }
+        let input = OrdersInput {
+            orders: plain_orders,
+            count: orders.len() as u32,
+            timestamp,
+        };
+        let mpc_result = batch_128::match_orders_mpc_128(input);
+
+        assert_eq!(plain_result.fills.len(), mpc_result.count as usize);
+        for (i, plain_fill) in plain_result.fills.iter().enumerate() {
+            let mpc_fill = &mpc_result.fills[i];
+            assert_eq!(plain_fill.order_index, mpc_fill.order_index, "fill {i}");
+            assert_eq!(plain_fill.counterparty_index, mpc_fill.counterparty_index, "fill {i}");
+            assert_eq!(plain_fill.amount_in, mpc_fill.amount_in, "fill {i}");
+            assert_eq!(plain_fill.amount_out, mpc_fill.amount_out, "fill {i}");
+        }
+    }
 
-        println!("Plain matcher produced {} fills", plain_result.fills.len());
+    /// Runs one variant's circuit simulator over `orders`, returning each
+    /// fill as (order_index, counterparty_index, amount_in, amount_out)
+    macro_rules! simulate_variant {
+        ($variant:ident, $instruction:ident, $orders:expr, $timestamp:expr) => {{
+            let mut plain_orders = [$variant::PlainOrder {
+                index: 0,
+                side: 0,
+                amount_in: 0,
+                filled_amount_in: 0,
+                min_amount_out: 0,
+                created_at: 0,
+                expires_at: 0,
+                time_in_force: 0,
+                status: 0,
+            }; $variant::CAPACITY];
+            for (i, order) in $orders.iter().enumerate() {
+                let plain = order_to_plain(order);
+                plain_orders[i] = $variant::PlainOrder {
+                    index: plain.index,
+                    side: plain.side,
+                    amount_in: plain.amount_in,
+                    filled_amount_in: plain.filled_amount_in,
+                    min_amount_out: plain.min_amount_out,
+                    created_at: plain.created_at,
+                    expires_at: plain.expires_at,
+                    time_in_force: plain.time_in_force,
+                    status: plain.status,
+                };
+            }
+
+            let result = $variant::$instruction($variant::OrdersInput {
+                orders: plain_orders,
+                count: $orders.len() as u32,
+                timestamp: $timestamp,
+            });
+            result.fills[..result.count as usize]
+                .iter()
+                .map(|f| (f.order_index, f.counterparty_index, f.amount_in, f.amount_out))
+                .collect::<Vec<_>>()
+        }};
+    }
+
+    /// Asserts the reference matcher and the `capacity`-order circuit
+    /// simulator produce the same fills
+    fn test_variant_equivalence(orders: &[Order], timestamp: i64, capacity: usize) {
+        assert!(orders.len() <= capacity);
+        let plain: Vec<_> = reference_match_orders(orders, timestamp)
+            .fills
+            .iter()
+            .map(|f| (f.order_index, f.counterparty_index, f.amount_in, f.amount_out))
+            .collect();
+        let simulated = match capacity {
+            8 => simulate_variant!(batch_8, match_orders_mpc_8, orders, timestamp),
+            32 => simulate_variant!(batch_32, match_orders_mpc_32, orders, timestamp),
+            128 => simulate_variant!(batch_128, match_orders_mpc_128, orders, timestamp),
+            512 => simulate_variant!(batch_512, match_orders_mpc_512, orders, timestamp),
+            _ => panic!("no circuit compiled for capacity {capacity}"),
+        };
+        assert_eq!(plain, simulated, "capacity {capacity}");
     }
 
     #[test]
@@ -422,6 +394,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -431,11 +405,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 95,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
     }
 
     #[test]
@@ -448,6 +424,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -457,6 +435,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -466,6 +446,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 55,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -475,11 +457,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 75,
                 created_at: 2001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
     }
 
     #[test]
@@ -492,6 +476,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 95,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -501,11 +487,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 85,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
     }
 
     #[test]
@@ -518,6 +506,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 50,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -527,17 +517,63 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_expired_orders_equivalence() {
+        let orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 10,
+                created_at: 1000,
+                expires_at: NOW,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Bid,
+                amount_in: 60,
+                filled_amount_in: 0,
+                min_amount_out: 10,
+                created_at: 1001,
+                expires_at: NOW + 1,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 2,
+                side: OrderSide::Ask,
+                amount_in: 80,
+                filled_amount_in: 0,
+                min_amount_out: 10,
+                created_at: 1002,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        // Before, at and after the first bid's expiry
+        test_matching_equivalence(&orders, NOW - 1);
+        test_matching_equivalence(&orders, NOW);
+        test_matching_equivalence(&orders, NOW + 1);
     }
 
     #[test]
     fn test_empty_input_equivalence() {
         let orders = vec![];
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
     }
 
     #[test]
@@ -550,6 +586,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 50,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -559,6 +597,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -568,6 +608,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 55,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -577,11 +619,216 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 2001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_sparse_indices_equivalence() {
+        let orders = vec![
+            Order {
+                index: 3,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 99,
+                side: OrderSide::Bid,
+                amount_in: 40,
+                filled_amount_in: 0,
+                min_amount_out: 20,
+                created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 57,
+                side: OrderSide::Ask,
+                amount_in: 120,
+                filled_amount_in: 0,
+                min_amount_out: 60,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_large_indices_equivalence() {
+        // Indices well beyond any batch capacity must not affect matching
+        let orders = vec![
+            Order {
+                index: 100,
+                side: OrderSide::Bid,
+                amount_in: 80,
+                filled_amount_in: 0,
+                min_amount_out: 40,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1_000_000,
+                side: OrderSide::Ask,
+                amount_in: 50,
+                filled_amount_in: 0,
+                min_amount_out: 25,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: u32::MAX,
+                side: OrderSide::Ask,
+                amount_in: 30,
+                filled_amount_in: 0,
+                min_amount_out: 15,
+                created_at: 1002,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_duplicate_indices_equivalence() {
+        let orders = vec![
+            Order {
+                index: 5,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 5,
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        test_matching_equivalence(&orders);
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    /// Helper to build an open order for the adversarial books below
+    fn open_order(
+        index: u32,
+        side: OrderSide,
+        amount_in: u64,
+        filled_amount_in: u64,
+        min_amount_out: u64,
+        created_at: i64,
+    ) -> Order {
+        Order {
+            index,
+            side,
+            amount_in,
+            filled_amount_in,
+            min_amount_out,
+            created_at,
+            expires_at: 0,
+            time_in_force: TimeInForce::GoodTillCancel,
+            status: OrderStatus::Open,
+        }
+    }
+
+    #[test]
+    fn test_single_bid_sweeps_full_book_equivalence() {
+        // One bid against capacity - 1 unit asks: every step is a fill that
+        // only advances the ask pointer
+        let capacity = batch_128::CAPACITY as u32;
+        let mut orders = vec![open_order(0, OrderSide::Bid, (capacity - 1) as u64, 0, 1, 0)];
+        for i in 1..capacity {
+            orders.push(open_order(i, OrderSide::Ask, 1, 0, 1, i as i64));
+        }
+
+        assert_eq!(matching::match_orders(&orders, NOW).unwrap().fills.len(), (capacity - 1) as usize);
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_min_amount_out_skips_equivalence() {
+        // Every bid but the newest is skipped on min_amount_out, one step each
+        let capacity = batch_128::CAPACITY as u32;
+        let mut orders = vec![open_order(0, OrderSide::Ask, 10, 0, 1, 0)];
+        for i in 1..capacity - 1 {
+            orders.push(open_order(i, OrderSide::Bid, 10, 0, 11, i as i64));
+        }
+        orders.push(open_order(capacity - 1, OrderSide::Bid, 10, 0, 10, capacity as i64));
+
+        let plan = matching::match_orders(&orders, NOW).unwrap();
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].order_index, capacity - 1);
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_exhausted_orders_first_equivalence() {
+        // The oldest orders on both sides have nothing left, so the pointers
+        // walk past all of them before the only crossable pair
+        let capacity = batch_128::CAPACITY as u32;
+        let exhausted = (capacity - 2) / 2;
+        let mut orders = Vec::new();
+        for i in 0..exhausted {
+            orders.push(open_order(i, OrderSide::Bid, 50, 50, 1, i as i64));
+            orders.push(open_order(exhausted + i, OrderSide::Ask, 50, 50, 1, i as i64));
+        }
+        orders.push(open_order(capacity - 2, OrderSide::Bid, 40, 0, 1, capacity as i64));
+        orders.push(open_order(capacity - 1, OrderSide::Ask, 40, 0, 1, capacity as i64));
+
+        let plan = matching::match_orders(&orders, NOW).unwrap();
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].order_index, capacity - 2);
+        assert_eq!(plan.fills[0].counterparty_index, capacity - 1);
+        test_matching_equivalence(&orders, NOW);
+    }
+
+    #[test]
+    fn test_staggered_sizes_equivalence() {
+        // Bid and ask sizes never line up, so most fills exhaust only one side
+        let capacity = batch_128::CAPACITY as u32;
+        let half = capacity / 2;
+        let mut orders = Vec::new();
+        for i in 0..half {
+            orders.push(open_order(i, OrderSide::Bid, 3, 0, 1, i as i64));
+            orders.push(open_order(half + i, OrderSide::Ask, 2, 0, 1, i as i64));
+        }
+
+        let plan = matching::match_orders(&orders, NOW).unwrap();
+        let total: u64 = plan.fills.iter().map(|f| f.amount_in).sum();
+        assert_eq!(total, 2 * half as u64); // All ask liquidity crosses
+        test_matching_equivalence(&orders, NOW);
     }
 
     #[test]
@@ -594,6 +841,8 @@ mod tests {
             filled_amount_in: 250,
             min_amount_out: 900,
             created_at: 12345678,
+            expires_at: 12349999,
+            time_in_force: TimeInForce::FillOrKill,
             status: OrderStatus::Open,
         };
 
@@ -606,6 +855,83 @@ mod tests {
         assert_eq!(original.filled_amount_in, reconstructed.filled_amount_in);
         assert_eq!(original.min_amount_out, reconstructed.min_amount_out);
         assert_eq!(original.created_at, reconstructed.created_at);
+        assert_eq!(original.expires_at, reconstructed.expires_at);
+        assert_eq!(original.time_in_force, reconstructed.time_in_force);
         assert_eq!(original.status, reconstructed.status);
     }
+
+    #[test]
+    fn test_capacity_for_picks_smallest_fitting_variant() {
+        assert_eq!(capacity_for(0), Some(8));
+        assert_eq!(capacity_for(8), Some(8));
+        assert_eq!(capacity_for(9), Some(32));
+        assert_eq!(capacity_for(32), Some(32));
+        assert_eq!(capacity_for(33), Some(128));
+        assert_eq!(capacity_for(100), Some(128));
+        assert_eq!(capacity_for(128), Some(128));
+        assert_eq!(capacity_for(129), Some(512));
+        assert_eq!(capacity_for(512), Some(512));
+        assert_eq!(capacity_for(513), None);
+    }
+
+    /// A book of `len` orders: one bid sweeping unit asks, so every order
+    /// takes part and the fill array is filled to `len - 1`
+    fn sweep_book(len: u32) -> Vec<Order> {
+        let mut orders = vec![open_order(0, OrderSide::Bid, (len - 1) as u64, 0, 1, 0)];
+        for i in 1..len {
+            orders.push(open_order(i, OrderSide::Ask, 1, 0, 1, i as i64));
+        }
+        orders
+    }
+
+    #[test]
+    fn test_every_variant_matches_full_book() {
+        for capacity in BATCH_CAPACITIES {
+            test_variant_equivalence(&sweep_book(capacity as u32), NOW, capacity);
+        }
+    }
+
+    #[test]
+    fn test_capacity_boundaries_run_on_chosen_variant() {
+        // Books at and just past each boundary run on the variant
+        // capacity_for picks for them
+        for (len, expected) in [(8, 8), (9, 32), (32, 32), (33, 128), (512, 512)] {
+            let capacity = capacity_for(len).unwrap();
+            assert_eq!(capacity, expected, "{len} orders");
+            test_variant_equivalence(&sweep_book(len as u32), NOW, capacity);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_books_match_small_variants(
+            orders in matching::tests::arb_book(batch_8::CAPACITY),
+            larger in matching::tests::arb_book(batch_32::CAPACITY),
+            now in matching::tests::arb_now(),
+        ) {
+            test_variant_equivalence(&orders, now, batch_8::CAPACITY);
+            test_variant_equivalence(&larger, now, batch_32::CAPACITY);
+        }
+
+        #[test]
+        fn random_books_match_circuit_simulator(
+            orders in matching::tests::arb_book(batch_128::CAPACITY),
+            now in matching::tests::arb_now(),
+        ) {
+            test_matching_equivalence(&orders, now);
+        }
+
+        #[test]
+        fn random_books_with_duplicate_index_match_circuit_simulator(
+            orders in matching::tests::arb_book(batch_128::CAPACITY)
+                .prop_filter("needs two orders", |o| o.len() >= 2),
+            pick in any::<prop::sample::Index>(),
+        ) {
+            let mut orders = orders;
+            let duplicate = pick.index(orders.len() - 1) + 1;
+            orders[duplicate].index = orders[0].index;
+
+            test_matching_equivalence(&orders, NOW);
+        }
+    }
 }
@@ -0,0 +1,370 @@
+//! Shared matching kernel
+//!
+//! The FIFO greedy matcher is written exactly once, here, as the body of
+//! `match_orders_kernel`. It is instantiated twice from the same tokens:
+//! - with plain integers in `matching`, which backs `matching::match_orders`
+//! - inside each `#[encrypted]` circuit variant in `circuits`, where Arcis
+//!   compiles the same code over secret-shared values
+//!
+//! Arcis only accepts `use arcis_imports::*` inside an encrypted module and does
+//! not expand macro invocations there, so the kernel cannot be imported or
+//! called across the boundary. Instead `with_match_orders_kernel!` hands its
+//! tokens to a callback macro, which pastes them into the generated module
+//! before any attribute macro runs.
+//!
+//! The kernel follows Arcis rules: fixed-size arrays, loop bounds that are
+//! compile-time constants, no `while`/`continue`/`break`/`match`, and no early
+//! returns. Each greedy step computes its outcome as flags and applies it with
+//! selects, so control flow never depends on order data.
+//!
+//! # Cost
+//!
+//! Because every loop runs to its bound, the kernel costs the same for any
+//! book of a given capacity, and that cost is O(capacity²).
+//!
+//! What dominates is reading and writing arrays at secret positions. The
+//! counters `open_count`, `bid_count`, `ask_count` and `fill_count` and the
+//! cursors `bid_idx` and `ask_idx` depend on order data. Arcis can't address
+//! memory with a secret, so it compiles `bids[bid_idx]` to a comparison
+//! against every slot and a select of the whole element, O(capacity) each,
+//! and a write like `open_orders[open_count] = order` to a select in every
+//! slot. The kernel makes about 16 such accesses per slot or greedy step:
+//! - the filter writes each live order into `open_orders` (1)
+//! - the split writes each order and its remaining size into one side (4)
+//! - each greedy step reads both sides' order, remaining size and
+//!   committed flag (6), then writes back remaining sizes and flags and
+//!   the fill (5)
+//!
+//! The loops indexed by their public counter cost O(capacity²) too, but
+//! only one comparison or swap per iteration:
+//! - the duplicate-index check compares every pair of slots
+//! - each side is bubble sorted, capacity passes over capacity slots
+//! - each greedy step's fill-or-kill lookahead scans the other side
+//!
+//! Counting a select per field of a moved element (an order has 9), that is
+//! on the order of 100 · capacity² secret comparisons and selects per
+//! batch: about 6k for the 8 variant, 1.6M for the 128 variant and 26M for
+//! the 512 variant, most of it in the secret-indexed accesses. Hence
+//! `capacity_for` picks the smallest variant that fits. A sorting network
+//! would cut the sorts to O(capacity · log² capacity), but the kernel
+//! would stay quadratic while its counters and cursors are secret.
+//!
+//! The caller must define `PlainOrder`, `PlainFill`, `OrdersInput` (with
+//! `orders: [PlainOrder; capacity]`, `count: u32` and `timestamp: i64`) and
+//! `MatchResult` (with `fills: [PlainFill; capacity]` and `count: u32`) in the
+//! target module.
+
+/// Invokes `$callback! { $($prefix)* <kernel> }`, where `<kernel>` is the
+/// `match_orders_kernel` function sized for `$capacity` orders
+macro_rules! with_match_orders_kernel {
+    ($capacity:tt, $callback:ident! { $($prefix:tt)* }) => {
+        $callback! {
+            $($prefix)*
+
+            /// FIFO greedy matching kernel
+            ///
+            /// Implements the algorithm documented on `matching::match_orders`
+            /// for up to `$capacity` orders. Generated from
+            /// encrypted-ixs/src/kernel.rs; edit it there.
+            fn match_orders_kernel(input: OrdersInput) -> MatchResult {
+                // Reject batches with duplicate indices: fills reference orders
+                // by index, so colliding orders could not be told apart
+                let mut has_duplicate = false;
+                for i in 0..$capacity {
+                    for j in 0..$capacity {
+                        if i < j && j < input.count {
+                            if input.orders[i as usize].index == input.orders[j as usize].index {
+                                has_duplicate = true;
+                            }
+                        }
+                    }
+                }
+
+                // Filter to open and partially filled orders that are still
+                // live at the batch timestamp (status 0 or 1; expires_at == 0
+                // never expires)
+                let mut open_orders = [PlainOrder {
+                    index: 0,
+                    side: 0,
+                    amount_in: 0,
+                    filled_amount_in: 0,
+                    min_amount_out: 0,
+                    created_at: 0,
+                    expires_at: 0,
+                    time_in_force: 0,
+                    status: 0,
+                }; $capacity];
+                let mut open_count: u32 = 0;
+
+                for i in 0..$capacity {
+                    if i < input.count {
+                        let order = input.orders[i as usize];
+                        let expired = order.expires_at != 0 && order.expires_at <= input.timestamp;
+                        // OrderStatus::Open or OrderStatus::PartiallyFilled
+                        if (order.status == 0 || order.status == 1) && !expired {
+                            open_orders[open_count as usize] = order;
+                            open_count = open_count + 1;
+                        }
+                    }
+                }
+
+                // Split into bids and asks, tracking remaining size per slot
+                // (remaining = amount_in - filled_amount_in)
+                let mut bids = [PlainOrder {
+                    index: 0,
+                    side: 0,
+                    amount_in: 0,
+                    filled_amount_in: 0,
+                    min_amount_out: 0,
+                    created_at: 0,
+                    expires_at: 0,
+                    time_in_force: 0,
+                    status: 0,
+                }; $capacity];
+                let mut bid_remaining = [0u64; $capacity];
+                let mut bid_count: u32 = 0;
+
+                let mut asks = [PlainOrder {
+                    index: 0,
+                    side: 0,
+                    amount_in: 0,
+                    filled_amount_in: 0,
+                    min_amount_out: 0,
+                    created_at: 0,
+                    expires_at: 0,
+                    time_in_force: 0,
+                    status: 0,
+                }; $capacity];
+                let mut ask_remaining = [0u64; $capacity];
+                let mut ask_count: u32 = 0;
+
+                for i in 0..$capacity {
+                    if i < open_count {
+                        let order = open_orders[i as usize];
+                        let rem = if order.amount_in >= order.filled_amount_in {
+                            order.amount_in - order.filled_amount_in
+                        } else {
+                            0
+                        };
+                        if order.side == 0 {  // OrderSide::Bid
+                            bids[bid_count as usize] = order;
+                            bid_remaining[bid_count as usize] = rem;
+                            bid_count = bid_count + 1;
+                        } else {  // OrderSide::Ask (side == 1)
+                            asks[ask_count as usize] = order;
+                            ask_remaining[ask_count as usize] = rem;
+                            ask_count = ask_count + 1;
+                        }
+                    }
+                }
+
+                // Sort both by created_at ascending (FIFO) - bubble sort
+                // Remaining sizes are swapped alongside their orders
+                // Bubble sort bids
+                for i in 0..$capacity {
+                    if i < bid_count {
+                        for j in 0..$capacity {
+                            if j < bid_count - 1 - i {
+                                if bids[j as usize].created_at > bids[(j + 1) as usize].created_at {
+                                    let temp = bids[j as usize];
+                                    bids[j as usize] = bids[(j + 1) as usize];
+                                    bids[(j + 1) as usize] = temp;
+                                    let temp_remaining = bid_remaining[j as usize];
+                                    bid_remaining[j as usize] = bid_remaining[(j + 1) as usize];
+                                    bid_remaining[(j + 1) as usize] = temp_remaining;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Bubble sort asks
+                for i in 0..$capacity {
+                    if i < ask_count {
+                        for j in 0..$capacity {
+                            if j < ask_count - 1 - i {
+                                if asks[j as usize].created_at > asks[(j + 1) as usize].created_at {
+                                    let temp = asks[j as usize];
+                                    asks[j as usize] = asks[(j + 1) as usize];
+                                    asks[(j + 1) as usize] = temp;
+                                    let temp_remaining = ask_remaining[j as usize];
+                                    ask_remaining[j as usize] = ask_remaining[(j + 1) as usize];
+                                    ask_remaining[(j + 1) as usize] = temp_remaining;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Greedy batch matcher
+                let mut fills = [PlainFill {
+                    order_index: 0,
+                    counterparty_index: 0,
+                    amount_in: 0,
+                    amount_out: 0,
+                }; $capacity];
+                let mut fill_count: u32 = 0;
+
+                let mut bid_idx: u32 = 0;
+                let mut ask_idx: u32 = 0;
+
+                // Set once an order has had its first fill. A fill-or-kill
+                // order is committed to filling in full from then on
+                let mut bid_committed = [false; $capacity];
+                let mut ask_committed = [false; $capacity];
+
+                // Use a fixed iteration count to avoid while loops
+                //
+                // Every iteration advances bid_idx, ask_idx or both: a skip
+                // moves one pointer, and a fill exhausts at least one side
+                // because match_amount is the smaller remaining size. The loop
+                // therefore finishes within bid_count + ask_count - 1 steps,
+                // and bid_count + ask_count <= open_count <= $capacity, so one
+                // iteration per slot covers the worst case. Steps past
+                // bid_count + ask_count are masked off, which is the early exit
+                // Arcis allows: the loop still runs $capacity times, but only
+                // the first bid_count + ask_count iterations can change state.
+                let step_limit = bid_count + ask_count;
+                let mut step: u32 = 0;
+                for _ in 0..$capacity {
+                    if step < step_limit && bid_idx < bid_count && ask_idx < ask_count {
+                        let bid = bids[bid_idx as usize];
+                        let ask = asks[ask_idx as usize];
+
+                        let remaining_bid = bid_remaining[bid_idx as usize];
+                        let remaining_ask = ask_remaining[ask_idx as usize];
+
+                        // Match amount is the minimum of remaining sizes
+                        let match_amount = if remaining_bid < remaining_ask {
+                            remaining_bid
+                        } else {
+                            remaining_ask
+                        };
+
+                        // Fill-or-kill orders (time_in_force == 2) fill their
+                        // whole remaining size in this batch, possibly against
+                        // several counterparties, or not at all. One at the
+                        // head of its side is pending until its first fill
+                        let bid_fok = bid.time_in_force == 2;
+                        let ask_fok = ask.time_in_force == 2;
+                        let bid_pending = bid_fok && !bid_committed[bid_idx as usize];
+                        let ask_pending = ask_fok && !ask_committed[ask_idx as usize];
+
+                        // Look ahead from each head: replay the steps that
+                        // would fill it against the other side, passing over
+                        // empty orders and pending fill-or-kill orders that
+                        // don't exactly match what is left, as those steps
+                        // do. A bid is stuck at the first fill below its
+                        // min_amount_out, since that step would skip it; an
+                        // ask just passes over bids it can't satisfy. Only the
+                        // outcome for a pending head is used below. This makes
+                        // every step cost O($capacity), like a sort pass
+                        let mut bid_need = remaining_bid;
+                        let mut bid_stuck = false;
+                        let mut ask_need = remaining_ask;
+                        for j in 0..$capacity {
+                            if j >= ask_idx && j < ask_count && bid_need > 0 && !bid_stuck {
+                                let available = ask_remaining[j as usize];
+                                let passed = asks[j as usize].time_in_force == 2
+                                    && !ask_committed[j as usize]
+                                    && available != bid_need;
+                                if available > 0 && !passed {
+                                    let amount = if bid_need < available { bid_need } else { available };
+                                    if amount < bid.min_amount_out {
+                                        bid_stuck = true;
+                                    } else {
+                                        bid_need = bid_need - amount;
+                                    }
+                                }
+                            }
+                            if j >= bid_idx && j < bid_count && ask_need > 0 {
+                                let available = bid_remaining[j as usize];
+                                let passed = bids[j as usize].time_in_force == 2
+                                    && !bid_committed[j as usize]
+                                    && available != ask_need;
+                                if available > 0 && !passed {
+                                    let amount = if ask_need < available { ask_need } else { available };
+                                    if amount >= bids[j as usize].min_amount_out {
+                                        ask_need = ask_need - amount;
+                                    }
+                                }
+                            }
+                        }
+
+                        // Unless the two heads exactly fill each other, a
+                        // pending ask yields to any fill-or-kill bid and a
+                        // pending bid to a committed fill-or-kill ask, and
+                        // either is passed over if the lookahead can't fill it
+                        let exact = remaining_bid == remaining_ask;
+                        let ask_fok_blocked = ask_pending && !exact && (bid_fok || ask_need > 0);
+                        let bid_fok_blocked = bid_pending && !exact && (ask_fok || bid_need > 0);
+
+                        // Exactly one outcome per step, in priority order:
+                        // - skip the bid if it is fully filled
+                        // - skip the ask if it is fully filled, or if it is a
+                        //   blocked fill-or-kill order
+                        // - skip the bid if it is a blocked fill-or-kill order,
+                        //   or if the ask can't meet its minimum output
+                        // - otherwise fill match_amount between the two
+                        //
+                        // The lookahead replays exactly these rules, so a
+                        // committed fill-or-kill order is never skipped before
+                        // it is filled in full
+                        let bid_empty = remaining_bid == 0;
+                        let ask_empty = remaining_ask == 0;
+                        let skip_bid = bid_empty
+                            || (!ask_empty
+                                && !ask_fok_blocked
+                                && (bid_fok_blocked || match_amount < bid.min_amount_out));
+                        let skip_ask = !skip_bid && (ask_empty || ask_fok_blocked);
+                        let is_fill = !skip_bid && !skip_ask;
+
+                        // Update remaining amounts
+                        let new_bid_remaining = if is_fill {
+                            remaining_bid - match_amount
+                        } else {
+                            remaining_bid
+                        };
+                        let new_ask_remaining = if is_fill {
+                            remaining_ask - match_amount
+                        } else {
+                            remaining_ask
+                        };
+                        bid_remaining[bid_idx as usize] = new_bid_remaining;
+                        ask_remaining[ask_idx as usize] = new_ask_remaining;
+
+                        // Create fill
+                        // Bid is buying base with quote, so:
+                        // - bid.owner receives base (match_amount)
+                        // - ask.owner receives quote (match_amount)
+                        if is_fill {
+                            bid_committed[bid_idx as usize] = true;
+                            ask_committed[ask_idx as usize] = true;
+                            fills[fill_count as usize] = PlainFill {
+                                order_index: bid.index,
+                                counterparty_index: ask.index,
+                                amount_in: match_amount,
+                                amount_out: match_amount,
+                            };
+                            fill_count = fill_count + 1;
+                        }
+
+                        // Move past skipped or fully filled orders
+                        if skip_bid || new_bid_remaining == 0 {
+                            bid_idx = bid_idx + 1;
+                        }
+                        if skip_ask || new_ask_remaining == 0 {
+                            ask_idx = ask_idx + 1;
+                        }
+                    }
+                    step = step + 1;
+                }
+
+                MatchResult {
+                    fills,
+                    count: if has_duplicate { 0 } else { fill_count },
+                }
+            }
+        }
+    };
+}
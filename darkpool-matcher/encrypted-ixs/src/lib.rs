@@ -5,22 +5,20 @@
 //! 2. `circuits` - Arcis encrypted instructions compiled to `.idarc`.
 //! 3. `test_simple` - toy circuits used during bring-up.
 //!
-//! Keeping everything under one crate guarantees the algorithm stays in sync
-//! whether it runs locally, inside the solver, or within Arcium's MXE.
+//! `matching` and `circuits` are both generated from the single kernel in
+//! `kernel.rs`, so the algorithm stays in sync whether it runs locally,
+//! inside the solver, or within Arcium's MXE.
 
-pub mod matching;
-pub use matching::{match_orders, ExecutionPlan, Fill, Order, OrderSide, OrderStatus};
-
-#[cfg(feature = "arcis")]
-pub mod circuits;
+#[macro_use]
+mod kernel;
 
-#[cfg(feature = "arcis")]
-pub use circuits::{match_orders_mpc, MatchResult, OrdersInput, PlainFill, PlainOrder};
+pub mod matching;
+pub use matching::{
+    match_orders, match_orders_with_fees, BatchTooLarge, ExecutionPlan, FeeSchedule, Fill, Order,
+    OrderSide, OrderStatus, TimeInForce,
+};
 
-#[cfg(not(feature = "arcis"))]
 pub mod circuits;
-
-#[cfg(not(feature = "arcis"))]
-pub use circuits::{MatchResult, OrdersInput, PlainFill, PlainOrder};
+pub use circuits::{batch_128, batch_32, batch_512, batch_8, capacity_for, BATCH_CAPACITIES};
 
 mod test_simple; // Simple test circuit
@@ -1,10 +1,11 @@
 //! Order matching algorithm implementation
 //!
 //! This is the canonical solver logic for the dark pool.
-//! It will later be translated to encrypted types for Arcium,
-//! but the algorithm remains the same.
+//! The algorithm itself lives in kernel.rs and is shared with the encrypted
+//! circuits; this module instantiates it over plain integers.
 
 use std::collections::HashMap;
+use std::fmt;
 
 /// Order side: Bid (buying) or Ask (selling)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -20,6 +21,22 @@ pub enum OrderStatus {
     PartiallyFilled,
     Filled,
     Cancelled,
+    Expired,
+}
+
+/// How long an order stays eligible for matching
+///
+/// Immediate-or-cancel orders match like good-till-cancel ones, but only in
+/// the first batch opened after they were placed: the caller leaves them out
+/// of later batches, and `settle_batch` refunds whatever of them that batch
+/// didn't fill, unfilled or not. Fill-or-kill orders fill their whole
+/// remaining size in the batch, against as many counterparties as it takes,
+/// or are passed over for the batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    ImmediateOrCancel,
+    FillOrKill,
 }
 
 /// Order structure
@@ -34,16 +51,52 @@ pub struct Order {
     pub filled_amount_in: u64,
     pub min_amount_out: u64,
     pub created_at: i64,
+    /// Unix timestamp after which the order can no longer match; 0 never expires
+    pub expires_at: i64,
+    pub time_in_force: TimeInForce,
     pub status: OrderStatus,
 }
 
+impl Order {
+    /// Whether the order can still trade: open, or partially filled with a
+    /// remainder left
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, OrderStatus::Open | OrderStatus::PartiallyFilled)
+    }
+
+    /// Whether the order has expired as of `timestamp`, matching `settle_batch`
+    pub fn is_expired(&self, timestamp: i64) -> bool {
+        self.expires_at != 0 && self.expires_at <= timestamp
+    }
+}
+
 /// Fill structure representing a matched trade
-#[derive(Debug, Clone)]
+///
+/// `amount_in`/`amount_out` are gross. The `net_*` amounts are what each owner
+/// actually receives once `settle_batch` has taken the market's fees:
+/// the counterparty receives `net_amount_in`, the order owner `net_amount_out`.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Fill {
     pub order_index: u32,
     pub counterparty_index: u32,
     pub amount_in: u64,
     pub amount_out: u64,
+    pub net_amount_in: u64,
+    pub net_amount_out: u64,
+}
+
+/// Maker/taker fee rates in basis points, mirroring the on-chain `Market`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+}
+
+impl FeeSchedule {
+    /// Fee charged on `amount` at `fee_bps`, rounded down like `settle_batch`
+    pub fn fee(amount: u64, fee_bps: u16) -> u64 {
+        (amount as u128 * fee_bps as u128 / 10_000) as u64
+    }
 }
 
 /// Execution plan containing matched fills
@@ -52,6 +105,27 @@ pub struct ExecutionPlan {
     pub fills: Vec<Fill>,
 }
 
+/// A book with more orders than the largest circuit capacity
+///
+/// Such books must be split across several batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchTooLarge {
+    pub orders: usize,
+}
+
+impl fmt::Display for BatchTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} orders exceed the largest batch capacity ({})",
+            self.orders,
+            plain::batch_512::CAPACITY
+        )
+    }
+}
+
+impl std::error::Error for BatchTooLarge {}
+
 /// Match orders using greedy FIFO algorithm
 ///
 /// This is the canonical solver logic for the dark pool.
@@ -60,7 +134,9 @@ pub struct ExecutionPlan {
 ///
 /// # Algorithm
 ///
-/// 1. Filter to OPEN orders only
+/// 0. Reject the batch (no fills) if two orders share an index
+/// 1. Filter to OPEN and PARTIALLY_FILLED orders that have not expired at
+///    `batch_timestamp`
 /// 2. Split into BID and ASK orders
 /// 3. Sort both by created_at ascending (FIFO)
 /// 4. Track remaining size per order: remaining = amount_in - filled_amount_in
@@ -68,6 +144,10 @@ pub struct ExecutionPlan {
 ///    - Take current bid and ask
 ///    - match_amount = min(remaining_bid, remaining_ask)
 ///    - If match_amount <= 0, advance pointer and continue
+///    - Skip a fill-or-kill order that has not filled yet unless the orders
+///      from the other side's pointer onward would fill it completely; when
+///      both are fill-or-kill and don't fill each other exactly, the ask is
+///      skipped
 ///    - Ensure match_amount >= bid.min_amount_out
 ///    - Emit Fill with amount_in = amount_out = match_amount
 ///    - Decrease remaining for both orders
@@ -76,127 +156,421 @@ pub struct ExecutionPlan {
 /// # Arguments
 ///
 /// * `orders` - Slice of orders (all must be from the same market)
+/// * `batch_timestamp` - Unix timestamp the batch is matched at
 ///
 /// # Returns
 ///
-/// * `ExecutionPlan` containing matched fills
+/// * `ExecutionPlan` containing matched fills, empty if indices are not unique
+///
+/// # Errors
+///
+/// Returns [`BatchTooLarge`] if there are more orders than the largest circuit
+/// capacity (`circuits::BATCH_CAPACITIES`).
+pub fn match_orders(
+    orders: &[Order],
+    batch_timestamp: i64,
+) -> Result<ExecutionPlan, BatchTooLarge> {
+    let plain_orders: Vec<plain::PlainOrder> = orders.iter().map(plain::PlainOrder::from).collect();
+
+    // Run the smallest kernel instantiation that fits the book, like the
+    // relayer does when it picks a circuit
+    let fills = if orders.len() <= plain::batch_8::CAPACITY {
+        plain::batch_8::run(&plain_orders, batch_timestamp)
+    } else if orders.len() <= plain::batch_32::CAPACITY {
+        plain::batch_32::run(&plain_orders, batch_timestamp)
+    } else if orders.len() <= plain::batch_128::CAPACITY {
+        plain::batch_128::run(&plain_orders, batch_timestamp)
+    } else if orders.len() <= plain::batch_512::CAPACITY {
+        plain::batch_512::run(&plain_orders, batch_timestamp)
+    } else {
+        return Err(BatchTooLarge { orders: orders.len() });
+    };
+
+    Ok(ExecutionPlan {
+        fills: fills
+            .iter()
+            .map(|fill| Fill {
+                order_index: fill.order_index,
+                counterparty_index: fill.counterparty_index,
+                amount_in: fill.amount_in,
+                amount_out: fill.amount_out,
+                net_amount_in: fill.amount_in,
+                net_amount_out: fill.amount_out,
+            })
+            .collect(),
+    })
+}
+
+/// Match orders and compute each fill's net proceeds under `fees`
 ///
-/// # Panics
+/// Matching itself is fee-agnostic; fees are taken at settlement. As in
+/// `settle_batch`, the older order of a fill is the maker and, on a
+/// `created_at` tie, the counterparty is the maker. Each side pays its rate on
+/// the amount it receives, rounded down.
 ///
-/// Panics if orders slice is empty (caller should handle this).
-pub fn match_orders(orders: &[Order]) -> ExecutionPlan {
-    if orders.is_empty() {
-        return ExecutionPlan { fills: Vec::new() };
+/// # Errors
+///
+/// Returns [`BatchTooLarge`] under the same conditions as [`match_orders`].
+pub fn match_orders_with_fees(
+    orders: &[Order],
+    batch_timestamp: i64,
+    fees: FeeSchedule,
+) -> Result<ExecutionPlan, BatchTooLarge> {
+    let mut plan = match_orders(orders, batch_timestamp)?;
+    if plan.fills.is_empty() {
+        return Ok(plan);
     }
 
-    // Filter to OPEN orders only
-    let open_orders: Vec<&Order> = orders
-        .iter()
-        .filter(|o| o.status == OrderStatus::Open)
-        .collect();
+    // Indices are unique whenever the plan has fills
+    let created_at: HashMap<u32, i64> = orders.iter().map(|o| (o.index, o.created_at)).collect();
+
+    for fill in &mut plan.fills {
+        let (order_fee_bps, counterparty_fee_bps) =
+            if created_at[&fill.order_index] < created_at[&fill.counterparty_index] {
+                (fees.maker_fee_bps, fees.taker_fee_bps)
+            } else {
+                (fees.taker_fee_bps, fees.maker_fee_bps)
+            };
 
-    if open_orders.is_empty() {
-        return ExecutionPlan { fills: Vec::new() };
+        fill.net_amount_out = fill.amount_out - FeeSchedule::fee(fill.amount_out, order_fee_bps);
+        fill.net_amount_in = fill.amount_in - FeeSchedule::fee(fill.amount_in, counterparty_fee_bps);
     }
 
-    // Split into bids and asks
-    let mut bids: Vec<&Order> = open_orders
-        .iter()
-        .filter(|o| o.side == OrderSide::Bid)
-        .copied()
-        .collect();
+    Ok(plan)
+}
 
-    let mut asks: Vec<&Order> = open_orders
-        .iter()
-        .filter(|o| o.side == OrderSide::Ask)
-        .copied()
-        .collect();
+/// Plain-integer instantiations of the shared matching kernel, one per
+/// circuit capacity
+#[allow(
+    clippy::assign_op_pattern,
+    clippy::collapsible_if,
+    clippy::manual_swap,
+    clippy::needless_range_loop
+)]
+mod plain {
+    use super::{Order, OrderSide, OrderStatus, TimeInForce};
 
-    // Sort both by created_at ascending (FIFO)
-    bids.sort_by(|a, b| a.created_at.cmp(&b.created_at));
-    asks.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    #[derive(Clone, Copy)]
+    pub struct PlainOrder {
+        pub index: u32,
+        pub side: u8,
+        pub amount_in: u64,
+        pub filled_amount_in: u64,
+        pub min_amount_out: u64,
+        pub created_at: i64,
+        pub expires_at: i64,
+        pub time_in_force: u8,
+        pub status: u8,
+    }
 
-    let mut fills = Vec::new();
+    #[derive(Clone, Copy)]
+    pub struct PlainFill {
+        pub order_index: u32,
+        pub counterparty_index: u32,
+        pub amount_in: u64,
+        pub amount_out: u64,
+    }
 
-    // Track remaining size per order
-    let mut remaining: HashMap<u32, u64> = HashMap::new();
-    for order in &open_orders {
-        let rem = order
-            .amount_in
-            .saturating_sub(order.filled_amount_in);
-        remaining.insert(order.index, rem);
+    impl From<&Order> for PlainOrder {
+        fn from(order: &Order) -> Self {
+            PlainOrder {
+                index: order.index,
+                side: match order.side {
+                    OrderSide::Bid => 0,
+                    OrderSide::Ask => 1,
+                },
+                amount_in: order.amount_in,
+                filled_amount_in: order.filled_amount_in,
+                min_amount_out: order.min_amount_out,
+                created_at: order.created_at,
+                expires_at: order.expires_at,
+                time_in_force: match order.time_in_force {
+                    TimeInForce::GoodTillCancel => 0,
+                    TimeInForce::ImmediateOrCancel => 1,
+                    TimeInForce::FillOrKill => 2,
+                },
+                status: match order.status {
+                    OrderStatus::Open => 0,
+                    OrderStatus::PartiallyFilled => 1,
+                    OrderStatus::Filled => 2,
+                    OrderStatus::Cancelled => 3,
+                    OrderStatus::Expired => 4,
+                },
+            }
+        }
     }
 
-    let mut bid_idx = 0;
-    let mut ask_idx = 0;
+    macro_rules! plain_kernel {
+        (@variant $module:ident, $capacity:tt, $($kernel:tt)*) => {
+            pub mod $module {
+                use super::{PlainFill, PlainOrder};
 
-    // Greedy batch matcher
-    while bid_idx < bids.len() && ask_idx < asks.len() {
-        let bid = bids[bid_idx];
-        let ask = asks[ask_idx];
+                pub const CAPACITY: usize = $capacity;
 
-        let remaining_bid = *remaining.get(&bid.index).unwrap_or(&0);
-        let remaining_ask = *remaining.get(&ask.index).unwrap_or(&0);
+                pub struct OrdersInput {
+                    pub orders: [PlainOrder; $capacity],
+                    pub count: u32,
+                    pub timestamp: i64,
+                }
 
-        // Skip if either order is fully filled
-        if remaining_bid == 0 {
-            bid_idx += 1;
-            continue;
-        }
-        if remaining_ask == 0 {
-            ask_idx += 1;
-            continue;
-        }
+                pub struct MatchResult {
+                    pub fills: [PlainFill; $capacity],
+                    pub count: u32,
+                }
+
+                $($kernel)*
 
-        // Match amount is the minimum of remaining sizes
-        let match_amount = remaining_bid.min(remaining_ask);
+                /// Runs the kernel over `orders`, which must fit in `CAPACITY`
+                pub fn run(orders: &[PlainOrder], timestamp: i64) -> Vec<PlainFill> {
+                    let mut input = OrdersInput {
+                        orders: [PlainOrder {
+                            index: 0,
+                            side: 0,
+                            amount_in: 0,
+                            filled_amount_in: 0,
+                            min_amount_out: 0,
+                            created_at: 0,
+                            expires_at: 0,
+                            time_in_force: 0,
+                            status: 0,
+                        }; $capacity],
+                        count: orders.len() as u32,
+                        timestamp,
+                    };
+                    input.orders[..orders.len()].copy_from_slice(orders);
 
-        // Basic guards
-        if match_amount == 0 {
-            bid_idx += 1;
-            ask_idx += 1;
-            continue;
+                    let result = match_orders_kernel(input);
+                    result.fills[..result.count as usize].to_vec()
+                }
+            }
+        };
+        ($module:ident, $capacity:tt) => {
+            with_match_orders_kernel!($capacity, plain_kernel! {
+                @variant $module, $capacity,
+            });
+        };
+    }
+
+    plain_kernel!(batch_8, 8);
+    plain_kernel!(batch_32, 32);
+    plain_kernel!(batch_128, 128);
+    plain_kernel!(batch_512, 512);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    /// Batch timestamp for fixed books; later than every `created_at` in them
+    pub(crate) const NOW: i64 = 10_000;
+
+    /// Straightforward HashMap-based greedy matcher, kept as an oracle for the
+    /// kernel-backed `match_orders` and the circuit simulators, which share
+    /// its code and so can't check each other
+    pub(crate) fn reference_match_orders(orders: &[Order], batch_timestamp: i64) -> ExecutionPlan {
+        if orders.is_empty() {
+            return ExecutionPlan { fills: Vec::new() };
         }
 
-        // Ensure matchAmount meets bid's minimum output requirement
-        if match_amount < bid.min_amount_out {
-            // This bid can't be filled with current ask, skip to next bid
-            bid_idx += 1;
-            continue;
+        // Reject duplicate indices: fills reference orders by index, so two
+        // orders sharing one could not be told apart at settlement
+        let mut seen_indices = HashSet::new();
+        if !orders.iter().all(|o| seen_indices.insert(o.index)) {
+            return ExecutionPlan { fills: Vec::new() };
         }
 
-        // Create fill
-        // Bid is buying base with quote, so:
-        // - bid.owner receives base (match_amount)
-        // - ask.owner receives quote (match_amount)
-        fills.push(Fill {
-            order_index: bid.index,
-            counterparty_index: ask.index,
-            amount_in: match_amount,  // Quote from bid
-            amount_out: match_amount, // Base from ask
-        });
-
-        // Update remaining amounts
-        let new_bid_remaining = remaining_bid - match_amount;
-        let new_ask_remaining = remaining_ask - match_amount;
-        remaining.insert(bid.index, new_bid_remaining);
-        remaining.insert(ask.index, new_ask_remaining);
-
-        // Move to next order if fully filled
-        if new_bid_remaining == 0 {
-            bid_idx += 1;
+        // Filter to open or partially filled, unexpired orders only
+        let open_orders: Vec<&Order> = orders
+            .iter()
+            .filter(|o| o.is_open() && !o.is_expired(batch_timestamp))
+            .collect();
+
+        if open_orders.is_empty() {
+            return ExecutionPlan { fills: Vec::new() };
         }
-        if new_ask_remaining == 0 {
-            ask_idx += 1;
+
+        // Split into bids and asks
+        let mut bids: Vec<&Order> = open_orders
+            .iter()
+            .filter(|o| o.side == OrderSide::Bid)
+            .copied()
+            .collect();
+
+        let mut asks: Vec<&Order> = open_orders
+            .iter()
+            .filter(|o| o.side == OrderSide::Ask)
+            .copied()
+            .collect();
+
+        // Sort both by created_at ascending (FIFO)
+        bids.sort_by_key(|o| o.created_at);
+        asks.sort_by_key(|o| o.created_at);
+
+        let mut fills = Vec::new();
+
+        // Track remaining size per order
+        let mut remaining: HashMap<u32, u64> = HashMap::new();
+        for order in &open_orders {
+            let rem = order
+                .amount_in
+                .saturating_sub(order.filled_amount_in);
+            remaining.insert(order.index, rem);
         }
-    }
 
-    ExecutionPlan { fills }
-}
+        // Orders that have had a fill; a fill-or-kill order that hasn't is
+        // pending and is only filled if it can be filled completely
+        let mut committed: HashSet<u32> = HashSet::new();
+        let pending = |order: &Order, committed: &HashSet<u32>| {
+            order.time_in_force == TimeInForce::FillOrKill && !committed.contains(&order.index)
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // Whether the greedy steps from here would fill `need` of `bid`
+        // against `asks`, which pass over empty asks and pending fill-or-kill
+        // asks that don't exactly match what is left, and skip the bid at the
+        // first fill below its minimum
+        let bid_fillable = |bid: &Order,
+                            mut need: u64,
+                            asks: &[&Order],
+                            remaining: &HashMap<u32, u64>,
+                            committed: &HashSet<u32>| {
+            for ask in asks {
+                let available = remaining[&ask.index];
+                if available == 0 || (pending(ask, committed) && available != need) {
+                    continue;
+                }
+                let amount = need.min(available);
+                if amount < bid.min_amount_out {
+                    return false;
+                }
+                need -= amount;
+                if need == 0 {
+                    return true;
+                }
+            }
+            false
+        };
+
+        // Whether the greedy steps from here would fill `need` of an ask
+        // against `bids`, which pass over empty bids and pending fill-or-kill
+        // bids that don't exactly match what is left, and skip bids whose
+        // minimum the fill wouldn't meet
+        let ask_fillable = |mut need: u64,
+                            bids: &[&Order],
+                            remaining: &HashMap<u32, u64>,
+                            committed: &HashSet<u32>| {
+            for bid in bids {
+                let available = remaining[&bid.index];
+                if available == 0 || (pending(bid, committed) && available != need) {
+                    continue;
+                }
+                let amount = need.min(available);
+                if amount >= bid.min_amount_out {
+                    need -= amount;
+                    if need == 0 {
+                        return true;
+                    }
+                }
+            }
+            false
+        };
+
+        let mut bid_idx = 0;
+        let mut ask_idx = 0;
+
+        // Greedy batch matcher
+        while bid_idx < bids.len() && ask_idx < asks.len() {
+            let bid = bids[bid_idx];
+            let ask = asks[ask_idx];
+
+            let remaining_bid = *remaining.get(&bid.index).unwrap_or(&0);
+            let remaining_ask = *remaining.get(&ask.index).unwrap_or(&0);
+
+            // Skip if either order is fully filled
+            if remaining_bid == 0 {
+                bid_idx += 1;
+                continue;
+            }
+            if remaining_ask == 0 {
+                ask_idx += 1;
+                continue;
+            }
+
+            // Match amount is the minimum of remaining sizes
+            let match_amount = remaining_bid.min(remaining_ask);
+
+            // Basic guards
+            if match_amount == 0 {
+                bid_idx += 1;
+                ask_idx += 1;
+                continue;
+            }
+
+            // Unless the two exactly fill each other, a pending fill-or-kill
+            // ask yields to any fill-or-kill bid, and is passed over unless
+            // the bids from here fill it completely
+            let exact = remaining_bid == remaining_ask;
+            if pending(ask, &committed)
+                && !exact
+                && (bid.time_in_force == TimeInForce::FillOrKill
+                    || !ask_fillable(remaining_ask, &bids[bid_idx..], &remaining, &committed))
+            {
+                ask_idx += 1;
+                continue;
+            }
+
+            // Likewise a pending fill-or-kill bid yields to a committed
+            // fill-or-kill ask, and is passed over unless the asks from here
+            // fill it completely
+            if pending(bid, &committed)
+                && !exact
+                && (ask.time_in_force == TimeInForce::FillOrKill
+                    || !bid_fillable(bid, remaining_bid, &asks[ask_idx..], &remaining, &committed))
+            {
+                bid_idx += 1;
+                continue;
+            }
+
+            // Ensure matchAmount meets bid's minimum output requirement
+            if match_amount < bid.min_amount_out {
+                // This bid can't be filled with current ask, skip to next bid
+                bid_idx += 1;
+                continue;
+            }
+            committed.insert(bid.index);
+            committed.insert(ask.index);
+
+            // Create fill
+            // Bid is buying base with quote, so:
+            // - bid.owner receives base (match_amount)
+            // - ask.owner receives quote (match_amount)
+            fills.push(Fill {
+                order_index: bid.index,
+                counterparty_index: ask.index,
+                amount_in: match_amount,  // Quote from bid
+                amount_out: match_amount, // Base from ask
+                net_amount_in: match_amount,
+                net_amount_out: match_amount,
+            });
+
+            // Update remaining amounts
+            let new_bid_remaining = remaining_bid - match_amount;
+            let new_ask_remaining = remaining_ask - match_amount;
+            remaining.insert(bid.index, new_bid_remaining);
+            remaining.insert(ask.index, new_ask_remaining);
+
+            // Move to next order if fully filled
+            if new_bid_remaining == 0 {
+                bid_idx += 1;
+            }
+            if new_ask_remaining == 0 {
+                ask_idx += 1;
+            }
+        }
+
+        ExecutionPlan { fills }
+    }
 
     #[test]
     fn matches_single_bid_and_ask() {
@@ -208,6 +582,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -217,11 +593,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 95,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         assert_eq!(plan.fills.len(), 1);
         let fill = &plan.fills[0];
@@ -244,6 +622,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1000, // Oldest
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // Newer bid
@@ -254,6 +634,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // Older ask (should match first)
@@ -264,6 +646,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 55,
                 created_at: 1001, // Oldest ask
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // Newer ask
@@ -274,11 +658,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 75,
                 created_at: 2001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should match oldest bid (50) with oldest ask (60)
         // Match amount = min(50, 60) = 50
@@ -300,6 +686,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 95, // Requires at least 95
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -309,11 +697,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 85,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should not match because match_amount (90) < min_amount_out (95)
         assert_eq!(plan.fills.len(), 0);
@@ -329,6 +719,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 50,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -338,11 +730,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should match with amount = min(100, 50) = 50
         assert_eq!(plan.fills.len(), 1);
@@ -361,6 +755,8 @@ mod tests {
                 filled_amount_in: 100, // Already fully filled
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             Order {
@@ -370,11 +766,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should not match because bid is already fully filled
         assert_eq!(plan.fills.len(), 0);
@@ -390,6 +788,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Cancelled, // Not open
             },
             Order {
@@ -399,20 +799,381 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Filled, // Not open
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should return empty plan because no open orders
         assert_eq!(plan.fills.len(), 0);
     }
 
+    #[test]
+    fn matches_remainder_of_partially_filled_orders() {
+        let orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 60,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::PartiallyFilled,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 50,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        // Only the bid's 40 remaining can trade
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].amount_in, 40);
+        assert_eq!(plan.fills, reference_match_orders(&orders, NOW).fills);
+    }
+
+    #[test]
+    fn skips_expired_orders() {
+        let orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: NOW + 1, // Still live
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: NOW, // Expires exactly at the batch timestamp
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 2,
+                side: OrderSide::Ask,
+                amount_in: 40,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1002,
+                expires_at: 0, // Never expires
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        // The older ask has expired, so the bid fills against the newer one
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].counterparty_index, 2);
+        assert_eq!(plan.fills[0].amount_in, 40);
+
+        // Matched earlier, the older ask is still live and comes first
+        let plan = match_orders(&orders, NOW - 1).unwrap();
+        assert_eq!(plan.fills[0].counterparty_index, 1);
+    }
+
+    #[test]
+    fn reduced_order_keeps_fifo_priority() {
+        let orders = vec![
+            // Amended down from 100; keeps its created_at
+            Order {
+                index: 0,
+                side: OrderSide::Ask,
+                amount_in: 40,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 2,
+                side: OrderSide::Bid,
+                amount_in: 50,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1002,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        // The reduced ask still goes first, but only for its new size
+        assert_eq!(plan.fills.len(), 2);
+        assert_eq!(plan.fills[0].counterparty_index, 0);
+        assert_eq!(plan.fills[0].amount_in, 40);
+        assert_eq!(plan.fills[1].counterparty_index, 1);
+        assert_eq!(plan.fills[1].amount_in, 10);
+    }
+
+    #[test]
+    fn increased_order_loses_fifo_priority() {
+        let orders = vec![
+            // Placed first, then amended up from 100; created_at was reset
+            Order {
+                index: 0,
+                side: OrderSide::Ask,
+                amount_in: 150,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1500,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 2,
+                side: OrderSide::Bid,
+                amount_in: 50,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1002,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        // The ask that was placed later but never increased now comes first
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].counterparty_index, 1);
+        assert_eq!(plan.fills[0].amount_in, 50);
+    }
+
+    #[test]
+    fn fill_or_kill_bid_fills_across_several_asks() {
+        let mut orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::FillOrKill,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 60,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 2,
+                side: OrderSide::Ask,
+                amount_in: 60,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1002,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 3,
+                side: OrderSide::Bid,
+                amount_in: 50,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1003,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        // The two asks cover the FOK bid between them; the next bid takes
+        // what is left of the second
+        let plan = match_orders(&orders, NOW).unwrap();
+        let fills: Vec<(u32, u32, u64)> = plan
+            .fills
+            .iter()
+            .map(|f| (f.order_index, f.counterparty_index, f.amount_in))
+            .collect();
+        assert_eq!(fills, vec![(0, 1, 60), (0, 2, 40), (3, 2, 20)]);
+
+        // With a minimum of 50, the 40 from the second ask is too small, so
+        // the FOK bid is passed over before it fills at all
+        orders[0].min_amount_out = 50;
+        let plan = match_orders(&orders, NOW).unwrap();
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].order_index, 3);
+        assert_eq!(plan.fills[0].counterparty_index, 1);
+        assert_eq!(plan.fills[0].amount_in, 50);
+    }
+
+    #[test]
+    fn fill_or_kill_ask_fills_completely_or_is_skipped() {
+        let mut orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 80,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::FillOrKill,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        // The bid covers the whole ask
+        let plan = match_orders(&orders, NOW).unwrap();
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].amount_out, 80);
+
+        // A smaller bid would leave the ask partially filled
+        orders[0].amount_in = 70;
+        assert!(match_orders(&orders, NOW).unwrap().fills.is_empty());
+    }
+
+    #[test]
+    fn fill_or_kill_ask_fills_across_several_bids() {
+        let order = |index, side, amount_in, created_at, time_in_force| Order {
+            index,
+            side,
+            amount_in,
+            filled_amount_in: 0,
+            min_amount_out: 1,
+            created_at,
+            expires_at: 0,
+            time_in_force,
+            status: OrderStatus::Open,
+        };
+        let mut orders = vec![
+            order(0, OrderSide::Bid, 50, 1000, TimeInForce::GoodTillCancel),
+            order(1, OrderSide::Ask, 80, 1001, TimeInForce::FillOrKill),
+            order(2, OrderSide::Bid, 50, 1002, TimeInForce::GoodTillCancel),
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+        let fills: Vec<(u32, u32, u64)> = plan
+            .fills
+            .iter()
+            .map(|f| (f.order_index, f.counterparty_index, f.amount_in))
+            .collect();
+        assert_eq!(fills, vec![(0, 1, 50), (2, 1, 30)]);
+
+        // Two fill-or-kill orders only meet when they fill each other exactly
+        orders[0].time_in_force = TimeInForce::FillOrKill;
+        assert!(match_orders(&orders, NOW).unwrap().fills.is_empty());
+        orders[0].amount_in = 80;
+        let plan = match_orders(&orders, NOW).unwrap();
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].amount_in, 80);
+    }
+
+    #[test]
+    fn immediate_or_cancel_matches_like_good_till_cancel() {
+        let mut orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 40,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+        let gtc_plan = match_orders(&orders, NOW).unwrap();
+
+        // Partial fills are allowed; settlement refunds the remainder
+        orders[0].time_in_force = TimeInForce::ImmediateOrCancel;
+        let ioc_plan = match_orders(&orders, NOW).unwrap();
+
+        assert_eq!(ioc_plan.fills, gtc_plan.fills);
+        assert_eq!(ioc_plan.fills[0].amount_in, 40);
+    }
+
     #[test]
     fn handles_empty_input() {
         let orders = vec![];
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
         assert_eq!(plan.fills.len(), 0);
     }
 
@@ -426,11 +1187,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
         assert_eq!(plan.fills.len(), 0);
     }
 
@@ -444,11 +1207,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 90,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
         assert_eq!(plan.fills.len(), 0);
     }
 
@@ -463,6 +1228,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 50,
                 created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // Second bid
@@ -473,6 +1240,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // First ask (matches with first bid)
@@ -483,6 +1252,8 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 55,
                 created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
             // Second ask (matches with second bid)
@@ -493,11 +1264,13 @@ mod tests {
                 filled_amount_in: 0,
                 min_amount_out: 45,
                 created_at: 2001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
                 status: OrderStatus::Open,
             },
         ];
 
-        let plan = match_orders(&orders);
+        let plan = match_orders(&orders, NOW).unwrap();
 
         // Should have 2 fills
         assert_eq!(plan.fills.len(), 2);
@@ -512,5 +1285,346 @@ mod tests {
         assert_eq!(plan.fills[1].counterparty_index, 3);
         assert_eq!(plan.fills[1].amount_in, 50); // min(50, 50)
     }
-}
 
+    #[test]
+    fn rejects_duplicate_indices() {
+        let orders = vec![
+            Order {
+                index: 7,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 7, // Same index as the bid
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        // Should return empty plan because fills could not be attributed
+        assert_eq!(plan.fills.len(), 0);
+    }
+
+    #[test]
+    fn matches_sparse_large_indices() {
+        let orders = vec![
+            Order {
+                index: 4_000_000_000,
+                side: OrderSide::Bid,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 250,
+                side: OrderSide::Ask,
+                amount_in: 100,
+                filled_amount_in: 0,
+                min_amount_out: 50,
+                created_at: 1001,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+
+        let plan = match_orders(&orders, NOW).unwrap();
+
+        assert_eq!(plan.fills.len(), 1);
+        assert_eq!(plan.fills[0].order_index, 4_000_000_000);
+        assert_eq!(plan.fills[0].counterparty_index, 250);
+        assert_eq!(plan.fills[0].amount_in, 100);
+    }
+
+    /// Strategy for a single order with a random side, size, fill state,
+    /// limit, timestamps and status
+    ///
+    /// Timestamps are drawn from a narrow range so books contain plenty of
+    /// FIFO ties and expiries around the batch timestamp (see `arb_now`), and
+    /// `filled_amount_in` may exceed `amount_in` by one to exercise the
+    /// saturating remaining computation.
+    fn arb_order() -> impl Strategy<Value = Order> {
+        (
+            any::<bool>(),
+            0u64..1_000,
+            0u64..1_002,
+            0u64..600,
+            0i64..8,
+            0i64..16,
+            0u8..6,
+            0u8..8,
+        )
+            .prop_map(|(is_bid, amount_in, filled, min_amount_out, created_at, expires_at, tif, status)| Order {
+                index: 0,
+                side: if is_bid { OrderSide::Bid } else { OrderSide::Ask },
+                amount_in,
+                filled_amount_in: if filled % 3 == 0 { 0 } else { filled % (amount_in + 2) },
+                min_amount_out,
+                created_at,
+                // Half the orders never expire
+                expires_at: if expires_at < 8 { 0 } else { expires_at },
+                // Mostly good-till-cancel, with some of each other kind
+                time_in_force: match tif {
+                    0..=3 => TimeInForce::GoodTillCancel,
+                    4 => TimeInForce::ImmediateOrCancel,
+                    _ => TimeInForce::FillOrKill,
+                },
+                // Bias towards open orders so most books actually cross
+                status: match status {
+                    0..=4 => OrderStatus::Open,
+                    5 => OrderStatus::PartiallyFilled,
+                    6 => OrderStatus::Filled,
+                    _ => OrderStatus::Cancelled,
+                },
+            })
+    }
+
+    /// Strategy for a batch timestamp that falls inside the range `arb_order`
+    /// draws expiries from
+    pub(crate) fn arb_now() -> impl Strategy<Value = i64> {
+        8i64..16
+    }
+
+    /// Strategy for a book of up to `max_len` orders with unique, possibly
+    /// sparse indices
+    pub(crate) fn arb_book(max_len: usize) -> impl Strategy<Value = Vec<Order>> {
+        (prop::collection::vec(arb_order(), 0..=max_len), 1u32..1_000, any::<u32>()).prop_map(
+            |(mut orders, stride, offset)| {
+                for (i, order) in orders.iter_mut().enumerate() {
+                    order.index = offset.wrapping_add(i as u32 * stride);
+                }
+                orders
+            },
+        )
+    }
+
+    /// Position of every open order of `side` in FIFO order (stable by input)
+    fn fifo_positions(orders: &[Order], side: OrderSide, now: i64) -> HashMap<u32, usize> {
+        let mut queue: Vec<&Order> = orders
+            .iter()
+            .filter(|o| o.is_open() && !o.is_expired(now) && o.side == side)
+            .collect();
+        queue.sort_by_key(|o| o.created_at);
+        queue.iter().enumerate().map(|(pos, o)| (o.index, pos)).collect()
+    }
+
+    /// Checks every invariant an execution plan must satisfy for `orders`
+    fn assert_plan_invariants(orders: &[Order], now: i64, plan: &ExecutionPlan) {
+        let by_index: HashMap<u32, &Order> = orders.iter().map(|o| (o.index, o)).collect();
+        let mut filled: HashMap<u32, u64> = HashMap::new();
+
+        for fill in &plan.fills {
+            let bid = by_index[&fill.order_index];
+            let ask = by_index[&fill.counterparty_index];
+
+            // Fills only pair a live bid with a live ask
+            assert_eq!(bid.side, OrderSide::Bid);
+            assert_eq!(ask.side, OrderSide::Ask);
+            assert!(bid.is_open());
+            assert!(ask.is_open());
+            assert!(!bid.is_expired(now));
+            assert!(!ask.is_expired(now));
+
+            // 1:1 price, so what the bid pays is what the ask delivers
+            assert!(fill.amount_in > 0);
+            assert_eq!(fill.amount_in, fill.amount_out);
+
+            // Min-out rule holds for every fill
+            assert!(fill.amount_out >= bid.min_amount_out);
+
+            *filled.entry(bid.index).or_default() += fill.amount_in;
+            *filled.entry(ask.index).or_default() += fill.amount_out;
+        }
+
+        // No order is overfilled
+        for (index, total) in &filled {
+            let order = by_index[index];
+            assert!(*total <= order.amount_in.saturating_sub(order.filled_amount_in));
+        }
+
+        // Fill-or-kill orders fill completely, possibly across several
+        // fills, or not at all
+        for order in orders.iter().filter(|o| o.time_in_force == TimeInForce::FillOrKill) {
+            if let Some(total) = filled.get(&order.index) {
+                assert_eq!(*total, order.amount_in - order.filled_amount_in);
+            }
+        }
+
+        // Totals are conserved across the two sides
+        let bid_total: u64 = plan.fills.iter().map(|f| f.amount_in).sum();
+        let ask_total: u64 = plan.fills.iter().map(|f| f.amount_out).sum();
+        assert_eq!(bid_total, ask_total);
+
+        // FIFO: neither pointer ever moves backwards, and an ask is only
+        // passed over once it is exhausted or is a fill-or-kill order that
+        // could not be filled completely
+        let bid_positions = fifo_positions(orders, OrderSide::Bid, now);
+        let ask_positions = fifo_positions(orders, OrderSide::Ask, now);
+        for pair in plan.fills.windows(2) {
+            assert!(bid_positions[&pair[0].order_index] <= bid_positions[&pair[1].order_index]);
+            assert!(
+                ask_positions[&pair[0].counterparty_index] <= ask_positions[&pair[1].counterparty_index]
+            );
+        }
+        if let Some(last) = plan.fills.last() {
+            let last_ask = ask_positions[&last.counterparty_index];
+            for (index, position) in &ask_positions {
+                let order = by_index[index];
+                if *position < last_ask && order.time_in_force != TimeInForce::FillOrKill {
+                    let remaining = order.amount_in.saturating_sub(order.filled_amount_in);
+                    assert_eq!(filled.get(index).copied().unwrap_or(0), remaining);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn charges_maker_and_taker_fees_on_proceeds() {
+        let orders = vec![
+            // Resting ask: the maker
+            Order {
+                index: 0,
+                side: OrderSide::Ask,
+                amount_in: 10_000,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            // Newer bid: the taker
+            Order {
+                index: 1,
+                side: OrderSide::Bid,
+                amount_in: 10_000,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 2000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+        let fees = FeeSchedule {
+            maker_fee_bps: 5,
+            taker_fee_bps: 30,
+        };
+
+        let plan = match_orders_with_fees(&orders, NOW, fees).unwrap();
+
+        assert_eq!(plan.fills.len(), 1);
+        let fill = &plan.fills[0];
+        assert_eq!(fill.order_index, 1);
+        assert_eq!(fill.amount_in, 10_000);
+        assert_eq!(fill.amount_out, 10_000);
+        assert_eq!(fill.net_amount_out, 9_970); // Bid owner pays the taker fee
+        assert_eq!(fill.net_amount_in, 9_995); // Ask owner pays the maker fee
+    }
+
+    #[test]
+    fn fees_round_down_and_ties_make_counterparty_maker() {
+        let orders = vec![
+            Order {
+                index: 0,
+                side: OrderSide::Bid,
+                amount_in: 999,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+            Order {
+                index: 1,
+                side: OrderSide::Ask,
+                amount_in: 999,
+                filled_amount_in: 0,
+                min_amount_out: 1,
+                created_at: 1000,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            },
+        ];
+        let fees = FeeSchedule {
+            maker_fee_bps: 10,
+            taker_fee_bps: 20,
+        };
+
+        let plan = match_orders_with_fees(&orders, NOW, fees).unwrap();
+
+        let fill = &plan.fills[0];
+        assert_eq!(fill.net_amount_out, 999 - 1); // Taker: 999 * 20 / 10_000 = 1.998
+        assert_eq!(fill.net_amount_in, 999); // Maker: 999 * 10 / 10_000 = 0.999
+    }
+
+    #[test]
+    fn rejects_books_larger_than_any_circuit() {
+        let orders: Vec<Order> = (0..=plain::batch_512::CAPACITY as u32)
+            .map(|index| Order {
+                index,
+                side: OrderSide::Bid,
+                amount_in: 1,
+                filled_amount_in: 0,
+                min_amount_out: 0,
+                created_at: 0,
+                expires_at: 0,
+                time_in_force: TimeInForce::GoodTillCancel,
+                status: OrderStatus::Open,
+            })
+            .collect();
+
+        assert_eq!(
+            match_orders(&orders, NOW).unwrap_err(),
+            BatchTooLarge { orders: plain::batch_512::CAPACITY + 1 }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn kernel_matches_reference_matcher(orders in arb_book(160), now in arb_now()) {
+            prop_assert_eq!(
+                match_orders(&orders, now).unwrap().fills,
+                reference_match_orders(&orders, now).fills
+            );
+        }
+
+        #[test]
+        fn random_books_satisfy_plan_invariants(orders in arb_book(64), now in arb_now()) {
+            let plan = match_orders(&orders, now).unwrap();
+            assert_plan_invariants(&orders, now, &plan);
+        }
+
+        #[test]
+        fn random_books_with_duplicate_index_produce_no_fills(
+            orders in arb_book(64).prop_filter("needs two orders", |o| o.len() >= 2),
+            pick in any::<prop::sample::Index>(),
+        ) {
+            let mut orders = orders;
+            let duplicate = pick.index(orders.len() - 1) + 1;
+            orders[duplicate].index = orders[0].index;
+
+            prop_assert!(match_orders(&orders, NOW).unwrap().fills.is_empty());
+        }
+    }
+}
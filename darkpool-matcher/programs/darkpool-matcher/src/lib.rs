@@ -1,4 +1,6 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+use anchor_lang::system_program::{allocate, assign, transfer, Allocate, Assign, Transfer};
 use arcium_anchor::{
     queue_computation,
     prelude::*,
@@ -15,111 +17,390 @@ pub mod darkpool_matcher {
         Ok(())
     }
 
-    /// Queue a match_orders_mpc computation with encrypted order data
-    /// 
-    /// The encrypted data is stored in a PDA buffer account with layout:
+    /// Register the computation definition for the 8-order circuit
+    pub fn init_match_orders_8_comp_def(ctx: Context<InitMatchOrders8CompDef>) -> Result<()> {
+        init_comp_def(ctx.accounts, None, None)?;
+        Ok(())
+    }
+
+    /// Register the computation definition for the 32-order circuit
+    pub fn init_match_orders_32_comp_def(ctx: Context<InitMatchOrders32CompDef>) -> Result<()> {
+        init_comp_def(ctx.accounts, None, None)?;
+        Ok(())
+    }
+
+    /// Register the computation definition for the 128-order circuit
+    pub fn init_match_orders_128_comp_def(ctx: Context<InitMatchOrders128CompDef>) -> Result<()> {
+        init_comp_def(ctx.accounts, None, None)?;
+        Ok(())
+    }
+
+    /// Register the computation definition for the 512-order circuit
+    pub fn init_match_orders_512_comp_def(ctx: Context<InitMatchOrders512CompDef>) -> Result<()> {
+        init_comp_def(ctx.accounts, None, None)?;
+        Ok(())
+    }
+
+    /// Allocate the ciphertext buffer for a `capacity`-order circuit, or grow
+    /// it towards full size
+    ///
+    /// Accounts grow by at most 10 KiB per instruction, so the 128 and 512
+    /// buffers take several calls (they can share a transaction). The buffer
+    /// is tagged as a `CiphertextBuffer<capacity>` once it reaches full size.
+    pub fn grow_ciphertext_buffer(
+        ctx: Context<GrowCiphertextBuffer>,
+        computation_offset: u64,
+        capacity: u16,
+    ) -> Result<()> {
+        let (data_len, discriminator) = buffer_layout(capacity)?;
+        let target_len = 8 + data_len;
+        let buffer = ctx.accounts.ciphertext_buffer.to_account_info();
+        let current_len = buffer.data_len();
+        require!(current_len < target_len, ErrorCode::BufferAlreadyAllocated);
+        let new_len = target_len.min(current_len + MAX_PERMITTED_DATA_INCREASE);
+
+        let rent = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(buffer.lamports());
+        if rent > 0 {
+            transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.payer.to_account_info(),
+                        to: buffer.clone(),
+                    },
+                ),
+                rent,
+            )?;
+        }
+
+        if buffer.owner == &System::id() {
+            // First call: allocate and take ownership of the PDA
+            let payer_key = ctx.accounts.payer.key();
+            let offset_bytes = computation_offset.to_le_bytes();
+            let seeds: &[&[u8]] = &[
+                b"ciphertext_buffer",
+                payer_key.as_ref(),
+                offset_bytes.as_ref(),
+                &[ctx.bumps.ciphertext_buffer],
+            ];
+            let system_program = ctx.accounts.system_program.to_account_info();
+            allocate(
+                CpiContext::new_with_signer(
+                    system_program.clone(),
+                    Allocate { account_to_allocate: buffer.clone() },
+                    &[seeds],
+                ),
+                new_len as u64,
+            )?;
+            assign(
+                CpiContext::new_with_signer(
+                    system_program,
+                    Assign { account_to_assign: buffer.clone() },
+                    &[seeds],
+                ),
+                &crate::ID,
+            )?;
+        } else {
+            buffer.resize(new_len)?;
+        }
+
+        if new_len == target_len {
+            buffer.try_borrow_mut_data()?[..8].copy_from_slice(discriminator);
+        }
+        Ok(())
+    }
+
+    /// Write encrypted order data into a ciphertext buffer at `offset`
+    ///
+    /// A large book's ciphertexts don't fit in one transaction, so they are
+    /// written over several calls before `match_orders_<N>` queues them.
+    pub fn write_ciphertexts(
+        ctx: Context<WriteCiphertexts>,
+        _computation_offset: u64,
+        offset: u32,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let buffer = ctx.accounts.ciphertext_buffer.to_account_info();
+        require!(buffer.owner == &crate::ID, ErrorCode::BufferNotAllocated);
+
+        // Skip the discriminator
+        let start = 8 + offset as usize;
+        let end = start + data.len();
+        let mut buffer_data = buffer.try_borrow_mut_data()?;
+        require!(end <= buffer_data.len(), ErrorCode::BufferTooLarge);
+        buffer_data[start..end].copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Queue a match_orders_mpc_8 computation over the first
+    /// `ciphertext_len` bytes of its ciphertext buffer
+    ///
+    /// The buffer is allocated with `grow_ciphertext_buffer` and filled with
+    /// `write_ciphertexts`; its layout is:
     /// [nonce (16 bytes) | pubkey (32 bytes) | ciphertexts (N*32 bytes)]
-    pub fn match_orders(
-        ctx: Context<MatchOrders>,
+    pub fn match_orders_8(
+        ctx: Context<MatchOrders8>,
+        computation_offset: u64,
+        ciphertext_len: u32,
+    ) -> Result<()> {
+        let read_len = {
+            let buffer = ctx.accounts.ciphertext_buffer.load()?;
+            check_ciphertext_len(&buffer.data, ciphertext_len as usize)?
+        };
+        let args = buffer_args(ctx.accounts.ciphertext_buffer.key(), read_len);
+
+        queue_computation(ctx.accounts, computation_offset, args, None, vec![], 0)?;
+
+        msg!("Queued match_orders_mpc_8 computation with offset: {}", computation_offset);
+        Ok(())
+    }
+
+    /// Queue a match_orders_mpc_32 computation over the first
+    /// `ciphertext_len` bytes of its ciphertext buffer
+    pub fn match_orders_32(
+        ctx: Context<MatchOrders32>,
+        computation_offset: u64,
+        ciphertext_len: u32,
+    ) -> Result<()> {
+        let read_len = {
+            let buffer = ctx.accounts.ciphertext_buffer.load()?;
+            check_ciphertext_len(&buffer.data, ciphertext_len as usize)?
+        };
+        let args = buffer_args(ctx.accounts.ciphertext_buffer.key(), read_len);
+
+        queue_computation(ctx.accounts, computation_offset, args, None, vec![], 0)?;
+
+        msg!("Queued match_orders_mpc_32 computation with offset: {}", computation_offset);
+        Ok(())
+    }
+
+    /// Queue a match_orders_mpc_128 computation over the first
+    /// `ciphertext_len` bytes of its ciphertext buffer
+    pub fn match_orders_128(
+        ctx: Context<MatchOrders128>,
+        computation_offset: u64,
+        ciphertext_len: u32,
+    ) -> Result<()> {
+        let read_len = {
+            let buffer = ctx.accounts.ciphertext_buffer.load()?;
+            check_ciphertext_len(&buffer.data, ciphertext_len as usize)?
+        };
+        let args = buffer_args(ctx.accounts.ciphertext_buffer.key(), read_len);
+
+        queue_computation(ctx.accounts, computation_offset, args, None, vec![], 0)?;
+
+        msg!("Queued match_orders_mpc_128 computation with offset: {}", computation_offset);
+        Ok(())
+    }
+
+    /// Queue a match_orders_mpc_512 computation over the first
+    /// `ciphertext_len` bytes of its ciphertext buffer
+    pub fn match_orders_512(
+        ctx: Context<MatchOrders512>,
         computation_offset: u64,
-        ciphertext_data: Vec<u8>,
+        ciphertext_len: u32,
     ) -> Result<()> {
-        // Validate buffer size: must be at least 48 bytes (16 nonce + 32 pubkey)
-        require!(
-            ciphertext_data.len() >= 48,
-            ErrorCode::InvalidBufferSize
-        );
-        require!(
-            (ciphertext_data.len() - 48) % 32 == 0,
-            ErrorCode::InvalidCiphertextAlignment
-        );
-        require!(
-            ciphertext_data.len() <= MAX_BUFFER_SIZE,
-            ErrorCode::BufferTooLarge
-        );
-
-        // Write encrypted data to buffer account
-        let buffer = &mut ctx.accounts.ciphertext_buffer.load_mut()?;
-        let write_len = ciphertext_data.len().min(MAX_BUFFER_SIZE);
-        buffer.data[..write_len].copy_from_slice(&ciphertext_data[..write_len]);
-
-        // Build arguments for queue_computation
-        // The circuit expects: Account containing [nonce(16) | pubkey(32) | ciphertexts(N*32)]
-        let args = vec![Argument::Account(
-            ctx.accounts.ciphertext_buffer.key(),
-            write_len as u32,
-            write_len as u32,
-        )];
-
-        // Queue the computation
-        queue_computation(
-            ctx.accounts,
-            computation_offset,
-            args,
-            None, // callback_url
-            vec![], // callback_instructions
-            0, // num_callback_txs
-        )?;
-
-        msg!("Queued match_orders_mpc computation with offset: {}", computation_offset);
+        let read_len = {
+            let buffer = ctx.accounts.ciphertext_buffer.load()?;
+            check_ciphertext_len(&buffer.data, ciphertext_len as usize)?
+        };
+        let args = buffer_args(ctx.accounts.ciphertext_buffer.key(), read_len);
+
+        queue_computation(ctx.accounts, computation_offset, args, None, vec![], 0)?;
+
+        msg!("Queued match_orders_mpc_512 computation with offset: {}", computation_offset);
         Ok(())
     }
 }
 
-#[derive(Accounts)]
-pub struct Initialize {}
+/// Validate the length of encrypted order data held in a circuit's buffer
+///
+/// Returns the number of bytes to read.
+fn check_ciphertext_len(buffer: &[u8], ciphertext_len: usize) -> Result<usize> {
+    // Validate buffer size: must be at least 48 bytes (16 nonce + 32 pubkey)
+    require!(
+        ciphertext_len >= 48,
+        ErrorCode::InvalidBufferSize
+    );
+    require!(
+        (ciphertext_len - 48) % 32 == 0,
+        ErrorCode::InvalidCiphertextAlignment
+    );
+    require!(
+        ciphertext_len <= buffer.len(),
+        ErrorCode::BufferTooLarge
+    );
+    Ok(ciphertext_len)
+}
 
-// SignerAccount struct for PDA signer (required by queue_computation_accounts)
-#[account]
-pub struct SignerAccount {
-    pub bump: u8,
+/// Data size and discriminator of the buffer for a `capacity`-order circuit
+fn buffer_layout(capacity: u16) -> Result<(usize, &'static [u8])> {
+    match capacity {
+        8 => Ok((buffer_size(8), CiphertextBuffer8::DISCRIMINATOR)),
+        32 => Ok((buffer_size(32), CiphertextBuffer32::DISCRIMINATOR)),
+        128 => Ok((buffer_size(128), CiphertextBuffer128::DISCRIMINATOR)),
+        512 => Ok((buffer_size(512), CiphertextBuffer512::DISCRIMINATOR)),
+        _ => err!(ErrorCode::UnsupportedCapacity),
+    }
+}
+
+/// Build arguments for queue_computation
+///
+/// The circuit expects: Account containing [nonce(16) | pubkey(32) | ciphertexts(N*32)]
+fn buffer_args(buffer: Pubkey, read_len: usize) -> Vec<Argument> {
+    vec![Argument::Account(buffer, read_len as u32, read_len as u32)]
 }
 
-const MAX_BUFFER_SIZE: usize = 16 + 32 + 700 * 32; // nonce + pubkey + max ciphertexts
+#[derive(Accounts)]
+pub struct Initialize {}
 
-#[queue_computation_accounts("match_orders_mpc", payer)]
 #[derive(Accounts)]
 #[instruction(computation_offset: u64)]
-pub struct MatchOrders<'info> {
+pub struct GrowCiphertextBuffer<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
+    /// CHECK: allocated and tagged by `grow_ciphertext_buffer`, then loaded
+    /// as a `CiphertextBuffer<N>` by `match_orders_<N>`
+    #[account(
+        mut,
+        seeds = [b"ciphertext_buffer", payer.key().as_ref(), computation_offset.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub ciphertext_buffer: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
 
-    /// PDA buffer account to store encrypted order data
-    /// Layout: [nonce (16) | pubkey (32) | ciphertexts (N*32)]
+#[derive(Accounts)]
+#[instruction(computation_offset: u64)]
+pub struct WriteCiphertexts<'info> {
+    pub payer: Signer<'info>,
+    /// CHECK: must be owned by this program, i.e. allocated by
+    /// `grow_ciphertext_buffer`
     #[account(
-        init_if_needed,
-        payer = payer,
-        space = MAX_BUFFER_SIZE,
+        mut,
         seeds = [b"ciphertext_buffer", payer.key().as_ref(), computation_offset.to_le_bytes().as_ref()],
         bump
     )]
-    pub ciphertext_buffer: AccountLoader<'info, CiphertextBuffer>,
+    pub ciphertext_buffer: UncheckedAccount<'info>,
+}
 
-    // Required Arcium accounts (validated by #[queue_computation_accounts])
-    pub mxe_account: Account<'info, MXEAccount>,
-    pub sign_pda_account: Account<'info, SignerAccount>,
-    #[account(mut)]
-    pub mempool_account: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub executing_pool: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub computation_account: UncheckedAccount<'info>,
-    pub comp_def_account: Account<'info, ComputationDefinitionAccount>,
-    #[account(mut)]
-    pub cluster_account: Account<'info, Cluster>,
-    #[account(mut)]
-    pub pool_account: Account<'info, FeePool>,
-    pub clock_account: Account<'info, ClockAccount>,
-    pub system_program: Program<'info, System>,
-    pub arcium_program: Program<'info, Arcium>,
+// SignerAccount struct for PDA signer (required by queue_computation_accounts)
+#[account]
+pub struct SignerAccount {
+    pub bump: u8,
 }
 
-#[account(zero_copy)]
-#[repr(C)]
-pub struct CiphertextBuffer {
-    pub data: [u8; MAX_BUFFER_SIZE],
+/// Encrypted fields per PlainOrder
+/// (index, side, amount_in, filled_amount_in, min_amount_out, created_at,
+/// expires_at, time_in_force, status)
+const ORDER_FIELDS: usize = 9;
+
+/// Buffer size for a circuit compiled with `capacity` orders:
+/// nonce + pubkey + one ciphertext per order field + the encrypted order count
+/// and batch timestamp
+const fn buffer_size(capacity: usize) -> usize {
+    16 + 32 + (capacity * ORDER_FIELDS + 2) * 32
 }
 
+/// Generates the buffer and account structs for one circuit variant
+///
+/// Must be kept in line with the `match_orders_circuit!` invocations in
+/// encrypted-ixs/src/circuits.rs.
+macro_rules! match_orders_variant {
+    (
+        $circuit:tt,
+        $capacity:tt,
+        $buffer:ident,
+        $queue_accounts:ident,
+        $init_accounts:ident
+    ) => {
+        #[account(zero_copy)]
+        #[repr(C)]
+        pub struct $buffer {
+            pub data: [u8; buffer_size($capacity)],
+        }
+
+        #[queue_computation_accounts($circuit, payer)]
+        #[derive(Accounts)]
+        #[instruction(computation_offset: u64)]
+        pub struct $queue_accounts<'info> {
+            #[account(mut)]
+            pub payer: Signer<'info>,
+
+            /// PDA buffer account holding the encrypted order data
+            /// Layout: [nonce (16) | pubkey (32) | ciphertexts (N*32)]
+            #[account(
+                seeds = [b"ciphertext_buffer", payer.key().as_ref(), computation_offset.to_le_bytes().as_ref()],
+                bump
+            )]
+            pub ciphertext_buffer: AccountLoader<'info, $buffer>,
+
+            // Required Arcium accounts (validated by #[queue_computation_accounts])
+            pub mxe_account: Account<'info, MXEAccount>,
+            pub sign_pda_account: Account<'info, SignerAccount>,
+            #[account(mut)]
+            pub mempool_account: UncheckedAccount<'info>,
+            #[account(mut)]
+            pub executing_pool: UncheckedAccount<'info>,
+            #[account(mut)]
+            pub computation_account: UncheckedAccount<'info>,
+            pub comp_def_account: Account<'info, ComputationDefinitionAccount>,
+            #[account(mut)]
+            pub cluster_account: Account<'info, Cluster>,
+            #[account(mut)]
+            pub pool_account: Account<'info, FeePool>,
+            pub clock_account: Account<'info, ClockAccount>,
+            pub system_program: Program<'info, System>,
+            pub arcium_program: Program<'info, Arcium>,
+        }
+
+        #[init_computation_definition_accounts($circuit, payer)]
+        #[derive(Accounts)]
+        pub struct $init_accounts<'info> {
+            #[account(mut)]
+            pub payer: Signer<'info>,
+            #[account(mut, address = derive_mxe_pda!())]
+            pub mxe_account: Box<Account<'info, MXEAccount>>,
+            #[account(mut)]
+            /// CHECK: comp_def_account, checked by the arcium program
+            pub comp_def_account: UncheckedAccount<'info>,
+            pub arcium_program: Program<'info, Arcium>,
+            pub system_program: Program<'info, System>,
+        }
+    };
+}
+
+match_orders_variant!(
+    "match_orders_mpc_8",
+    8,
+    CiphertextBuffer8,
+    MatchOrders8,
+    InitMatchOrders8CompDef
+);
+match_orders_variant!(
+    "match_orders_mpc_32",
+    32,
+    CiphertextBuffer32,
+    MatchOrders32,
+    InitMatchOrders32CompDef
+);
+match_orders_variant!(
+    "match_orders_mpc_128",
+    128,
+    CiphertextBuffer128,
+    MatchOrders128,
+    InitMatchOrders128CompDef
+);
+match_orders_variant!(
+    "match_orders_mpc_512",
+    512,
+    CiphertextBuffer512,
+    MatchOrders512,
+    InitMatchOrders512CompDef
+);
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Invalid buffer size: must be at least 48 bytes")]
@@ -128,4 +409,10 @@ pub enum ErrorCode {
     InvalidCiphertextAlignment,
     #[msg("Buffer data exceeds maximum size")]
     BufferTooLarge,
+    #[msg("No circuit is compiled for this capacity")]
+    UnsupportedCapacity,
+    #[msg("Ciphertext buffer is already at full size")]
+    BufferAlreadyAllocated,
+    #[msg("Ciphertext buffer has not been allocated")]
+    BufferNotAllocated,
 }
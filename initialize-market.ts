@@ -127,7 +127,8 @@ async function initializeMarket() {
           quoteMint: QUOTE_TOKEN_MINT,
           baseVault: baseVaultPDA,
           quoteVault: quoteVaultPDA,
-          tokenProgram: TOKEN_PROGRAM_ID,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
           rent: SYSVAR_RENT_PUBKEY,
           systemProgram: SystemProgram.programId,
         })
@@ -1,74 +1,522 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_spl::token_interface::spl_token_2022::extension::{
+    BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+};
+use anchor_spl::token_interface::{
+    self, spl_token_2022, CloseAccount, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+use solana_sha256_hasher::hashv;
 
 declare_id!("CMy5ru8L5nwnn4RK8TZJiCLs4FVkouV2PKPnuPCLFedB");
 
+/// Basis point denominator for fee rates
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Highest maker or taker fee the admin can set (10%)
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+/// Most orders a trader can have open in one market at a time
+pub const MAX_OPEN_ORDERS: usize = 32;
+
+/// Most solvers that can be registered at once
+pub const MAX_SOLVERS: usize = 8;
+
+/// Most orders a batch can be matched over, the largest capacity the
+/// matching circuit is compiled for
+pub const MAX_BATCH_INPUTS: usize = 512;
+
+/// Depth of a claim-settled batch's claims tree, which has room for
+/// 2^16 - 1 leaves
+pub const CLAIMS_TREE_DEPTH: usize = 16;
+
+/// Seconds after a claim-settled batch opens before anyone, not just its
+/// solver, may finalize it
+pub const CLAIMS_FINALIZE_TIMEOUT: i64 = 24 * 60 * 60;
+
+/// Schema version carried by every event; bumped whenever an event's
+/// fields change
+pub const EVENT_VERSION: u8 = 1;
+
 #[program]
 pub mod darkpool {
     use super::*;
 
-    pub fn initialize_config(ctx: Context<InitializeConfig>, admin: Pubkey) -> Result<()> {
+    /// Create the config; only the program's upgrade authority may call this,
+    /// so a fresh deployment can't be claimed by whoever calls it first
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        admin: Pubkey,
+        timelock_delay: i64,
+    ) -> Result<()> {
+        require!(timelock_delay >= 0, DarkpoolError::InvalidTimelockDelay);
+
         let config = &mut ctx.accounts.config;
         config.admin = admin;
         config.bump = ctx.bumps.config;
+        config.solvers = Vec::new();
+        config.pending_admin = Pubkey::default();
+        config.timelock_delay = timelock_delay;
+        config.next_change_id = 0;
+        config.bond_mint = Pubkey::default();
+        config.min_bond = 0;
+        config.slash_amount = 0;
+        config.unbonding_delay = 0;
+        Ok(())
+    }
+
+    /// First step of an admin transfer; `new_admin` must then call
+    /// `accept_admin`. Proposing the default pubkey withdraws a proposal.
+    pub fn propose_admin(ctx: Context<ProposeAdmin>, new_admin: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.pending_admin = new_admin;
+
+        emit!(AdminProposed {
+            version: EVENT_VERSION,
+            admin: config.admin,
+            pending_admin: new_admin,
+        });
+
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let previous_admin = config.admin;
+        config.admin = config.pending_admin;
+        config.pending_admin = Pubkey::default();
+
+        emit!(AdminTransferred {
+            version: EVENT_VERSION,
+            previous_admin,
+            admin: config.admin,
+        });
+
+        Ok(())
+    }
+
+    /// Queue a sensitive config change; anyone can execute it with
+    /// `execute_change` once `config.timelock_delay` seconds have passed
+    pub fn queue_change(ctx: Context<QueueChange>, change: ConfigChange) -> Result<()> {
+        change.validate()?;
+
+        let config = &mut ctx.accounts.config;
+        let eta = Clock::get()?
+            .unix_timestamp
+            .checked_add(config.timelock_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        let pending = &mut ctx.accounts.pending_change;
+        pending.id = config.next_change_id;
+        pending.change = change;
+        pending.eta = eta;
+        pending.bump = ctx.bumps.pending_change;
+
+        config.next_change_id = config.next_change_id
+            .checked_add(1)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        emit!(ChangeQueued {
+            version: EVENT_VERSION,
+            id: pending.id,
+            change,
+            eta,
+        });
+
+        Ok(())
+    }
+
+    /// Drop a queued change before it is executed
+    pub fn cancel_change(ctx: Context<CancelChange>) -> Result<()> {
+        emit!(ChangeCancelled {
+            version: EVENT_VERSION,
+            id: ctx.accounts.pending_change.id,
+        });
+
+        Ok(())
+    }
+
+    /// Apply a queued change whose timelock has passed
+    ///
+    /// Changes to a market need that market passed as `market`.
+    pub fn execute_change(ctx: Context<ExecuteChange>) -> Result<()> {
+        let pending = &ctx.accounts.pending_change;
+        let config = &mut ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+
+        require!(
+            Clock::get()?.unix_timestamp >= pending.eta,
+            DarkpoolError::TimelockNotElapsed
+        );
+
+        // Re-check what may have changed since the change was queued
+        let change = pending.change;
+        change.validate()?;
+
+        match change {
+            ConfigChange::AddSolver { solver } => {
+                require!(!config.is_solver(&solver), DarkpoolError::SolverAlreadyRegistered);
+                require!(config.solvers.len() < MAX_SOLVERS, DarkpoolError::TooManySolvers);
+
+                config.solvers.push(solver);
+
+                emit!(SolverAdded {
+                    version: EVENT_VERSION,
+                    solver,
+                });
+            }
+            ConfigChange::RemoveSolver { solver } => {
+                // Markets assigned to the solver can't settle until reassigned
+                require!(config.is_solver(&solver), DarkpoolError::SolverNotRegistered);
+
+                config.solvers.retain(|key| key != &solver);
+
+                emit!(SolverRemoved {
+                    version: EVENT_VERSION,
+                    solver,
+                });
+            }
+            ConfigChange::SetTimelockDelay { delay } => {
+                config.timelock_delay = delay;
+
+                emit!(TimelockDelayUpdated {
+                    version: EVENT_VERSION,
+                    delay,
+                });
+            }
+            ConfigChange::SetMarketFees { market: target, maker_fee_bps, taker_fee_bps } => {
+                let market = change_market(market, target)?;
+                market.maker_fee_bps = maker_fee_bps;
+                market.taker_fee_bps = taker_fee_bps;
+
+                emit!(MarketFeesUpdated {
+                    version: EVENT_VERSION,
+                    market: target,
+                    maker_fee_bps,
+                    taker_fee_bps,
+                });
+            }
+            ConfigChange::SetMarketSolver { market: target, solver } => {
+                // The default pubkey lets any registered solver settle the market
+                require!(
+                    solver == Pubkey::default() || config.is_solver(&solver),
+                    DarkpoolError::SolverNotRegistered
+                );
+
+                let market = change_market(market, target)?;
+                market.solver = solver;
+
+                emit!(MarketSolverUpdated {
+                    version: EVENT_VERSION,
+                    market: target,
+                    solver,
+                });
+            }
+            ConfigChange::SetMarketStatus { market: target, status } => {
+                // Closing is final: open orders can then only be refunded
+                // through `refund_delisted_order`, after which `close_market`
+                // tears the market down
+                let market = change_market(market, target)?;
+                require!(market.status != MarketStatus::Closed, DarkpoolError::MarketClosed);
+
+                market.status = status;
+
+                emit!(MarketStatusUpdated {
+                    version: EVENT_VERSION,
+                    market: target,
+                    status,
+                });
+            }
+            ConfigChange::SetBondParams { mint, min_bond, slash_amount, unbonding_delay } => {
+                // Bonds already held in another mint stay withdrawable but
+                // no longer count towards `min_bond`
+                config.bond_mint = mint;
+                config.min_bond = min_bond;
+                config.slash_amount = slash_amount;
+                config.unbonding_delay = unbonding_delay;
+
+                emit!(BondParamsUpdated {
+                    version: EVENT_VERSION,
+                    mint,
+                    min_bond,
+                    slash_amount,
+                    unbonding_delay,
+                });
+            }
+        }
+
+        emit!(ChangeExecuted {
+            version: EVENT_VERSION,
+            id: pending.id,
+            change,
+        });
+
         Ok(())
     }
 
+    /// Each mint may belong to either SPL Token or Token-2022, and its vaults
+    /// to the same program; Token-2022 mints with an extension the vaults
+    /// can't custody are rejected
     pub fn create_market(
         ctx: Context<CreateMarket>,
         base_mint: Pubkey,
         quote_mint: Pubkey,
     ) -> Result<()> {
+        check_mint_extensions(&ctx.accounts.base_mint)?;
+        check_mint_extensions(&ctx.accounts.quote_mint)?;
+
         let market = &mut ctx.accounts.market;
         market.base_mint = base_mint;
         market.quote_mint = quote_mint;
         market.base_vault = ctx.accounts.base_vault.key();
         market.quote_vault = ctx.accounts.quote_vault.key();
         market.bump = ctx.bumps.market;
+        market.base_fee_vault = ctx.accounts.base_fee_vault.key();
+        market.quote_fee_vault = ctx.accounts.quote_fee_vault.key();
+        market.maker_fee_bps = 0;
+        market.taker_fee_bps = 0;
+        market.status = MarketStatus::Active;
+        market.solver = Pubkey::default();
+        market.next_batch_seq = 0;
+        market.unclaimed_base = 0;
+        market.unclaimed_quote = 0;
+        market.locked_base = 0;
+        market.locked_quote = 0;
+        market.base_volume = 0;
+        market.quote_volume = 0;
+        market.base_token_program = ctx.accounts.base_token_program.key();
+        market.quote_token_program = ctx.accounts.quote_token_program.key();
+        market.settling_batch = None;
+
+        // Vaults are initialized by Anchor via the #[account(init)] constraints
+
+        Ok(())
+    }
+
+    /// Emergency brake: pause a market immediately, without the timelock
+    ///
+    /// Pausing only stops new orders and settlement; every other status
+    /// change, including resuming, goes through `queue_change`.
+    pub fn pause_market(ctx: Context<PauseMarket>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        require!(market.status != MarketStatus::Closed, DarkpoolError::MarketClosed);
+
+        market.status = MarketStatus::Paused;
+
+        emit!(MarketStatusUpdated {
+            version: EVENT_VERSION,
+            market: market.key(),
+            status: MarketStatus::Paused,
+        });
 
-        // Initialize vaults as token accounts
-        let market_seeds = &[
+        Ok(())
+    }
+
+    pub fn withdraw_fees(
+        ctx: Context<WithdrawFees>,
+        base_amount: u64,
+        quote_amount: u64,
+    ) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let seeds = &[
             b"market",
-            base_mint.as_ref(),
-            quote_mint.as_ref(),
-            &[ctx.bumps.market],
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
         ];
-        // Vaults are initialized by Anchor via the #[account(init)] constraints
-        // No manual initialization needed
+        let signer = &[&seeds[..]];
+
+        let base_token_program = ctx.accounts.base_token_program.to_account_info();
+        let quote_token_program = ctx.accounts.quote_token_program.to_account_info();
+        let market_info = ctx.accounts.market.to_account_info();
+        vault_transfer(
+            &base_token_program,
+            &ctx.accounts.base_fee_vault.to_account_info(),
+            &ctx.accounts.base_mint.to_account_info(),
+            &ctx.accounts.admin_base_account.to_account_info(),
+            &market_info,
+            signer,
+            base_amount,
+        )?;
+        vault_transfer(
+            &quote_token_program,
+            &ctx.accounts.quote_fee_vault.to_account_info(),
+            &ctx.accounts.quote_mint.to_account_info(),
+            &ctx.accounts.admin_quote_account.to_account_info(),
+            &market_info,
+            signer,
+            quote_amount,
+        )?;
+
+        emit!(FeesWithdrawn {
+            version: EVENT_VERSION,
+            market: market.key(),
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Create the caller's solver bond, held in `config.bond_mint`
+    pub fn init_solver_bond(ctx: Context<InitSolverBond>) -> Result<()> {
+        check_mint_extensions(&ctx.accounts.bond_mint)?;
+
+        let bond = &mut ctx.accounts.solver_bond;
+        bond.solver = ctx.accounts.solver.key();
+        bond.mint = ctx.accounts.bond_mint.key();
+        bond.vault = ctx.accounts.bond_vault.key();
+        bond.amount = 0;
+        bond.unbonding_amount = 0;
+        bond.unbonding_at = 0;
+        bond.bump = ctx.bumps.solver_bond;
+        Ok(())
+    }
+
+    pub fn deposit_bond(ctx: Context<DepositBond>, amount: u64) -> Result<()> {
+        require!(amount > 0, DarkpoolError::InvalidBondAmount);
+
+        // Only what reaches the vault counts towards the bond
+        let amount = deposit(
+            &ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.solver_token_account.to_account_info(),
+            &ctx.accounts.bond_mint.to_account_info(),
+            &ctx.accounts.bond_vault.to_account_info(),
+            &ctx.accounts.solver.to_account_info(),
+            amount,
+        )?;
+
+        let bond = &mut ctx.accounts.solver_bond;
+        bond.amount = bond.amount
+            .checked_add(amount)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        emit!(BondDeposited {
+            version: EVENT_VERSION,
+            solver: bond.solver,
+            amount,
+            bonded: bond.amount,
+        });
+
+        Ok(())
+    }
+
+    /// Start unbonding `amount` of the active bond
+    ///
+    /// Unbonding stake no longer counts towards `min_bond` but stays
+    /// slashable for `config.unbonding_delay` seconds, which is also how long
+    /// a settlement can be challenged. Unbonding more restarts the delay for
+    /// everything that is unbonding.
+    pub fn request_unbond(ctx: Context<RequestUnbond>, amount: u64) -> Result<()> {
+        let bond = &mut ctx.accounts.solver_bond;
+        require!(amount > 0 && amount <= bond.amount, DarkpoolError::InvalidBondAmount);
+
+        let unbonding_at = Clock::get()?
+            .unix_timestamp
+            .checked_add(ctx.accounts.config.unbonding_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        bond.amount -= amount;
+        bond.unbonding_amount = bond.unbonding_amount
+            .checked_add(amount)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        bond.unbonding_at = unbonding_at;
+
+        emit!(UnbondRequested {
+            version: EVENT_VERSION,
+            solver: bond.solver,
+            amount,
+            unbonding_amount: bond.unbonding_amount,
+            unbonding_at,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraw stake whose unbonding delay has passed
+    pub fn withdraw_unbonded(ctx: Context<WithdrawUnbonded>) -> Result<()> {
+        let bond = &ctx.accounts.solver_bond;
+        let amount = bond.unbonding_amount;
+        require!(amount > 0, DarkpoolError::InvalidBondAmount);
+        require!(
+            Clock::get()?.unix_timestamp >= bond.unbonding_at,
+            DarkpoolError::UnbondingNotElapsed
+        );
+
+        let seeds = &[b"bond".as_ref(), bond.solver.as_ref(), &[bond.bump]];
+        vault_transfer(
+            &ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.bond_vault.to_account_info(),
+            &ctx.accounts.bond_mint.to_account_info(),
+            &ctx.accounts.solver_token_account.to_account_info(),
+            &ctx.accounts.solver_bond.to_account_info(),
+            &[&seeds[..]],
+            amount,
+        )?;
 
+        let bond = &mut ctx.accounts.solver_bond;
+        bond.unbonding_amount = 0;
+
+        emit!(BondWithdrawn {
+            version: EVENT_VERSION,
+            solver: bond.solver,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Create the caller's trader account for a market ahead of time;
+    /// `place_order` also creates it on a trader's first order
+    pub fn init_trader_account(ctx: Context<InitTraderAccount>) -> Result<()> {
+        let trader = &mut ctx.accounts.trader_account;
+        trader.owner = ctx.accounts.owner.key();
+        trader.market = ctx.accounts.market.key();
+        trader.next_nonce = 0;
+        trader.open_orders = Vec::new();
+        trader.locked_base = 0;
+        trader.locked_quote = 0;
+        trader.bump = ctx.bumps.trader_account;
         Ok(())
     }
 
+    /// Place an order, depositing `amount_in` of its input token
+    ///
+    /// Creates the caller's trader account if this is their first order in
+    /// the market.
+    ///
+    /// With a transfer-fee mint the order is for what reached the vault, and
+    /// `min_amount_out` is scaled down with it so the limit price holds.
     pub fn place_order(
         ctx: Context<PlaceOrder>,
         side: OrderSide,
         amount_in: u64,
         min_amount_out: u64,
         nonce: u64,
+        expires_at: i64,
+        time_in_force: TimeInForce,
     ) -> Result<()> {
-        let order = &mut ctx.accounts.order;
-        let market = &ctx.accounts.market;
         let clock = Clock::get()?;
 
-        // Set order fields
-        order.owner = ctx.accounts.owner.key();
-        order.market = market.key();
-        order.side = side;
-        order.amount_in = amount_in;
-        order.filled_amount_in = 0;
-        order.min_amount_out = min_amount_out;
-        order.status = OrderStatus::Open;
-        order.created_at = clock.unix_timestamp;
-        order.bump = ctx.bumps.order;
-        order.nonce = nonce;
+        require!(
+            ctx.accounts.market.accepts_orders(),
+            DarkpoolError::MarketNotAcceptingOrders
+        );
+
+        // 0 means the order never expires
+        require!(
+            expires_at == 0 || expires_at > clock.unix_timestamp,
+            DarkpoolError::InvalidExpiry
+        );
 
         // Transfer tokens from user to appropriate vault
-        let (source_account, vault_account) = match side {
+        let (source_account, vault_account, mint_account, token_program) = match side {
             OrderSide::Bid => {
                 // Buying base with quote: transfer quote tokens
                 (
                     ctx.accounts.user_quote_account.to_account_info(),
                     ctx.accounts.quote_vault.to_account_info(),
+                    ctx.accounts.quote_mint.to_account_info(),
+                    ctx.accounts.quote_token_program.to_account_info(),
                 )
             }
             OrderSide::Ask => {
@@ -76,26 +524,70 @@ pub mod darkpool {
                 (
                     ctx.accounts.user_base_account.to_account_info(),
                     ctx.accounts.base_vault.to_account_info(),
+                    ctx.accounts.base_mint.to_account_info(),
+                    ctx.accounts.base_token_program.to_account_info(),
                 )
             }
         };
+        let requested_amount_in = amount_in;
+        let amount_in = deposit(
+            &token_program,
+            &source_account,
+            &mint_account,
+            &vault_account,
+            &ctx.accounts.owner.to_account_info(),
+            requested_amount_in,
+        )?;
+        let min_amount_out = scale_min_amount_out(min_amount_out, amount_in, requested_amount_in)?;
 
-        let cpi_accounts = Transfer {
-            from: source_account,
-            to: vault_account,
-            authority: ctx.accounts.owner.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount_in)?;
+        // Nonces are handed out in order so clients can derive every order PDA
+        let order_key = ctx.accounts.order.key();
+        let trader = &mut ctx.accounts.trader_account;
+        if trader.owner == Pubkey::default() {
+            trader.owner = ctx.accounts.owner.key();
+            trader.market = ctx.accounts.market.key();
+            trader.bump = ctx.bumps.trader_account;
+        }
+        require!(nonce == trader.next_nonce, DarkpoolError::InvalidNonce);
+        trader.next_nonce = trader.next_nonce
+            .checked_add(1)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        trader.track(order_key)?;
+        trader.lock(side, amount_in)?;
+
+        // Set order fields
+        let order = &mut ctx.accounts.order;
+        order.owner = ctx.accounts.owner.key();
+        order.market = ctx.accounts.market.key();
+        order.side = side;
+        order.amount_in = amount_in;
+        order.filled_amount_in = 0;
+        order.min_amount_out = min_amount_out;
+        order.status = OrderStatus::Open;
+        order.created_at = clock.unix_timestamp;
+        order.bump = ctx.bumps.order;
+        order.nonce = nonce;
+        order.expires_at = expires_at;
+        order.time_in_force = time_in_force;
+        order.batch_seq = ctx.accounts.market.next_batch_seq;
+
+        ctx.accounts.market.lock(side, amount_in)?;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
 
         emit!(OrderPlaced {
-            order: order.key(),
+            version: EVENT_VERSION,
+            order: order_key,
             owner: order.owner,
             market: order.market,
             side,
             amount_in,
             min_amount_out,
+            expires_at,
+            time_in_force,
         });
 
         Ok(())
@@ -106,9 +598,10 @@ pub mod darkpool {
         let market = &ctx.accounts.market;
 
         require!(
-            order.status == OrderStatus::Open,
+            order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled,
             DarkpoolError::OrderNotOpen
         );
+        require!(!market.is_settling(order), DarkpoolError::SettlementInProgress);
 
         let remaining = order.amount_in
             .checked_sub(order.filled_amount_in)
@@ -119,12 +612,14 @@ pub mod darkpool {
         }
 
         // Transfer remaining tokens back to owner
-        let (vault_account, user_account) = match order.side {
+        let (vault_account, user_account, mint_account, token_program) = match order.side {
             OrderSide::Bid => {
                 // Return quote tokens
                 (
                     ctx.accounts.quote_vault.to_account_info(),
                     ctx.accounts.user_quote_account.to_account_info(),
+                    ctx.accounts.quote_mint.to_account_info(),
+                    ctx.accounts.quote_token_program.to_account_info(),
                 )
             }
             OrderSide::Ask => {
@@ -132,6 +627,8 @@ pub mod darkpool {
                 (
                     ctx.accounts.base_vault.to_account_info(),
                     ctx.accounts.user_base_account.to_account_info(),
+                    ctx.accounts.base_mint.to_account_info(),
+                    ctx.accounts.base_token_program.to_account_info(),
                 )
             }
         };
@@ -144,453 +641,4017 @@ pub mod darkpool {
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: vault_account,
-            to: user_account,
-            authority: ctx.accounts.market.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        token::transfer(cpi_ctx, remaining)?;
+        vault_transfer(
+            &token_program,
+            &vault_account,
+            &mint_account,
+            &user_account,
+            &ctx.accounts.market.to_account_info(),
+            signer,
+            remaining,
+        )?;
 
         order.status = OrderStatus::Cancelled;
 
+        let trader = &mut ctx.accounts.trader_account;
+        trader.unlock(order.side, remaining)?;
+        trader.untrack(&order.key());
+        ctx.accounts.market.unlock(order.side, remaining)?;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
         emit!(OrderCancelled {
+            version: EVENT_VERSION,
             order: order.key(),
             owner: order.owner,
             remaining,
         });
 
+        // The account itself is closed to the owner by the `close` constraint
+        emit!(order.closed_event(order.key(), order.to_account_info().lamports()));
+
         Ok(())
     }
 
-    pub fn settle_batch(
-        ctx: Context<SettleBatch>,
-        fills: Vec<Fill>,
-        arcium_signature: Vec<u8>,
+    /// Change an open order's size and limit in place
+    ///
+    /// Reducing `amount_in` refunds the difference and keeps the order's
+    /// `created_at`, so it keeps its FIFO priority. Increasing it deposits the
+    /// difference and resets `created_at`, sending the order to the back of
+    /// the queue as if it had just been placed.
+    pub fn amend_order(
+        ctx: Context<AmendOrder>,
+        new_amount_in: u64,
+        new_min_amount_out: u64,
     ) -> Result<()> {
-        // Extract values from ctx.accounts (these are just values, not references)
-        let market_key = ctx.accounts.market.key();
-        let market_bump = ctx.accounts.market.bump;
-        let base_mint = ctx.accounts.market.base_mint;
-        let quote_mint = ctx.accounts.market.quote_mint;
-        
-        let seeds = &[
-            b"market",
-            base_mint.as_ref(),
-            quote_mint.as_ref(),
-            &[market_bump],
-        ];
-        let signer = &[&seeds[..]];
+        let order = &mut ctx.accounts.order;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
 
-        // Get all accounts from remaining_accounts to avoid lifetime conflicts
-        // Order: [base_vault, quote_vault, market, token_program, then per-fill accounts...]
-        let base_vault_info = ctx.remaining_accounts
-            .get(0)
-            .ok_or(DarkpoolError::OrderNotFound)?;
-        let quote_vault_info = ctx.remaining_accounts
-            .get(1)
-            .ok_or(DarkpoolError::OrderNotFound)?;
-        let market_info = ctx.remaining_accounts
-            .get(2)
-            .ok_or(DarkpoolError::OrderNotFound)?;
-        let token_program_info = ctx.remaining_accounts
-            .get(3)
-            .ok_or(DarkpoolError::OrderNotFound)?;
-        
-        // Validate accounts
         require!(
-            base_vault_info.owner == &anchor_spl::token::ID,
-            DarkpoolError::InvalidTokenAccount
-        );
-        require!(
-            quote_vault_info.owner == &anchor_spl::token::ID,
-            DarkpoolError::InvalidTokenAccount
-        );
-        require!(
-            market_info.key() == market_key,
-            DarkpoolError::MismatchedMarket
+            order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled,
+            DarkpoolError::OrderNotOpen
         );
+        require!(!order.is_expired(clock.unix_timestamp), DarkpoolError::OrderExpired);
+        require!(!market.is_settling(order), DarkpoolError::SettlementInProgress);
         require!(
-            token_program_info.key() == anchor_spl::token::ID,
-            DarkpoolError::InvalidTokenAccount
+            new_amount_in > order.filled_amount_in,
+            DarkpoolError::AmendBelowFilled
         );
 
-        // Process fills - do order updates and transfers in same loop
-        let mut account_idx = 4; // Start after vault, market, and token_program accounts
+        // The vault and user account holding the order's input token
+        let (vault_account, user_account, mint_account, token_program) = match order.side {
+            OrderSide::Bid => (
+                ctx.accounts.quote_vault.to_account_info(),
+                ctx.accounts.user_quote_account.to_account_info(),
+                ctx.accounts.quote_mint.to_account_info(),
+                ctx.accounts.quote_token_program.to_account_info(),
+            ),
+            OrderSide::Ask => (
+                ctx.accounts.base_vault.to_account_info(),
+                ctx.accounts.user_base_account.to_account_info(),
+                ctx.accounts.base_mint.to_account_info(),
+                ctx.accounts.base_token_program.to_account_info(),
+            ),
+        };
+        let trader = &mut ctx.accounts.trader_account;
+        let mut amount_in = new_amount_in;
+        let mut min_amount_out = new_min_amount_out;
 
-        for fill in &fills {
-            // For each fill, expect accounts in this order:
-            // [order_account, counterparty_order_account, order_owner_base_account, order_owner_quote_account, counterparty_owner_base_account, counterparty_owner_quote_account]
-            
-            let order_account_info = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-            let counterparty_account_info = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-            let order_owner_base = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-            let order_owner_quote = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-            let counterparty_owner_base = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-            let counterparty_owner_quote = ctx.remaining_accounts
-                .get(account_idx)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            account_idx += 1;
-
-            // Validate account keys match fill
-            require!(
-                order_account_info.key() == fill.order,
-                DarkpoolError::OrderNotFound
-            );
-            require!(
-                counterparty_account_info.key() == fill.counterparty,
-                DarkpoolError::OrderNotFound
-            );
+        if new_amount_in > order.amount_in {
+            // Growing an order is like placing one; shrinking is always allowed
+            require!(market.accepts_orders(), DarkpoolError::MarketNotAcceptingOrders);
 
-            // Load and deserialize orders
-            let mut order_data = order_account_info.try_borrow_mut_data()?;
-            let mut counterparty_data = counterparty_account_info.try_borrow_mut_data()?;
+            let delta = deposit(
+                &token_program,
+                &user_account,
+                &mint_account,
+                &vault_account,
+                &ctx.accounts.owner.to_account_info(),
+                new_amount_in - order.amount_in,
+            )?;
+            amount_in = order
+                .amount_in
+                .checked_add(delta)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            min_amount_out = scale_min_amount_out(new_min_amount_out, amount_in, new_amount_in)?;
 
-            // Deserialize orders (skip discriminator)
-            let order_discriminator = 8;
-            let mut order = Order::try_deserialize(&mut &order_data[order_discriminator..])?;
-            let mut counterparty = Order::try_deserialize(&mut &counterparty_data[order_discriminator..])?;
+            trader.lock(order.side, delta)?;
+            ctx.accounts.market.lock(order.side, delta)?;
+            order.created_at = clock.unix_timestamp;
+        } else if new_amount_in < order.amount_in {
+            let delta = order.amount_in - new_amount_in;
+            let seeds = &[
+                b"market",
+                market.base_mint.as_ref(),
+                market.quote_mint.as_ref(),
+                &[market.bump],
+            ];
+            vault_transfer(
+                &token_program,
+                &vault_account,
+                &mint_account,
+                &user_account,
+                &market.to_account_info(),
+                &[&seeds[..]],
+                delta,
+            )?;
 
-            // Validate orders
-            require!(order.market == market_key, DarkpoolError::MismatchedMarket);
-            require!(
-                counterparty.market == market_key,
-                DarkpoolError::MismatchedMarket
-            );
-            require!(
-                order.status != OrderStatus::Cancelled,
-                DarkpoolError::OrderCancelled
-            );
-            require!(
-                counterparty.status != OrderStatus::Cancelled,
-                DarkpoolError::OrderCancelled
-            );
+            trader.unlock(order.side, delta)?;
+            ctx.accounts.market.unlock(order.side, delta)?;
+        }
 
-            // Ensure orders are opposite sides
-            require!(
-                order.side != counterparty.side,
-                DarkpoolError::SameSideOrders
-            );
+        order.amount_in = amount_in;
+        order.min_amount_out = min_amount_out;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
 
-            // Validate fill amounts
-            let order_new_filled = order
-                .filled_amount_in
-                .checked_add(fill.amount_in)
-                .ok_or(DarkpoolError::MathOverflow)?;
-            require!(
-                order_new_filled <= order.amount_in,
-                DarkpoolError::InsufficientRemaining
-            );
+        emit!(OrderAmended {
+            version: EVENT_VERSION,
+            order: order.key(),
+            owner: order.owner,
+            amount_in,
+            min_amount_out,
+            created_at: order.created_at,
+        });
 
-            let counterparty_new_filled = counterparty
-                .filled_amount_in
-                .checked_add(fill.amount_out)
-                .ok_or(DarkpoolError::MathOverflow)?;
-            require!(
-                counterparty_new_filled <= counterparty.amount_in,
-                DarkpoolError::InsufficientRemaining
-            );
+        Ok(())
+    }
+
+    /// Refund `amount` of an order's unfilled remainder and leave the rest
+    /// resting in the pool
+    ///
+    /// `amount_in` shrinks by `amount`, so the remainder stays
+    /// `amount_in - filled_amount_in`, and the order keeps its FIFO priority.
+    /// Pulling back the whole remainder cancels the order like `cancel_order`,
+    /// even if it was partially filled: it's marked `Cancelled`, dropped from
+    /// the trader's open orders and can be closed with `close_order`. Takes
+    /// the same accounts as `amend_order`.
+    pub fn reduce_order(ctx: Context<AmendOrder>, amount: u64) -> Result<()> {
+        let order = &mut ctx.accounts.order;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
 
-            // Update filled amounts
-            order.filled_amount_in = order_new_filled;
-            counterparty.filled_amount_in = counterparty_new_filled;
+        require!(
+            order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled,
+            DarkpoolError::OrderNotOpen
+        );
+        require!(!order.is_expired(clock.unix_timestamp), DarkpoolError::OrderExpired);
+        require!(!market.is_settling(order), DarkpoolError::SettlementInProgress);
 
-            // Update status
-            if order.filled_amount_in == order.amount_in {
-                order.status = OrderStatus::Filled;
-            } else {
-                order.status = OrderStatus::PartiallyFilled;
-            }
+        let remaining = order.amount_in
+            .checked_sub(order.filled_amount_in)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        require!(amount > 0 && amount <= remaining, DarkpoolError::InvalidReduceAmount);
 
-            if counterparty.filled_amount_in == counterparty.amount_in {
-                counterparty.status = OrderStatus::Filled;
-            } else {
-                counterparty.status = OrderStatus::PartiallyFilled;
-            }
+        let (vault_account, user_account, mint_account, token_program) = match order.side {
+            OrderSide::Bid => (
+                ctx.accounts.quote_vault.to_account_info(),
+                ctx.accounts.user_quote_account.to_account_info(),
+                ctx.accounts.quote_mint.to_account_info(),
+                ctx.accounts.quote_token_program.to_account_info(),
+            ),
+            OrderSide::Ask => (
+                ctx.accounts.base_vault.to_account_info(),
+                ctx.accounts.user_base_account.to_account_info(),
+                ctx.accounts.base_mint.to_account_info(),
+                ctx.accounts.base_token_program.to_account_info(),
+            ),
+        };
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        vault_transfer(
+            &token_program,
+            &vault_account,
+            &mint_account,
+            &user_account,
+            &market.to_account_info(),
+            &[&seeds[..]],
+            amount,
+        )?;
 
-            // Serialize back
-            order.try_serialize(&mut &mut order_data[order_discriminator..])?;
-            counterparty.try_serialize(&mut &mut counterparty_data[order_discriminator..])?;
-            
-            // Store values we need for transfers
-            let order_side = order.side;
-            let amount_out = fill.amount_out;
-            let amount_in = fill.amount_in;
-            let order_owner_base_key = order_owner_base.key();
-            let order_owner_quote_key = order_owner_quote.key();
-            let counterparty_owner_base_key = counterparty_owner_base.key();
-            let counterparty_owner_quote_key = counterparty_owner_quote.key();
-            
-            // Drop borrows (AccountInfo references don't need dropping)
-            drop(order_data);
-            drop(counterparty_data);
-            
-            // Get AccountInfo references fresh for transfers
-            let order_owner_base_info = ctx.remaining_accounts
-                .get(account_idx - 4)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            let order_owner_quote_info = ctx.remaining_accounts
-                .get(account_idx - 3)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            let counterparty_owner_base_info = ctx.remaining_accounts
-                .get(account_idx - 2)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            let counterparty_owner_quote_info = ctx.remaining_accounts
-                .get(account_idx - 1)
-                .ok_or(DarkpoolError::OrderNotFound)?;
-            
-            // Validate keys still match
-            require!(order_owner_base_info.key() == order_owner_base_key, DarkpoolError::OrderNotFound);
-            require!(order_owner_quote_info.key() == order_owner_quote_key, DarkpoolError::OrderNotFound);
-            require!(counterparty_owner_base_info.key() == counterparty_owner_base_key, DarkpoolError::OrderNotFound);
-            require!(counterparty_owner_quote_info.key() == counterparty_owner_quote_key, DarkpoolError::OrderNotFound);
-            
-            // Do transfers - all AccountInfo from remaining_accounts (no lifetime conflict!)
-            match order_side {
-                OrderSide::Bid => {
-                    // Order is buying base with quote
-                    // Transfer base to order owner
-                    let cpi_accounts = Transfer {
-                        from: base_vault_info.to_account_info(),
-                        to: order_owner_base_info.to_account_info(),
-                        authority: market_info.to_account_info(),
-                    };
-                    let cpi_ctx = CpiContext::new_with_signer(token_program_info.to_account_info(), cpi_accounts, signer);
-                    token::transfer(cpi_ctx, amount_out)?;
-
-                    // Transfer quote to counterparty owner
-                    let cpi_accounts = Transfer {
-                        from: quote_vault_info.to_account_info(),
-                        to: counterparty_owner_quote_info.to_account_info(),
-                        authority: market_info.to_account_info(),
-                    };
-                    let cpi_ctx = CpiContext::new_with_signer(token_program_info.to_account_info(), cpi_accounts, signer);
-                    token::transfer(cpi_ctx, amount_in)?;
-                }
-                OrderSide::Ask => {
-                    // Order is selling base for quote
-                    // Transfer quote to order owner
-                    let cpi_accounts = Transfer {
-                        from: quote_vault_info.to_account_info(),
-                        to: order_owner_quote_info.to_account_info(),
-                        authority: market_info.to_account_info(),
-                    };
-                    let cpi_ctx = CpiContext::new_with_signer(token_program_info.to_account_info(), cpi_accounts, signer);
-                    token::transfer(cpi_ctx, amount_out)?;
-
-                    // Transfer base to counterparty owner
-                    let cpi_accounts = Transfer {
-                        from: base_vault_info.to_account_info(),
-                        to: counterparty_owner_base_info.to_account_info(),
-                        authority: market_info.to_account_info(),
-                    };
-                    let cpi_ctx = CpiContext::new_with_signer(token_program_info.to_account_info(), cpi_accounts, signer);
-                    token::transfer(cpi_ctx, amount_in)?;
-                }
-            }
+        order.amount_in -= amount;
+        // Pulling back the whole remainder is a cancel, filled part or not
+        if order.amount_in == order.filled_amount_in {
+            order.status = OrderStatus::Cancelled;
+            ctx.accounts.trader_account.untrack(&order.key());
         }
+        ctx.accounts.trader_account.unlock(order.side, amount)?;
+        ctx.accounts.market.unlock(order.side, amount)?;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
 
-        emit!(BatchSettled {
-            fills_count: fills.len() as u8,
-            arcium_signature,
+        emit!(OrderReduced {
+            version: EVENT_VERSION,
+            order: order.key(),
+            owner: order.owner,
+            amount,
+            amount_in: order.amount_in,
+            remaining: remaining - amount,
         });
+        if order.status == OrderStatus::Cancelled {
+            emit!(OrderCancelled {
+                version: EVENT_VERSION,
+                order: order.key(),
+                owner: order.owner,
+                remaining: amount,
+            });
+        }
 
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct InitializeConfig<'info> {
-    #[account(
+    /// Cancel every order passed in `remaining_accounts` and close them,
+    /// refunding the total remaining base and quote in one transfer each
+    ///
+    /// Orders that are already filled, cancelled or expired are just closed.
+    /// Clients take the orders from `trader_account.open_orders`; if they
+    /// don't fit in one transaction's compute budget, call again with the
+    /// rest, since each call only touches the orders it is given.
+    pub fn cancel_all_orders<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CancelAllOrders<'info>>,
+    ) -> Result<()> {
+        let owner_key = ctx.accounts.owner.key();
+        let market = &ctx.accounts.market;
+        let market_key = market.key();
+        let owner_info = ctx.accounts.owner.to_account_info();
+        let trader = &mut ctx.accounts.trader_account;
+
+        let mut base_refunded: u64 = 0;
+        let mut quote_refunded: u64 = 0;
+        let mut cancelled: u32 = 0;
+        let mut closed: u32 = 0;
+
+        for order_info in ctx.remaining_accounts {
+            // Listed more than once and already closed
+            if order_info.data_is_empty() {
+                continue;
+            }
+            require!(order_info.owner == &crate::ID, DarkpoolError::InvalidOrderAccount);
+
+            let mut order = Order::try_deserialize(&mut &order_info.try_borrow_data()?[..])?;
+            require!(order.owner == owner_key, DarkpoolError::Unauthorized);
+            require!(order.market == market_key, DarkpoolError::MismatchedMarket);
+            require!(!market.is_settling(&order), DarkpoolError::SettlementInProgress);
+
+            if !order.is_terminal() {
+                let remaining = order.amount_in
+                    .checked_sub(order.filled_amount_in)
+                    .ok_or(DarkpoolError::MathOverflow)?;
+                let refunded = match order.side {
+                    OrderSide::Bid => &mut quote_refunded,
+                    OrderSide::Ask => &mut base_refunded,
+                };
+                *refunded = refunded.checked_add(remaining).ok_or(DarkpoolError::MathOverflow)?;
+
+                trader.unlock(order.side, remaining)?;
+                order.status = OrderStatus::Cancelled;
+                cancelled += 1;
+            }
+
+            trader.untrack(&order_info.key());
+            close_order_account(order_info, &owner_info, &order)?;
+            closed += 1;
+        }
+
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let base_token_program = ctx.accounts.base_token_program.to_account_info();
+        let quote_token_program = ctx.accounts.quote_token_program.to_account_info();
+        let market_info = market.to_account_info();
+        vault_transfer(
+            &base_token_program,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.base_mint.to_account_info(),
+            &ctx.accounts.user_base_account.to_account_info(),
+            &market_info,
+            signer,
+            base_refunded,
+        )?;
+        vault_transfer(
+            &quote_token_program,
+            &ctx.accounts.quote_vault.to_account_info(),
+            &ctx.accounts.quote_mint.to_account_info(),
+            &ctx.accounts.user_quote_account.to_account_info(),
+            &market_info,
+            signer,
+            quote_refunded,
+        )?;
+
+        let market = &mut ctx.accounts.market;
+        market.unlock(OrderSide::Ask, base_refunded)?;
+        market.unlock(OrderSide::Bid, quote_refunded)?;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        emit!(OrdersCancelled {
+            version: EVENT_VERSION,
+            owner: owner_key,
+            market: market_key,
+            cancelled,
+            closed,
+            base_refunded,
+            quote_refunded,
+            open_orders: trader.open_orders.len() as u32,
+        });
+
+        Ok(())
+    }
+
+    /// Close a filled, cancelled or expired order and return its rent to the
+    /// owner
+    pub fn close_order(ctx: Context<CloseOrder>) -> Result<()> {
+        let order = &ctx.accounts.order;
+
+        require!(order.is_terminal(), DarkpoolError::OrderNotTerminal);
+        require!(
+            !ctx.accounts.market.is_settling(order),
+            DarkpoolError::SettlementInProgress
+        );
+
+        ctx.accounts.trader_account.untrack(&order.key());
+
+        emit!(order.closed_event(order.key(), order.to_account_info().lamports()));
+
+        Ok(())
+    }
+
+    /// Permissionless crank: refund an expired order's remaining amount to its
+    /// owner and mark it `Expired`
+    ///
+    /// Also refunds an immediate-or-cancel order the settlement of its batch
+    /// left live, marking it `Cancelled`, once `batch` (the order's
+    /// `batch_seq`) has settled.
+    pub fn expire_order(ctx: Context<ExpireOrder>) -> Result<()> {
+        let order = &mut ctx.accounts.order;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+
+        require!(
+            order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled,
+            DarkpoolError::OrderNotOpen
+        );
+        let expired = order.is_expired(clock.unix_timestamp);
+        let batch_done = order.time_in_force == TimeInForce::ImmediateOrCancel
+            && ctx.accounts.batch.as_ref().is_some_and(|batch| batch.settled);
+        require!(expired || batch_done, DarkpoolError::OrderNotExpired);
+
+        let remaining = order.amount_in
+            .checked_sub(order.filled_amount_in)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        // Refund from the vault holding the order's input token
+        let (vault_account, owner_account, mint_account, token_program) = match order.side {
+            OrderSide::Bid => (
+                ctx.accounts.quote_vault.to_account_info(),
+                ctx.accounts.owner_quote_account.to_account_info(),
+                ctx.accounts.quote_mint.to_account_info(),
+                ctx.accounts.quote_token_program.to_account_info(),
+            ),
+            OrderSide::Ask => (
+                ctx.accounts.base_vault.to_account_info(),
+                ctx.accounts.owner_base_account.to_account_info(),
+                ctx.accounts.base_mint.to_account_info(),
+                ctx.accounts.base_token_program.to_account_info(),
+            ),
+        };
+
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        vault_transfer(
+            &token_program,
+            &vault_account,
+            &mint_account,
+            &owner_account,
+            &market.to_account_info(),
+            signer,
+            remaining,
+        )?;
+
+        order.status = if expired {
+            OrderStatus::Expired
+        } else {
+            OrderStatus::Cancelled
+        };
+
+        let trader = &mut ctx.accounts.trader_account;
+        trader.unlock(order.side, remaining)?;
+        trader.untrack(&order.key());
+        ctx.accounts.market.unlock(order.side, remaining)?;
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        if expired {
+            emit!(OrderExpired {
+                version: EVENT_VERSION,
+                order: order.key(),
+                owner: order.owner,
+                remaining,
+            });
+        } else {
+            emit!(OrderCancelled {
+                version: EVENT_VERSION,
+                order: order.key(),
+                owner: order.owner,
+                remaining,
+            });
+        }
+        emit!(order.closed_event(order.key(), order.to_account_info().lamports()));
+
+        Ok(())
+    }
+
+    /// Permissionless crank for delisted markets: refund an order's remaining
+    /// amount to its owner and close it
+    ///
+    /// Takes the same accounts as `expire_order`. Orders that already finished
+    /// are just closed.
+    pub fn refund_delisted_order(ctx: Context<ExpireOrder>) -> Result<()> {
+        let order = &mut ctx.accounts.order;
+        let market = &ctx.accounts.market;
+
+        require!(market.status == MarketStatus::Closed, DarkpoolError::MarketNotClosed);
+
+        let trader = &mut ctx.accounts.trader_account;
+        if !order.is_terminal() {
+            let remaining = order.amount_in
+                .checked_sub(order.filled_amount_in)
+                .ok_or(DarkpoolError::MathOverflow)?;
+
+            let (vault_account, owner_account, mint_account, token_program) = match order.side {
+                OrderSide::Bid => (
+                    ctx.accounts.quote_vault.to_account_info(),
+                    ctx.accounts.owner_quote_account.to_account_info(),
+                    ctx.accounts.quote_mint.to_account_info(),
+                    ctx.accounts.quote_token_program.to_account_info(),
+                ),
+                OrderSide::Ask => (
+                    ctx.accounts.base_vault.to_account_info(),
+                    ctx.accounts.owner_base_account.to_account_info(),
+                    ctx.accounts.base_mint.to_account_info(),
+                    ctx.accounts.base_token_program.to_account_info(),
+                ),
+            };
+            let seeds = &[
+                b"market",
+                market.base_mint.as_ref(),
+                market.quote_mint.as_ref(),
+                &[market.bump],
+            ];
+            vault_transfer(
+                &token_program,
+                &vault_account,
+                &mint_account,
+                &owner_account,
+                &market.to_account_info(),
+                &[&seeds[..]],
+                remaining,
+            )?;
+
+            trader.unlock(order.side, remaining)?;
+            ctx.accounts.market.unlock(order.side, remaining)?;
+            order.status = OrderStatus::Cancelled;
+
+            emit!(OrderCancelled {
+                version: EVENT_VERSION,
+                order: order.key(),
+                owner: order.owner,
+                remaining,
+            });
+        }
+        trader.untrack(&order.key());
+        debug_check_solvency(
+            &ctx.accounts.market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        emit!(order.closed_event(order.key(), order.to_account_info().lamports()));
+
+        Ok(())
+    }
+
+    /// Permissionless crank: once a delisted market owes its traders
+    /// nothing, close its vaults and the market account, returning the rent
+    /// to the admin
+    ///
+    /// Every order must have been refunded and every claim paid first. What
+    /// the vaults still hold is tracked by no order or claim (uncollected
+    /// fees, tokens sent to the vaults directly) and is swept to the admin's
+    /// token accounts. Token-2022 vaults of a transfer-fee mint also need
+    /// their withheld fees harvested to the mint before they can close.
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        let market = &ctx.accounts.market;
+
+        require!(market.status == MarketStatus::Closed, DarkpoolError::MarketNotClosed);
+        require!(
+            market.locked_base == 0
+                && market.locked_quote == 0
+                && market.unclaimed_base == 0
+                && market.unclaimed_quote == 0,
+            DarkpoolError::VaultsNotEmpty
+        );
+
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let market_info = market.to_account_info();
+        let base = (
+            ctx.accounts.base_token_program.to_account_info(),
+            ctx.accounts.base_mint.to_account_info(),
+            ctx.accounts.admin_base_account.to_account_info(),
+        );
+        let quote = (
+            ctx.accounts.quote_token_program.to_account_info(),
+            ctx.accounts.quote_mint.to_account_info(),
+            ctx.accounts.admin_quote_account.to_account_info(),
+        );
+        for (vault, (token_program, mint, admin_account)) in [
+            (&ctx.accounts.base_vault, &base),
+            (&ctx.accounts.quote_vault, &quote),
+            (&*ctx.accounts.base_fee_vault, &base),
+            (&*ctx.accounts.quote_fee_vault, &quote),
+        ] {
+            let vault_info = vault.to_account_info();
+            vault_transfer(
+                token_program,
+                &vault_info,
+                mint,
+                admin_account,
+                &market_info,
+                signer,
+                vault.amount,
+            )?;
+
+            let cpi_accounts = CloseAccount {
+                account: vault_info,
+                destination: ctx.accounts.admin.to_account_info(),
+                authority: market_info.clone(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+            token_interface::close_account(cpi_ctx)?;
+        }
+
+        // The market account itself is closed to the admin by the `close` constraint
+        emit!(MarketClosed {
+            version: EVENT_VERSION,
+            market: market.key(),
+        });
+
+        Ok(())
+    }
+
+    /// Permissionless check that a market's vaults hold at least everything
+    /// its accounting says they owe: open orders' unfilled remainders and
+    /// unclaimed proceeds
+    ///
+    /// Anything above that is fees a claim-settled batch has yet to move to
+    /// the fee vaults, or tokens sent to the vaults directly.
+    pub fn verify_solvency(ctx: Context<VerifySolvency>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        check_solvency(
+            market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        emit!(SolvencyVerified {
+            version: EVENT_VERSION,
+            market: market.key(),
+            base_vault_amount: ctx.accounts.base_vault.amount,
+            quote_vault_amount: ctx.accounts.quote_vault.amount,
+            locked_base: market.locked_base,
+            locked_quote: market.locked_quote,
+            unclaimed_base: market.unclaimed_base,
+            unclaimed_quote: market.unclaimed_quote,
+        });
+
+        Ok(())
+    }
+
+    /// Open the market's next batch before matching it
+    ///
+    /// `input_commitment` is the `order_commitment` hash of the orders fed
+    /// to the matching computation queued at `computation_offset`. Those
+    /// orders must then be published with `commit_batch_inputs` before the
+    /// batch can be settled, once, by the same solver.
+    pub fn open_batch(
+        ctx: Context<OpenBatch>,
+        input_commitment: [u8; 32],
+        computation_offset: u64,
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        require!(market.status == MarketStatus::Active, DarkpoolError::MarketNotActive);
+
+        let batch = &mut ctx.accounts.batch;
+        batch.market = market.key();
+        batch.seq = market.next_batch_seq;
+        batch.solver = ctx.accounts.solver.key();
+        batch.input_commitment = input_commitment;
+        batch.computation_offset = computation_offset;
+        batch.opened_at = Clock::get()?.unix_timestamp;
+        batch.settled = false;
+        batch.bump = ctx.bumps.batch;
+        batch.fills_applied = 0;
+        batch.claimable_base = 0;
+        batch.claimable_quote = 0;
+        batch.base_fees = 0;
+        batch.quote_fees = 0;
+        batch.claims_root = [0; 32];
+        batch.chunked = false;
+        batch.claims_frontier = [[0; 32]; CLAIMS_TREE_DEPTH];
+        batch.claims_count = 0;
+        batch.inputs_committed = false;
+
+        let inputs = &mut ctx.accounts.batch_inputs;
+        inputs.market = batch.market;
+        inputs.seq = batch.seq;
+        inputs.bump = ctx.bumps.batch_inputs;
+        inputs.orders = Vec::new();
+
+        market.next_batch_seq = market.next_batch_seq
+            .checked_add(1)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        emit!(BatchOpened {
+            version: EVENT_VERSION,
+            market: batch.market,
+            seq: batch.seq,
+            solver: batch.solver,
+            input_commitment,
+            computation_offset,
+        });
+
+        Ok(())
+    }
+
+    /// Publish the next orders of an open batch's input, passed as remaining
+    /// accounts in matching order
+    ///
+    /// Each must be a live order of the market placed before the batch
+    /// opened. Once the published orders hash to the batch's
+    /// `input_commitment` the input is complete and the batch can be
+    /// settled; `challenge_settlement` slashes any fill of an order outside
+    /// it.
+    pub fn commit_batch_inputs(ctx: Context<CommitBatchInputs>) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let batch = &mut ctx.accounts.batch;
+        let now = Clock::get()?.unix_timestamp;
+
+        let inputs = &mut ctx.accounts.batch_inputs;
+        let mut added = Vec::with_capacity(ctx.remaining_accounts.len());
+        for order_info in ctx.remaining_accounts {
+            require!(order_info.owner == &crate::ID, DarkpoolError::InvalidOrderAccount);
+            let order = Order::try_deserialize(&mut &order_info.try_borrow_data()?[..])?;
+            require!(order.market == market_key, DarkpoolError::MismatchedMarket);
+            require!(
+                order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled,
+                DarkpoolError::OrderNotOpen
+            );
+            require!(!order.is_expired(now), DarkpoolError::OrderExpired);
+            require!(order.batch_seq <= batch.seq, DarkpoolError::OrderPlacedAfterBatch);
+
+            let key = order_info.key();
+            require!(
+                !inputs.orders.contains(&key) && !added.contains(&key),
+                DarkpoolError::DuplicateBatchInput
+            );
+            added.push(key);
+        }
+        require!(!added.is_empty(), DarkpoolError::OrderNotFound);
+        require!(
+            inputs.orders.len() + added.len() <= MAX_BATCH_INPUTS,
+            DarkpoolError::TooManyBatchInputs
+        );
+
+        // Make room for the new orders, the solver paying the extra rent
+        let space = 8 + BatchInputs::space(inputs.orders.len() + added.len());
+        grow_account(
+            &inputs.to_account_info(),
+            &ctx.accounts.solver.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            space,
+        )?;
+        inputs.orders.extend_from_slice(&added);
+        batch.inputs_committed = order_commitment(&inputs.orders) == batch.input_commitment;
+
+        emit!(BatchInputsCommitted {
+            version: EVENT_VERSION,
+            market: market_key,
+            seq: batch.seq,
+            orders: added,
+            complete: batch.inputs_committed,
+        });
+
+        Ok(())
+    }
+
+    /// Settle the fills of an open batch, consuming it
+    ///
+    /// Every fill is written to a `SettlementRecord` so it can be checked by
+    /// `challenge_settlement`. The solver passes every immediate-or-cancel
+    /// order of the batch's input that no fill touches after the fills'
+    /// orders, so that they are refunded along with the partially filled ones.
+    pub fn settle_batch(
+        ctx: Context<SettleBatch>,
+        fills: Vec<Fill>,
+        arcium_signature: Vec<u8>,
+    ) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let batch_seq = ctx.accounts.batch.seq;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(
+            ctx.accounts.market.status == MarketStatus::Active,
+            DarkpoolError::MarketNotActive
+        );
+
+        require_bonded(&ctx.accounts.config, &ctx.accounts.solver_bond)?;
+
+        let (settled_fills, base_fees, quote_fees) = push_fills(
+            ctx.remaining_accounts,
+            &mut ctx.accounts.market,
+            &fills,
+            batch_seq,
+            now,
+        )?;
+
+        let batch = &mut ctx.accounts.batch;
+        batch.settled = true;
+
+        let record = &mut ctx.accounts.settlement_record;
+        record.market = market_key;
+        record.solver = ctx.accounts.solver.key();
+        record.seq = batch.seq;
+        record.challenge_deadline = now
+            .checked_add(ctx.accounts.config.unbonding_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        record.input_commitment = batch.input_commitment;
+        record.slashed = false;
+        record.bump = ctx.bumps.settlement_record;
+        record.fills = settled_fills;
+
+        emit!(BatchSettled {
+            version: EVENT_VERSION,
+            market: market_key,
+            seq: batch.seq,
+            fills_count: fills.len() as u32,
+            arcium_signature,
+            base_fees,
+            quote_fees,
+        });
+
+        Ok(())
+    }
+
+    /// Start settling an open batch over several transactions
+    ///
+    /// `fill_commitment` is the `chain_fill` hash of all `fills_total` fills
+    /// of the batch, which `settle_chunk` then pushes in order. The batch's
+    /// `SettlementRecord` is created up front and filled in as chunks land.
+    /// Until the session ends, owners can't cancel, amend, reduce or close
+    /// orders placed before the batch, and the market can't begin another.
+    pub fn begin_settlement(
+        ctx: Context<BeginSettlement>,
+        fill_commitment: [u8; 32],
+        fills_total: u32,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.market.status == MarketStatus::Active,
+            DarkpoolError::MarketNotActive
+        );
+        require!(fills_total > 0, DarkpoolError::InvalidChunk);
+        require!(
+            ctx.accounts.market.settling_batch.is_none(),
+            DarkpoolError::SettlementInProgress
+        );
+
+        let batch = &mut ctx.accounts.batch;
+        batch.chunked = true;
+        ctx.accounts.market.settling_batch = Some(batch.seq);
+
+        let session = &mut ctx.accounts.session;
+        session.batch = batch.key();
+        session.solver = batch.solver;
+        session.fill_commitment = fill_commitment;
+        session.applied_hash = [0; 32];
+        session.fills_total = fills_total;
+        session.fills_applied = 0;
+        session.chunks_applied = 0;
+        session.base_fees = 0;
+        session.quote_fees = 0;
+        session.status = SessionStatus::Open;
+        session.bump = ctx.bumps.session;
+
+        // Can't be closed before the session ends and sets a real deadline
+        let record = &mut ctx.accounts.settlement_record;
+        record.market = batch.market;
+        record.solver = batch.solver;
+        record.seq = batch.seq;
+        record.challenge_deadline = i64::MAX;
+        record.input_commitment = batch.input_commitment;
+        record.slashed = false;
+        record.bump = ctx.bumps.settlement_record;
+        record.fills = Vec::new();
+
+        emit!(SettlementBegun {
+            version: EVENT_VERSION,
+            market: batch.market,
+            seq: batch.seq,
+            fill_commitment,
+            fills_total,
+        });
+
+        Ok(())
+    }
+
+    /// Push-settle the next chunk of a session's fills
+    ///
+    /// Chunks must arrive in order, `chunk_index` counting from 0, and take
+    /// the same remaining accounts as `settle_batch`. An immediate-or-cancel
+    /// order's remainder is refunded at the end of the chunk that fills it,
+    /// so all its fills must be in that chunk; likewise all of a fill-or-kill
+    /// order's fills must be in one chunk.
+    pub fn settle_chunk(
+        ctx: Context<SettleChunk>,
+        chunk_index: u32,
+        fills: Vec<Fill>,
+    ) -> Result<()> {
+        let batch_seq = ctx.accounts.batch.seq;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(
+            ctx.accounts.market.status == MarketStatus::Active,
+            DarkpoolError::MarketNotActive
+        );
+
+        require_bonded(&ctx.accounts.config, &ctx.accounts.solver_bond)?;
+
+        let session = &ctx.accounts.session;
+        require!(chunk_index == session.chunks_applied, DarkpoolError::ChunkOutOfOrder);
+        let fills_applied = u32::try_from(fills.len())
+            .ok()
+            .and_then(|count| session.fills_applied.checked_add(count))
+            .filter(|applied| !fills.is_empty() && *applied <= session.fills_total)
+            .ok_or(DarkpoolError::InvalidChunk)?;
+
+        let (settled_fills, base_fees, quote_fees) = push_fills(
+            ctx.remaining_accounts,
+            &mut ctx.accounts.market,
+            &fills,
+            batch_seq,
+            now,
+        )?;
+
+        let session = &mut ctx.accounts.session;
+        session.applied_hash = fills.iter().fold(session.applied_hash, chain_fill);
+        session.fills_applied = fills_applied;
+        session.chunks_applied += 1;
+        session.base_fees = session.base_fees
+            .checked_add(base_fees)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        session.quote_fees = session.quote_fees
+            .checked_add(quote_fees)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        ctx.accounts.settlement_record.fills.extend(settled_fills);
+
+        emit!(ChunkSettled {
+            version: EVENT_VERSION,
+            market: ctx.accounts.market.key(),
+            seq: batch_seq,
+            chunk_index,
+            fills_count: fills.len() as u32,
+            fills_applied,
+        });
+
+        Ok(())
+    }
+
+    /// Settle a session's batch once every chunk has been pushed
+    ///
+    /// The applied fills must hash to the session's `fill_commitment`; if
+    /// they don't, the session can only be aborted. The challenge window
+    /// opens now, as for `settle_batch`.
+    pub fn finalize_settlement(
+        ctx: Context<FinalizeSettlement>,
+        arcium_signature: Vec<u8>,
+    ) -> Result<()> {
+        let session = &mut ctx.accounts.session;
+        require!(
+            session.fills_applied == session.fills_total,
+            DarkpoolError::SessionIncomplete
+        );
+        require!(
+            session.applied_hash == session.fill_commitment,
+            DarkpoolError::FillCommitmentMismatch
+        );
+        session.status = SessionStatus::Finalized;
+        ctx.accounts.market.settling_batch = None;
+
+        let batch = &mut ctx.accounts.batch;
+        batch.settled = true;
+
+        ctx.accounts.settlement_record.challenge_deadline = Clock::get()?
+            .unix_timestamp
+            .checked_add(ctx.accounts.config.unbonding_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        emit!(BatchSettled {
+            version: EVENT_VERSION,
+            market: batch.market,
+            seq: batch.seq,
+            fills_count: session.fills_total,
+            arcium_signature,
+            base_fees: session.base_fees,
+            quote_fees: session.quote_fees,
+        });
+
+        Ok(())
+    }
+
+    /// Stop a settlement session, by its solver or the admin
+    ///
+    /// Chunks already pushed stay settled, and challengeable, but no more
+    /// can be added and the batch can't be settled any other way.
+    pub fn abort_settlement(ctx: Context<AbortSettlement>) -> Result<()> {
+        let session = &mut ctx.accounts.session;
+        session.status = SessionStatus::Aborted;
+        ctx.accounts.market.settling_batch = None;
+
+        let batch = &mut ctx.accounts.batch;
+        batch.settled = true;
+
+        ctx.accounts.settlement_record.challenge_deadline = Clock::get()?
+            .unix_timestamp
+            .checked_add(ctx.accounts.config.unbonding_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        emit!(SettlementAborted {
+            version: EVENT_VERSION,
+            market: batch.market,
+            seq: batch.seq,
+            fills_applied: session.fills_applied,
+            fills_total: session.fills_total,
+        });
+
+        Ok(())
+    }
+
+    /// Apply a chunk of an open batch's fills to their orders, leaving the
+    /// proceeds in the vaults to be claimed
+    ///
+    /// The alternative to `settle_batch` for batches too large to pay out in
+    /// one transaction: no tokens move until `finalize_claims` commits to
+    /// what each order is due, after which owners withdraw it themselves
+    /// with `claim_fill`. What immediate-or-cancel orders have left is
+    /// refunded by `expire_order` once the batch is finalized.
+    ///
+    /// Each order the call fills adds a `claim_leaf` for its proceeds to the
+    /// batch's claims tree, and each fill goes to the batch's
+    /// `SettlementRecord`, which grows with every call.
+    pub fn apply_claim_fills(ctx: Context<ApplyClaimFills>, fills: Vec<Fill>) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let batch_key = ctx.accounts.batch.key();
+        let batch_seq = ctx.accounts.batch.seq;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(
+            ctx.accounts.market.status == MarketStatus::Active,
+            DarkpoolError::MarketNotActive
+        );
+
+        require_bonded(&ctx.accounts.config, &ctx.accounts.solver_bond)?;
+
+        // Remaining accounts are the order accounts, each once and in order
+        // of first appearance in `fills`, then two accounts per order owner:
+        // [owner, trader account]. The owner wallet receives the rent of
+        // orders the chunk closes
+        let order_keys = fill_orders(&fills);
+        let order_infos = check_order_accounts(ctx.remaining_accounts, &order_keys)?;
+        let order_index = |key: &Pubkey| -> Result<usize> {
+            order_keys
+                .iter()
+                .position(|k| k == key)
+                .ok_or_else(|| DarkpoolError::OrderNotFound.into())
+        };
+
+        let owner_infos = &ctx.remaining_accounts[order_keys.len()..];
+        let owner_chunks = owner_infos.chunks_exact(2);
+        require!(owner_chunks.remainder().is_empty(), DarkpoolError::InvalidOwnerAccounts);
+        let owner_groups: Vec<&[AccountInfo]> = owner_chunks.collect();
+        let owner_index = |owner: &Pubkey| -> Result<usize> {
+            owner_groups
+                .iter()
+                .position(|group| group[0].key == owner)
+                .ok_or_else(|| DarkpoolError::InvalidOrderOwner.into())
+        };
+
+        let mut settlements = vec![OwnerSettlement::default(); owner_groups.len()];
+        // What each order is owed, which becomes its claim
+        let mut order_proceeds = vec![OwnerSettlement::default(); order_keys.len()];
+        let mut settled_fills = Vec::with_capacity(fills.len());
+        let mut base_fees: u64 = 0;
+        let mut quote_fees: u64 = 0;
+
+        for fill in &fills {
+            let order_position = order_index(&fill.order)?;
+            let counterparty_position = order_index(&fill.counterparty)?;
+            let order_account_info = &order_infos[order_position];
+            let counterparty_account_info = &order_infos[counterparty_position];
+
+            let mut order_data = order_account_info.try_borrow_mut_data()?;
+            let mut counterparty_data = counterparty_account_info.try_borrow_mut_data()?;
+            let mut order = Order::try_deserialize(&mut &order_data[..])?;
+            let mut counterparty = Order::try_deserialize(&mut &counterparty_data[..])?;
+
+            settled_fills.push(SettledFill::new(fill, &order, &counterparty));
+
+            let (order_fee, counterparty_fee) = apply_fill(
+                fill,
+                &mut order,
+                &mut counterparty,
+                &ctx.accounts.market,
+                batch_seq,
+                now,
+            )?;
+            ctx.accounts.market.record_volume(order.side, fill)?;
+
+            order.try_serialize(&mut &mut order_data[..])?;
+            counterparty.try_serialize(&mut &mut counterparty_data[..])?;
+
+            drop(order_data);
+            drop(counterparty_data);
+
+            let order_settlement = &mut settlements[owner_index(&order.owner)?];
+            order_settlement.receive(order.side, fill.amount_out - order_fee)?;
+            order_settlement.release(order.side, fill.amount_in)?;
+            let counterparty_settlement = &mut settlements[owner_index(&counterparty.owner)?];
+            counterparty_settlement.receive(counterparty.side, fill.amount_in - counterparty_fee)?;
+            counterparty_settlement.release(counterparty.side, fill.amount_out)?;
+            order_proceeds[order_position].receive(order.side, fill.amount_out - order_fee)?;
+            order_proceeds[counterparty_position]
+                .receive(counterparty.side, fill.amount_in - counterparty_fee)?;
+
+            let (base_fee, quote_fee) = match order.side {
+                OrderSide::Bid => (order_fee, counterparty_fee),
+                OrderSide::Ask => (counterparty_fee, order_fee),
+            };
+            base_fees = base_fees.checked_add(base_fee).ok_or(DarkpoolError::MathOverflow)?;
+            quote_fees = quote_fees.checked_add(quote_fee).ok_or(DarkpoolError::MathOverflow)?;
+        }
+
+        // Orders are done with once terminal; what they are owed is claimed
+        // against the batch, not the order
+        let batch = &mut ctx.accounts.batch;
+        for (order_info, proceeds) in order_infos.iter().zip(&order_proceeds) {
+            let order = Order::try_deserialize(&mut &order_info.try_borrow_data()?[..])?;
+            check_fill_or_kill(&order)?;
+
+            let (base_amount, quote_amount) = (proceeds.base_owed, proceeds.quote_owed);
+            if base_amount > 0 || quote_amount > 0 {
+                let leaf_index = batch.claims_count;
+                let leaf = claim_leaf(
+                    &batch_key,
+                    leaf_index,
+                    &order_info.key(),
+                    &order.owner,
+                    base_amount,
+                    quote_amount,
+                );
+                batch.push_claim(leaf)?;
+                emit!(ClaimAdded {
+                    version: EVENT_VERSION,
+                    market: market_key,
+                    batch_seq,
+                    leaf_index,
+                    order: order_info.key(),
+                    owner: order.owner,
+                    base_amount,
+                    quote_amount,
+                });
+            }
+
+            if order.is_terminal() {
+                let owner = owner_index(&order.owner)?;
+                settlements[owner].closed_orders.push(order_info.key());
+                close_order_account(order_info, &owner_groups[owner][0], &order)?;
+            }
+        }
+
+        // What the chunk released stays in the vaults as unclaimed proceeds
+        let market = &mut ctx.accounts.market;
+        for (group, settlement) in owner_groups.iter().zip(&settlements) {
+            update_trader_account(&group[1], group[0].key, &market_key, |trader| {
+                settlement.apply(trader)
+            })?;
+            settlement.apply_to_market(market)?;
+            batch.claimable_base = batch.claimable_base
+                .checked_add(settlement.base_owed)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            batch.claimable_quote = batch.claimable_quote
+                .checked_add(settlement.quote_owed)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            market.unclaimed_base = market.unclaimed_base
+                .checked_add(settlement.base_owed)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            market.unclaimed_quote = market.unclaimed_quote
+                .checked_add(settlement.quote_owed)
+                .ok_or(DarkpoolError::MathOverflow)?;
+        }
+        batch.base_fees = batch.base_fees.checked_add(base_fees).ok_or(DarkpoolError::MathOverflow)?;
+        batch.quote_fees = batch.quote_fees.checked_add(quote_fees).ok_or(DarkpoolError::MathOverflow)?;
+        // The first call creates the record; it can't be closed before
+        // `finalize_claims` sets a real deadline
+        let record = &mut ctx.accounts.settlement_record;
+        if batch.fills_applied == 0 {
+            record.market = batch.market;
+            record.solver = batch.solver;
+            record.seq = batch.seq;
+            record.challenge_deadline = i64::MAX;
+            record.input_commitment = batch.input_commitment;
+            record.slashed = false;
+            record.bump = ctx.bumps.settlement_record;
+            record.fills = Vec::new();
+        }
+
+        // Make room for this call's fills, the solver paying the extra rent
+        let space = 8 + SettlementRecord::space(record.fills.len() + settled_fills.len());
+        grow_account(
+            &record.to_account_info(),
+            &ctx.accounts.solver.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            space,
+        )?;
+        record.fills.extend(settled_fills);
+
+        batch.fills_applied = u32::try_from(fills.len())
+            .ok()
+            .and_then(|count| batch.fills_applied.checked_add(count))
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Close a claim-settled batch by committing to what each of its orders
+    /// is due
+    ///
+    /// Fixes the root of the claims tree `apply_claim_fills` built, which
+    /// `claim_fill` proves against. The batch's solver may finalize at any
+    /// time; anyone may once `CLAIMS_FINALIZE_TIMEOUT` has passed since the
+    /// batch opened, so a stalled solver can't leave its proceeds unclaimable.
+    /// The batch's fees go to the fee vaults; its proceeds have counted as
+    /// unclaimed on the market since their fills were applied. The
+    /// settlement can be challenged for `config.unbonding_delay` from here.
+    pub fn finalize_claims(ctx: Context<FinalizeClaims>, arcium_signature: Vec<u8>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let batch = &mut ctx.accounts.batch;
+        if ctx.accounts.authority.key() != batch.solver {
+            let timeout = batch.opened_at
+                .checked_add(CLAIMS_FINALIZE_TIMEOUT)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            require!(now >= timeout, DarkpoolError::FinalizeTimeoutNotElapsed);
+        }
+        batch.claims_root = batch.claims_tree_root();
+        batch.settled = true;
+
+        ctx.accounts.settlement_record.challenge_deadline = now
+            .checked_add(ctx.accounts.config.unbonding_delay)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        let market = &ctx.accounts.market;
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let base_token_program = ctx.accounts.base_token_program.to_account_info();
+        let quote_token_program = ctx.accounts.quote_token_program.to_account_info();
+        let market_info = market.to_account_info();
+        vault_transfer(
+            &base_token_program,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.base_mint.to_account_info(),
+            &ctx.accounts.base_fee_vault.to_account_info(),
+            &market_info,
+            signer,
+            batch.base_fees,
+        )?;
+        vault_transfer(
+            &quote_token_program,
+            &ctx.accounts.quote_vault.to_account_info(),
+            &ctx.accounts.quote_mint.to_account_info(),
+            &ctx.accounts.quote_fee_vault.to_account_info(),
+            &market_info,
+            signer,
+            batch.quote_fees,
+        )?;
+        debug_check_solvency(
+            market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        emit!(BatchSettled {
+            version: EVENT_VERSION,
+            market: market.key(),
+            seq: batch.seq,
+            fills_count: batch.fills_applied,
+            arcium_signature,
+            base_fees: batch.base_fees,
+            quote_fees: batch.quote_fees,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraw what `order` is due from a finalized claim-settled batch,
+    /// proving it with the path from its `claim_leaf` to the batch's root
+    ///
+    /// Each leaf can be claimed once; its `ClaimReceipt` is created here and
+    /// kept so the claim can't be replayed.
+    pub fn claim_fill(
+        ctx: Context<ClaimFill>,
+        leaf_index: u32,
+        order: Pubkey,
+        base_amount: u64,
+        quote_amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let batch = &mut ctx.accounts.batch;
+        let owner = ctx.accounts.owner.key();
+
+        let leaf = claim_leaf(&batch.key(), leaf_index, &order, &owner, base_amount, quote_amount);
+        require!(
+            verify_claim_proof(&proof, &batch.claims_root, leaf),
+            DarkpoolError::InvalidClaimProof
+        );
+
+        // A root can't pay out more than the batch's fills left behind
+        batch.claimable_base = batch.claimable_base
+            .checked_sub(base_amount)
+            .ok_or(DarkpoolError::ClaimExceedsBatch)?;
+        batch.claimable_quote = batch.claimable_quote
+            .checked_sub(quote_amount)
+            .ok_or(DarkpoolError::ClaimExceedsBatch)?;
+
+        let market = &mut ctx.accounts.market;
+        market.unclaimed_base = market.unclaimed_base
+            .checked_sub(base_amount)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        market.unclaimed_quote = market.unclaimed_quote
+            .checked_sub(quote_amount)
+            .ok_or(DarkpoolError::MathOverflow)?;
+
+        ctx.accounts.claim_receipt.bump = ctx.bumps.claim_receipt;
+
+        let market = &ctx.accounts.market;
+        let seeds = &[
+            b"market",
+            market.base_mint.as_ref(),
+            market.quote_mint.as_ref(),
+            &[market.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let base_token_program = ctx.accounts.base_token_program.to_account_info();
+        let quote_token_program = ctx.accounts.quote_token_program.to_account_info();
+        let market_info = market.to_account_info();
+        vault_transfer(
+            &base_token_program,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.base_mint.to_account_info(),
+            &ctx.accounts.owner_base_account.to_account_info(),
+            &market_info,
+            signer,
+            base_amount,
+        )?;
+        vault_transfer(
+            &quote_token_program,
+            &ctx.accounts.quote_vault.to_account_info(),
+            &ctx.accounts.quote_mint.to_account_info(),
+            &ctx.accounts.owner_quote_account.to_account_info(),
+            &market_info,
+            signer,
+            quote_amount,
+        )?;
+        debug_check_solvency(
+            market,
+            &ctx.accounts.base_vault.to_account_info(),
+            &ctx.accounts.quote_vault.to_account_info(),
+        )?;
+
+        emit!(FillClaimed {
+            version: EVENT_VERSION,
+            market: market.key(),
+            batch_seq: ctx.accounts.batch.seq,
+            order,
+            owner,
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Prove that fill `fill_index` of a settled batch broke the rules and
+    /// slash the solver's bond to the owners it hurt
+    ///
+    /// Each settlement can be slashed once, until its challenge deadline.
+    /// `config.slash_amount` (or whatever is left of the bond) is split
+    /// between the affected owners, taken from unbonding stake first.
+    pub fn challenge_settlement(
+        ctx: Context<ChallengeSettlement>,
+        fill_index: u32,
+        proof: ChallengeProof,
+    ) -> Result<()> {
+        let record = &ctx.accounts.settlement_record;
+        require!(!record.slashed, DarkpoolError::AlreadySlashed);
+        require!(
+            Clock::get()?.unix_timestamp < record.challenge_deadline,
+            DarkpoolError::ChallengeWindowClosed
+        );
+
+        let fill = record.fills
+            .get(fill_index as usize)
+            .ok_or(DarkpoolError::InvalidChallenge)?;
+
+        // Which sides of the fill the violation hurt
+        let (order_affected, counterparty_affected) = match proof {
+            ChallengeProof::OrderOutsideCommitment => {
+                let orders = &ctx.accounts.batch_inputs.orders;
+                (
+                    !orders.contains(&fill.order),
+                    !orders.contains(&fill.counterparty),
+                )
+            }
+        };
+        require!(
+            order_affected || counterparty_affected,
+            DarkpoolError::InvalidChallenge
+        );
+
+        require!(
+            ctx.accounts.order_owner_account.owner == fill.order_owner,
+            DarkpoolError::InvalidOrderOwner
+        );
+        require!(
+            ctx.accounts.counterparty_owner_account.owner == fill.counterparty_owner,
+            DarkpoolError::InvalidOrderOwner
+        );
+
+        let bond = &mut ctx.accounts.solver_bond;
+        let available = bond.amount
+            .checked_add(bond.unbonding_amount)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        let slashed = ctx.accounts.config.slash_amount.min(available);
+        let from_unbonding = slashed.min(bond.unbonding_amount);
+        bond.unbonding_amount -= from_unbonding;
+        bond.amount -= slashed - from_unbonding;
+
+        // Split between the affected owners, any odd unit going to the order
+        let (order_share, counterparty_share) = match (order_affected, counterparty_affected) {
+            (true, true) => (slashed - slashed / 2, slashed / 2),
+            (true, false) => (slashed, 0),
+            _ => (0, slashed),
+        };
+
+        let solver = bond.solver;
+        let seeds = &[b"bond".as_ref(), solver.as_ref(), &[bond.bump]];
+        let signer = &[&seeds[..]];
+        let token_program = ctx.accounts.token_program.to_account_info();
+        let bond_vault = ctx.accounts.bond_vault.to_account_info();
+        let bond_info = ctx.accounts.solver_bond.to_account_info();
+        vault_transfer(
+            &token_program,
+            &bond_vault,
+            &ctx.accounts.bond_mint.to_account_info(),
+            &ctx.accounts.order_owner_account.to_account_info(),
+            &bond_info,
+            signer,
+            order_share,
+        )?;
+        vault_transfer(
+            &token_program,
+            &bond_vault,
+            &ctx.accounts.bond_mint.to_account_info(),
+            &ctx.accounts.counterparty_owner_account.to_account_info(),
+            &bond_info,
+            signer,
+            counterparty_share,
+        )?;
+
+        let record = &mut ctx.accounts.settlement_record;
+        record.slashed = true;
+
+        emit!(SolverSlashed {
+            version: EVENT_VERSION,
+            solver,
+            market: record.market,
+            seq: record.seq,
+            fill_index,
+            challenger: ctx.accounts.challenger.key(),
+            amount: slashed,
+        });
+
+        Ok(())
+    }
+
+    /// Return a settlement record's and its batch inputs' rent to the solver
+    /// once the settlement can no longer be challenged
+    pub fn close_settlement_record(ctx: Context<CloseSettlementRecord>) -> Result<()> {
+        require!(
+            Clock::get()?.unix_timestamp >= ctx.accounts.settlement_record.challenge_deadline,
+            DarkpoolError::ChallengeWindowOpen
+        );
+        Ok(())
+    }
+}
+
+/// Commitment to the set of orders a batch was matched over: the SHA-256
+/// hash of their pubkeys, in the order the solver fed them to the matcher
+pub fn order_commitment(orders: &[Pubkey]) -> [u8; 32] {
+    let keys: Vec<&[u8]> = orders.iter().map(|key| key.as_ref()).collect();
+    hashv(&keys).to_bytes()
+}
+
+/// Resize `account` to `space` bytes, `payer` covering the extra rent
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+) -> Result<()> {
+    let rent = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
+    if rent > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                anchor_lang::system_program::Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            rent,
+        )?;
+    }
+    account.resize(space)?;
+    Ok(())
+}
+
+/// The distinct orders of `fills`, in order of first appearance, which is
+/// how settlement instructions expect their accounts
+fn fill_orders(fills: &[Fill]) -> Vec<Pubkey> {
+    let mut order_keys: Vec<Pubkey> = Vec::new();
+    for fill in fills {
+        for key in [fill.order, fill.counterparty] {
+            if !order_keys.contains(&key) {
+                order_keys.push(key);
+            }
+        }
+    }
+    order_keys
+}
+
+/// Check that `accounts` start with the order accounts `order_keys`, each
+/// owned by this program, and return them
+fn check_order_accounts<'a, 'info>(
+    accounts: &'a [AccountInfo<'info>],
+    order_keys: &[Pubkey],
+) -> Result<&'a [AccountInfo<'info>]> {
+    let order_infos = accounts
+        .get(..order_keys.len())
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    for (order_info, key) in order_infos.iter().zip(order_keys) {
+        require!(order_info.key() == *key, DarkpoolError::OrderNotFound);
+        require!(order_info.owner == &crate::ID, DarkpoolError::InvalidOrderAccount);
+    }
+    Ok(order_infos)
+}
+
+/// Extend a `SettlementSession`'s running hash of fills with `fill`
+///
+/// Starting from all zeroes, folding every fill of a batch in order gives
+/// the session's `fill_commitment`.
+pub fn chain_fill(hash: [u8; 32], fill: &Fill) -> [u8; 32] {
+    hashv(&[
+        &hash,
+        fill.order.as_ref(),
+        fill.counterparty.as_ref(),
+        &fill.amount_in.to_le_bytes(),
+        &fill.amount_out.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+/// Leaf `index` of a claim-settled batch's claims tree: `base_amount` and
+/// `quote_amount` are due to `owner` for `order`
+///
+/// Leaves are prefixed with 0 and inner nodes with 1 so a node can't pass
+/// as a leaf. An order gets a leaf for each `apply_claim_fills` call it was
+/// filled in, told apart by their index.
+pub fn claim_leaf(
+    batch: &Pubkey,
+    index: u32,
+    order: &Pubkey,
+    owner: &Pubkey,
+    base_amount: u64,
+    quote_amount: u64,
+) -> [u8; 32] {
+    hashv(&[
+        &[0],
+        batch.as_ref(),
+        &index.to_le_bytes(),
+        order.as_ref(),
+        owner.as_ref(),
+        &base_amount.to_le_bytes(),
+        &quote_amount.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+/// Inner node of a claims tree over `a` and `b`, hashed smaller first
+pub fn claim_node(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[&[1], &left, &right]).to_bytes()
+}
+
+/// Whether `proof` is a full-depth path from `leaf` to `root`
+pub fn verify_claim_proof(proof: &[[u8; 32]], root: &[u8; 32], leaf: [u8; 32]) -> bool {
+    proof.len() == CLAIMS_TREE_DEPTH
+        && proof.iter().fold(leaf, |node, sibling| claim_node(node, *sibling)) == *root
+}
+
+/// Whether a side of a fill that received `received` fell short of its
+/// order's `min_amount_out`
+///
+/// This is the matcher's rule: a bid is only filled when that one fill gives
+/// it at least `min_amount_out` base. Asks have no per-fill minimum.
+fn below_min_out(side: OrderSide, received: u64, min_amount_out: u64) -> bool {
+    side == OrderSide::Bid && received < min_amount_out
+}
+
+/// Fee charged on `amount` at `fee_bps`, rounded down in the payer's favour
+pub fn fee_amount(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(DarkpoolError::MathOverflow)?
+        / BPS_DENOMINATOR as u128;
+    u64::try_from(fee).map_err(|_| DarkpoolError::MathOverflow.into())
+}
+
+/// Once a minimum bond is set, only solvers with that much active bond in
+/// the bond mint may settle
+fn require_bonded(config: &Config, solver_bond: &Option<Account<SolverBond>>) -> Result<()> {
+    if config.min_bond > 0 {
+        let bond = solver_bond.as_ref().ok_or(DarkpoolError::InsufficientBond)?;
+        require!(
+            bond.mint == config.bond_mint && bond.amount >= config.min_bond,
+            DarkpoolError::InsufficientBond
+        );
+    }
+    Ok(())
+}
+
+/// Check `fill` against both its orders and apply it to them, returning the
+/// fee each side pays out of what it receives
+///
+/// Shared by push (`settle_batch`) and claim (`apply_claim_fills`)
+/// settlement, which only differ in how proceeds reach the owners.
+fn apply_fill(
+    fill: &Fill,
+    order: &mut Order,
+    counterparty: &mut Order,
+    market: &Account<Market>,
+    batch_seq: u64,
+    now: i64,
+) -> Result<(u64, u64)> {
+    let market_key = market.key();
+
+    // Validate orders
+    require!(order.market == market_key, DarkpoolError::MismatchedMarket);
+    require!(
+        counterparty.market == market_key,
+        DarkpoolError::MismatchedMarket
+    );
+    require!(
+        order.status != OrderStatus::Cancelled,
+        DarkpoolError::OrderCancelled
+    );
+    require!(
+        counterparty.status != OrderStatus::Cancelled,
+        DarkpoolError::OrderCancelled
+    );
+    require!(!order.is_expired(now), DarkpoolError::OrderExpired);
+    require!(!counterparty.is_expired(now), DarkpoolError::OrderExpired);
+
+    // Ensure orders are opposite sides
+    require!(
+        order.side != counterparty.side,
+        DarkpoolError::SameSideOrders
+    );
+
+    // The order receives amount_out and the counterparty amount_in
+    require!(
+        !below_min_out(order.side, fill.amount_out, order.min_amount_out)
+            && !below_min_out(counterparty.side, fill.amount_in, counterparty.min_amount_out),
+        DarkpoolError::MinAmountOutNotMet
+    );
+
+    // Immediate-or-cancel orders only trade in their own batch. Fill-or-kill
+    // orders are checked once all of a call's fills are applied, since they
+    // may take several
+    for side in [&*order, &*counterparty] {
+        require!(
+            side.time_in_force != TimeInForce::ImmediateOrCancel || side.batch_seq == batch_seq,
+            DarkpoolError::OrderExpired
+        );
+    }
+
+    // Validate fill amounts
+    let order_new_filled = order
+        .filled_amount_in
+        .checked_add(fill.amount_in)
+        .ok_or(DarkpoolError::MathOverflow)?;
+    require!(
+        order_new_filled <= order.amount_in,
+        DarkpoolError::InsufficientRemaining
+    );
+
+    let counterparty_new_filled = counterparty
+        .filled_amount_in
+        .checked_add(fill.amount_out)
+        .ok_or(DarkpoolError::MathOverflow)?;
+    require!(
+        counterparty_new_filled <= counterparty.amount_in,
+        DarkpoolError::InsufficientRemaining
+    );
+
+    // Update filled amounts
+    order.filled_amount_in = order_new_filled;
+    counterparty.filled_amount_in = counterparty_new_filled;
+
+    // Update status
+    if order.filled_amount_in == order.amount_in {
+        order.status = OrderStatus::Filled;
+    } else {
+        order.status = OrderStatus::PartiallyFilled;
+    }
+
+    if counterparty.filled_amount_in == counterparty.amount_in {
+        counterparty.status = OrderStatus::Filled;
+    } else {
+        counterparty.status = OrderStatus::PartiallyFilled;
+    }
+
+    // The older order was resting in the pool and is the maker;
+    // on a tie the counterparty is treated as the maker
+    let (order_fee_bps, counterparty_fee_bps) = if order.created_at < counterparty.created_at {
+        (market.maker_fee_bps, market.taker_fee_bps)
+    } else {
+        (market.taker_fee_bps, market.maker_fee_bps)
+    };
+
+    // Each side pays its fee out of the proceeds it receives
+    let order_fee = fee_amount(fill.amount_out, order_fee_bps)?;
+    let counterparty_fee = fee_amount(fill.amount_in, counterparty_fee_bps)?;
+
+    emit!(OrderFilled {
+        version: EVENT_VERSION,
+        market: market_key,
+        batch_seq,
+        order: fill.order,
+        counterparty: fill.counterparty,
+        side: order.side,
+        amount_in: fill.amount_in,
+        amount_out: fill.amount_out,
+        order_fee,
+        counterparty_fee,
+        order_status: order.status,
+        counterparty_status: counterparty.status,
+    });
+
+    Ok((order_fee, counterparty_fee))
+}
+
+/// Check that a fill-or-kill order a settlement call has filled ended the
+/// call filled in full
+fn check_fill_or_kill(order: &Order) -> Result<()> {
+    require!(
+        order.time_in_force != TimeInForce::FillOrKill || order.status == OrderStatus::Filled,
+        DarkpoolError::FillOrKillNotFilled
+    );
+    Ok(())
+}
+
+/// Push-settle `fills` against the accounts `settle_batch` documents as its
+/// remaining accounts, returning the fills' challenge snapshots and the base
+/// and quote fees collected
+///
+/// Each owner is paid once per token for all of `fills`; immediate-or-cancel
+/// remainders are refunded, along with any unfilled immediate-or-cancel
+/// orders passed after the fills' orders, and terminal orders closed once
+/// they are applied.
+fn push_fills<'info>(
+    remaining_accounts: &[AccountInfo<'info>],
+    market: &mut Account<Market>,
+    fills: &[Fill],
+    batch_seq: u64,
+    now: i64,
+) -> Result<(Vec<SettledFill>, u64, u64)> {
+    let market_key = market.key();
+    let market_bump = market.bump;
+    let base_mint = market.base_mint;
+    let quote_mint = market.quote_mint;
+    let base_vault_key = market.base_vault;
+    let quote_vault_key = market.quote_vault;
+    let base_fee_vault_key = market.base_fee_vault;
+    let quote_fee_vault_key = market.quote_fee_vault;
+    let base_token_program = market.base_token_program;
+    let quote_token_program = market.quote_token_program;
+
+    let seeds = &[
+        b"market",
+        base_mint.as_ref(),
+        quote_mint.as_ref(),
+        &[market_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    // Get all accounts from remaining_accounts to avoid lifetime conflicts
+    // Order: [base_vault, quote_vault, market, base_token_program,
+    //         quote_token_program, base_fee_vault, quote_fee_vault,
+    //         base_mint, quote_mint, then order and owner accounts...]
+    const HEADER_LEN: usize = 9;
+    let base_vault_info = remaining_accounts
+        .first()
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let quote_vault_info = remaining_accounts
+        .get(1)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let market_info = remaining_accounts
+        .get(2)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let base_token_program_info = remaining_accounts
+        .get(3)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let quote_token_program_info = remaining_accounts
+        .get(4)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let base_fee_vault_info = remaining_accounts
+        .get(5)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let quote_fee_vault_info = remaining_accounts
+        .get(6)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let base_mint_info = remaining_accounts
+        .get(7)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    let quote_mint_info = remaining_accounts
+        .get(8)
+        .ok_or(DarkpoolError::OrderNotFound)?;
+    
+    // Validate accounts
+    require!(
+        base_vault_info.key() == base_vault_key,
+        DarkpoolError::InvalidTokenAccount
+    );
+    require!(
+        quote_vault_info.key() == quote_vault_key,
+        DarkpoolError::InvalidTokenAccount
+    );
+    require!(
+        market_info.key() == market_key,
+        DarkpoolError::MismatchedMarket
+    );
+    require!(
+        base_token_program_info.key() == base_token_program,
+        DarkpoolError::InvalidTokenProgram
+    );
+    require!(
+        quote_token_program_info.key() == quote_token_program,
+        DarkpoolError::InvalidTokenProgram
+    );
+    require!(
+        base_fee_vault_info.key() == base_fee_vault_key,
+        DarkpoolError::InvalidFeeVault
+    );
+    require!(
+        quote_fee_vault_info.key() == quote_fee_vault_key,
+        DarkpoolError::InvalidFeeVault
+    );
+    require!(base_mint_info.key() == base_mint, DarkpoolError::InvalidMint);
+    require!(quote_mint_info.key() == quote_mint, DarkpoolError::InvalidMint);
+
+    // After the header come the order accounts, each once and in order
+    // of first appearance in `fills`, then the batch's immediate-or-cancel
+    // orders that no fill touches, then four accounts per order owner:
+    // [owner, base account, quote account, trader account]. The owner
+    // wallet receives the rent of orders the batch closes. Owner wallets
+    // are never owned by this program, which is where the orders end
+    let order_keys = fill_orders(fills);
+    let order_infos = check_order_accounts(&remaining_accounts[HEADER_LEN..], &order_keys)?;
+    let unfilled_start = HEADER_LEN + order_keys.len();
+    let unfilled_count = remaining_accounts[unfilled_start..]
+        .iter()
+        .take_while(|info| info.owner == &crate::ID)
+        .count();
+    let unfilled_infos = &remaining_accounts[unfilled_start..unfilled_start + unfilled_count];
+
+    let order_index = |key: &Pubkey| -> Result<usize> {
+        order_keys
+            .iter()
+            .position(|k| k == key)
+            .ok_or_else(|| DarkpoolError::OrderNotFound.into())
+    };
+
+    let owner_infos = &remaining_accounts[unfilled_start + unfilled_count..];
+    let owner_chunks = owner_infos.chunks_exact(4);
+    require!(owner_chunks.remainder().is_empty(), DarkpoolError::InvalidOwnerAccounts);
+    let owner_groups: Vec<&[AccountInfo]> = owner_chunks.collect();
+    for group in &owner_groups {
+        check_payout_account(&group[1], group[0].key, &base_mint, &base_token_program)?;
+        check_payout_account(&group[2], group[0].key, &quote_mint, &quote_token_program)?;
+    }
+    let owner_index = |owner: &Pubkey| -> Result<usize> {
+        owner_groups
+            .iter()
+            .position(|group| group[0].key == owner)
+            .ok_or_else(|| DarkpoolError::InvalidOrderOwner.into())
+    };
+
+    // Transfers are netted: what each owner is due accumulates across
+    // all fills and is paid out once per token at the end
+    let mut settlements = vec![OwnerSettlement::default(); owner_groups.len()];
+    let mut base_fees: u64 = 0;
+    let mut quote_fees: u64 = 0;
+    let mut settled_fills = Vec::with_capacity(fills.len());
+
+    for fill in fills {
+        let order_account_info = &order_infos[order_index(&fill.order)?];
+        let counterparty_account_info = &order_infos[order_index(&fill.counterparty)?];
+
+        // Load and deserialize orders
+        let mut order_data = order_account_info.try_borrow_mut_data()?;
+        let mut counterparty_data = counterparty_account_info.try_borrow_mut_data()?;
+
+        // Deserialize orders (try_deserialize checks the discriminator)
+        let mut order = Order::try_deserialize(&mut &order_data[..])?;
+        let mut counterparty = Order::try_deserialize(&mut &counterparty_data[..])?;
+
+        // Snapshot the limits the fill is challengeable against; the
+        // order accounts may be closed by the end of the batch
+        settled_fills.push(SettledFill::new(fill, &order, &counterparty));
+
+        let (order_fee, counterparty_fee) = apply_fill(
+            fill,
+            &mut order,
+            &mut counterparty,
+            market,
+            batch_seq,
+            now,
+        )?;
+        market.record_volume(order.side, fill)?;
+
+        // Serialize back
+        order.try_serialize(&mut &mut order_data[..])?;
+        counterparty.try_serialize(&mut &mut counterparty_data[..])?;
+
+        drop(order_data);
+        drop(counterparty_data);
+
+        // Each side is paid the other's token less its fee, and what it
+        // gave is released from its locked amount
+        let order_settlement = &mut settlements[owner_index(&order.owner)?];
+        order_settlement.receive(order.side, fill.amount_out - order_fee)?;
+        order_settlement.release(order.side, fill.amount_in)?;
+        let counterparty_settlement = &mut settlements[owner_index(&counterparty.owner)?];
+        counterparty_settlement.receive(counterparty.side, fill.amount_in - counterparty_fee)?;
+        counterparty_settlement.release(counterparty.side, fill.amount_out)?;
+
+        let (base_fee, quote_fee) = match order.side {
+            OrderSide::Bid => (order_fee, counterparty_fee),
+            OrderSide::Ask => (counterparty_fee, order_fee),
+        };
+        base_fees = base_fees.checked_add(base_fee).ok_or(DarkpoolError::MathOverflow)?;
+        quote_fees = quote_fees.checked_add(quote_fee).ok_or(DarkpoolError::MathOverflow)?;
+    }
+
+    // The unfilled immediate-or-cancel orders must be this market's and
+    // due to trade in this batch or an earlier one
+    for order_info in unfilled_infos {
+        require!(
+            !order_keys.contains(order_info.key),
+            DarkpoolError::InvalidOrderAccount
+        );
+        let order = Order::try_deserialize(&mut &order_info.try_borrow_data()?[..])?;
+        require!(order.market == market_key, DarkpoolError::MismatchedMarket);
+        require!(
+            order.time_in_force == TimeInForce::ImmediateOrCancel && order.batch_seq <= batch_seq,
+            DarkpoolError::InvalidOrderAccount
+        );
+        require!(!order.is_terminal(), DarkpoolError::OrderNotOpen);
+    }
+
+    // Once all fills are applied, check fill-or-kill orders were filled in
+    // full and refund what immediate-or-cancel orders have left (they only
+    // trade in one batch), then close every order that has reached a
+    // terminal state
+    for order_info in order_infos.iter().chain(unfilled_infos) {
+        let mut order_data = order_info.try_borrow_mut_data()?;
+        let mut order = Order::try_deserialize(&mut &order_data[..])?;
+        let owner = owner_index(&order.owner)?;
+        check_fill_or_kill(&order)?;
+
+        if order.time_in_force == TimeInForce::ImmediateOrCancel && !order.is_terminal() {
+            let remaining = order.amount_in
+                .checked_sub(order.filled_amount_in)
+                .ok_or(DarkpoolError::MathOverflow)?;
+            order.status = OrderStatus::Cancelled;
+            order.try_serialize(&mut &mut order_data[..])?;
+            settlements[owner].refund(order.side, remaining)?;
+
+            emit!(OrderCancelled {
+                version: EVENT_VERSION,
+                order: order_info.key(),
+                owner: order.owner,
+                remaining,
+            });
+        }
+        drop(order_data);
+
+        if order.is_terminal() {
+            settlements[owner].closed_orders.push(order_info.key());
+            close_order_account(order_info, &owner_groups[owner][0], &order)?;
+        }
+    }
+
+    // One transfer per owner per token, and one update per trader account
+    for (group, settlement) in owner_groups.iter().zip(&settlements) {
+        vault_transfer(base_token_program_info, base_vault_info, base_mint_info, &group[1], market_info, signer, settlement.base_owed)?;
+        vault_transfer(quote_token_program_info, quote_vault_info, quote_mint_info, &group[2], market_info, signer, settlement.quote_owed)?;
+        update_trader_account(&group[3], group[0].key, &market_key, |trader| {
+            settlement.apply(trader)
+        })?;
+        settlement.apply_to_market(market)?;
+    }
+    vault_transfer(base_token_program_info, base_vault_info, base_mint_info, base_fee_vault_info, market_info, signer, base_fees)?;
+    vault_transfer(quote_token_program_info, quote_vault_info, quote_mint_info, quote_fee_vault_info, market_info, signer, quote_fees)?;
+
+    debug_check_solvency(market, base_vault_info, quote_vault_info)?;
+
+    Ok((settled_fills, base_fees, quote_fees))
+}
+
+/// Close an order account that isn't part of the instruction's `Accounts`,
+/// moving its rent to `owner` and emitting `OrderClosed` with `order`'s state
+fn close_order_account<'info>(
+    order_info: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    order: &Order,
+) -> Result<()> {
+    let lamports = order_info.lamports();
+    emit!(order.closed_event(order_info.key(), lamports));
+
+    // Same steps as Anchor's `close` constraint
+    **owner.try_borrow_mut_lamports()? = owner
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(DarkpoolError::MathOverflow)?;
+    **order_info.try_borrow_mut_lamports()? = 0;
+    order_info.assign(&system_program::ID);
+    order_info.resize(0)?;
+
+    Ok(())
+}
+
+/// The market a queued change targets, which must have been passed to
+/// `execute_change`
+fn change_market<'a, 'info>(
+    market: &'a mut Option<Account<'info, Market>>,
+    target: Pubkey,
+) -> Result<&'a mut Account<'info, Market>> {
+    let market = market.as_mut().ok_or(DarkpoolError::MismatchedMarket)?;
+    require!(market.key() == target, DarkpoolError::MismatchedMarket);
+    Ok(market)
+}
+
+/// Apply `update` to a trader account passed through `remaining_accounts`
+///
+/// The account must be owned by this program and belong to `owner` in
+/// `market`.
+fn update_trader_account<'info>(
+    trader_info: &AccountInfo<'info>,
+    owner: &Pubkey,
+    market: &Pubkey,
+    update: impl FnOnce(&mut TraderAccount) -> Result<()>,
+) -> Result<()> {
+    require!(trader_info.owner == &crate::ID, DarkpoolError::InvalidTraderAccount);
+
+    let mut trader_data = trader_info.try_borrow_mut_data()?;
+    let mut trader = TraderAccount::try_deserialize(&mut &trader_data[..])?;
+    require!(
+        trader.owner == *owner && trader.market == *market,
+        DarkpoolError::InvalidTraderAccount
+    );
+
+    update(&mut trader)?;
+    trader.try_serialize(&mut &mut trader_data[..])
+}
+
+/// Check that an account `settle_batch` pays out to is `owner`'s token
+/// account for `mint`, held by the mint's `token_program`
+fn check_payout_account(
+    info: &AccountInfo,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Result<()> {
+    require!(info.owner == token_program, DarkpoolError::InvalidTokenAccount);
+
+    let account = TokenAccount::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+    require!(account.owner == *owner, DarkpoolError::InvalidOrderOwner);
+    require!(account.mint == *mint, DarkpoolError::InvalidMint);
+    Ok(())
+}
+
+/// What one owner is due from a batch, paid out once all its fills are
+/// applied
+#[derive(Clone, Default)]
+struct OwnerSettlement {
+    /// Proceeds and refunds to transfer to the owner
+    base_owed: u64,
+    quote_owed: u64,
+    /// To release from the trader account's locked amounts
+    base_released: u64,
+    quote_released: u64,
+    /// Orders the batch closed, to drop from the trader account's index
+    closed_orders: Vec<Pubkey>,
+}
+
+impl OwnerSettlement {
+    /// Credit the proceeds of an order on `side`: base for bids, quote for asks
+    fn receive(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let owed = match side {
+            OrderSide::Bid => &mut self.base_owed,
+            OrderSide::Ask => &mut self.quote_owed,
+        };
+        *owed = owed.checked_add(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Release `amount` of what an order on `side` put in from the locked
+    /// amounts: quote for bids, base for asks
+    fn release(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let released = match side {
+            OrderSide::Bid => &mut self.quote_released,
+            OrderSide::Ask => &mut self.base_released,
+        };
+        *released = released.checked_add(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Hand back `amount` of what an order on `side` put in
+    fn refund(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        self.release(side, amount)?;
+        let owed = match side {
+            OrderSide::Bid => &mut self.quote_owed,
+            OrderSide::Ask => &mut self.base_owed,
+        };
+        *owed = owed.checked_add(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    fn apply(&self, trader: &mut TraderAccount) -> Result<()> {
+        // Bids lock quote and asks lock base
+        trader.unlock(OrderSide::Bid, self.quote_released)?;
+        trader.unlock(OrderSide::Ask, self.base_released)?;
+        for order in &self.closed_orders {
+            trader.untrack(order);
+        }
+        Ok(())
+    }
+
+    /// Release the same amounts from the market's totals
+    fn apply_to_market(&self, market: &mut Market) -> Result<()> {
+        market.unlock(OrderSide::Bid, self.quote_released)?;
+        market.unlock(OrderSide::Ask, self.base_released)
+    }
+}
+
+/// Check that a market's vaults cover its locked and unclaimed amounts
+fn check_solvency<'info>(
+    market: &Market,
+    base_vault: &AccountInfo<'info>,
+    quote_vault: &AccountInfo<'info>,
+) -> Result<()> {
+    for (vault, locked, unclaimed) in [
+        (base_vault, market.locked_base, market.unclaimed_base),
+        (quote_vault, market.locked_quote, market.unclaimed_quote),
+    ] {
+        let balance = TokenAccount::try_deserialize(&mut &vault.try_borrow_data()?[..])?.amount;
+        let owed = locked.checked_add(unclaimed).ok_or(DarkpoolError::MathOverflow)?;
+        require!(balance >= owed, DarkpoolError::VaultInsolvent);
+    }
+    Ok(())
+}
+
+/// `check_solvency` at the end of every instruction that moves tokens in or
+/// out of a market's vaults, in builds with the `debug-invariants` feature
+fn debug_check_solvency<'info>(
+    market: &Market,
+    base_vault: &AccountInfo<'info>,
+    quote_vault: &AccountInfo<'info>,
+) -> Result<()> {
+    if cfg!(feature = "debug-invariants") {
+        check_solvency(market, base_vault, quote_vault)
+    } else {
+        Ok(())
+    }
+}
+
+/// Transfer `amount` out of a PDA-owned vault (a market's or a solver
+/// bond's), signed by that PDA
+///
+/// Zero amounts are skipped so fee-free markets don't pay for empty CPIs.
+/// With a transfer-fee mint the recipient gets `amount` less the fee.
+fn vault_transfer<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let decimals = Mint::try_deserialize(&mut &mint.try_borrow_data()?[..])?.decimals;
+    let cpi_accounts = TransferChecked {
+        from: from.clone(),
+        mint: mint.clone(),
+        to: to.clone(),
+        authority: authority.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, decimals)
+}
+
+/// Transfer `amount` from a user's token account into a vault, returning
+/// what actually arrived, which is less than `amount` for transfer-fee mints
+fn deposit<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<u64> {
+    let balance = |vault: &AccountInfo| -> Result<u64> {
+        Ok(TokenAccount::try_deserialize(&mut &vault.try_borrow_data()?[..])?.amount)
+    };
+    let before = balance(to)?;
+
+    let decimals = Mint::try_deserialize(&mut &mint.try_borrow_data()?[..])?.decimals;
+    let cpi_accounts = TransferChecked {
+        from: from.clone(),
+        mint: mint.clone(),
+        to: to.clone(),
+        authority: authority.clone(),
+    };
+    let cpi_ctx = CpiContext::new(token_program.clone(), cpi_accounts);
+    token_interface::transfer_checked(cpi_ctx, amount, decimals)?;
+
+    balance(to)?
+        .checked_sub(before)
+        .ok_or_else(|| DarkpoolError::MathOverflow.into())
+}
+
+/// Scale an order's `min_amount_out` for `amount_in` down to the `received`
+/// part of it that reached the vault, rounding up so the limit price holds
+fn scale_min_amount_out(min_amount_out: u64, received: u64, amount_in: u64) -> Result<u64> {
+    if received == amount_in {
+        return Ok(min_amount_out);
+    }
+    let scaled = (min_amount_out as u128)
+        .checked_mul(received as u128)
+        .and_then(|product| product.checked_add(amount_in as u128 - 1))
+        .ok_or(DarkpoolError::MathOverflow)?
+        / amount_in as u128;
+    u64::try_from(scaled).map_err(|_| DarkpoolError::MathOverflow.into())
+}
+
+/// Token-2022 mint extensions a market or bond can't hold safely: a
+/// permanent delegate can move tokens out of the vaults, non-transferable
+/// and pausable mints can stop them moving, and a transfer hook needs
+/// accounts these transfers don't pass
+const UNSUPPORTED_MINT_EXTENSIONS: [ExtensionType; 4] = [
+    ExtensionType::PermanentDelegate,
+    ExtensionType::NonTransferable,
+    ExtensionType::Pausable,
+    ExtensionType::TransferHook,
+];
+
+/// Reject mints with an extension in `UNSUPPORTED_MINT_EXTENSIONS`
+fn check_mint_extensions(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let info = mint.to_account_info();
+    if info.owner != &spl_token_2022::ID {
+        return Ok(());
+    }
+
+    let data = info.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    for extension in state.get_extension_types()? {
+        require!(
+            !UNSUPPORTED_MINT_EXTENSIONS.contains(&extension),
+            DarkpoolError::UnsupportedMintExtension
+        );
+    }
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Config::LEN,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+    /// Must be the program's upgrade authority
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Darkpool>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(payer.key())
+            @ DarkpoolError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAdmin<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.pending_admin != Pubkey::default()
+            && config.pending_admin == pending_admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    pub pending_admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QueueChange<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PendingChange::LEN,
+        seeds = [b"change", config.next_change_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelChange<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"change", pending_change.id.to_le_bytes().as_ref()],
+        bump = pending_change.bump,
+        close = admin
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteChange<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"change", pending_change.id.to_le_bytes().as_ref()],
+        bump = pending_change.bump,
+        close = admin
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    /// Receives the pending change's rent
+    #[account(mut, address = config.admin @ DarkpoolError::Unauthorized)]
+    pub admin: SystemAccount<'info>,
+    /// Anyone may execute a change once its timelock has passed
+    pub executor: Signer<'info>,
+    /// The market a market change applies to
+    #[account(mut)]
+    pub market: Option<Account<'info, Market>>,
+}
+
+#[derive(Accounts)]
+pub struct CreateMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Market::LEN,
+        seeds = [b"market", base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(mint::token_program = base_token_program)]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(mint::token_program = quote_token_program)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = admin,
+        token::mint = base_mint,
+        token::authority = market,
+        token::token_program = base_token_program,
+        seeds = [b"vault", b"base".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = admin,
+        token::mint = quote_mint,
+        token::authority = market,
+        token::token_program = quote_token_program,
+        seeds = [b"vault", b"quote".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = admin,
+        token::mint = base_mint,
+        token::authority = market,
+        token::token_program = base_token_program,
+        seeds = [b"fee_vault", b"base".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub base_fee_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = admin,
+        token::mint = quote_mint,
+        token::authority = market,
+        token::token_program = quote_token_program,
+        seeds = [b"fee_vault", b"quote".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub quote_fee_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PauseMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    /// Receives the rent of the market and its vaults
+    #[account(mut, address = config.admin @ DarkpoolError::Unauthorized)]
+    pub admin: SystemAccount<'info>,
+    /// Anyone may close a delisted market once it owes nothing
+    pub cranker: Signer<'info>,
+    #[account(mut, close = admin)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub base_fee_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub quote_fee_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    /// Receive whatever the vaults still hold
+    #[account(
+        mut,
+        constraint = admin_base_account.owner == config.admin @ DarkpoolError::InvalidTokenAccount,
+        constraint = admin_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub admin_base_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = admin_quote_account.owner == config.admin @ DarkpoolError::InvalidTokenAccount,
+        constraint = admin_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub admin_quote_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub base_fee_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub quote_fee_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = admin_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub admin_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = admin_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub admin_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitSolverBond<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = solver,
+        space = 8 + SolverBond::LEN,
+        seeds = [b"bond", solver.key().as_ref()],
+        bump
+    )]
+    pub solver_bond: Account<'info, SolverBond>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
+    #[account(address = config.bond_mint @ DarkpoolError::InvalidMint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = solver,
+        token::mint = bond_mint,
+        token::authority = solver_bond,
+        seeds = [b"bond_vault", solver.key().as_ref()],
+        bump
+    )]
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositBond<'info> {
+    #[account(
+        mut,
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Account<'info, SolverBond>,
+    pub solver: Signer<'info>,
+    #[account(
+        mut,
+        address = solver_bond.vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = solver_token_account.mint == solver_bond.mint @ DarkpoolError::InvalidMint
+    )]
+    pub solver_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = solver_bond.mint @ DarkpoolError::InvalidMint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RequestUnbond<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Account<'info, SolverBond>,
+    pub solver: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawUnbonded<'info> {
+    #[account(
+        mut,
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Account<'info, SolverBond>,
+    pub solver: Signer<'info>,
+    #[account(
+        mut,
+        address = solver_bond.vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = solver_token_account.mint == solver_bond.mint @ DarkpoolError::InvalidMint
+    )]
+    pub solver_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = solver_bond.mint @ DarkpoolError::InvalidMint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderAccount<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + TraderAccount::LEN,
+        seeds = [b"trader", market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub market: Account<'info, Market>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(side: OrderSide, amount_in: u64, min_amount_out: u64, nonce: u64)]
+pub struct PlaceOrder<'info> {
+    #[account(
         init,
-        payer = payer,
-        space = 8 + Config::LEN,
+        payer = owner,
+        space = 8 + Order::LEN,
+        seeds = [b"order", market.key().as_ref(), owner.key().as_ref(), &nonce.to_le_bytes()],
+        bump
+    )]
+    pub order: Account<'info, Order>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + TraderAccount::LEN,
+        seeds = [b"trader", market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(
+        mut,
+        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"order", order.market.as_ref(), order.owner.as_ref(), &order.nonce.to_le_bytes()],
+        bump = order.bump,
+        has_one = market @ DarkpoolError::MismatchedMarket,
+        constraint = order.owner == owner.key() @ DarkpoolError::Unauthorized,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"trader", market.key().as_ref(), owner.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(
+        mut,
+        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct AmendOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"order", order.market.as_ref(), order.owner.as_ref(), &order.nonce.to_le_bytes()],
+        bump = order.bump,
+        has_one = market @ DarkpoolError::MismatchedMarket,
+        constraint = order.owner == owner.key() @ DarkpoolError::Unauthorized
+    )]
+    pub order: Account<'info, Order>,
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"trader", market.key().as_ref(), owner.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(
+        mut,
+        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAllOrders<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"trader", market.key().as_ref(), owner.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(
+        mut,
+        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    // Remaining accounts: the owner's order accounts in this market, writable
+}
+
+#[derive(Accounts)]
+pub struct CloseOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"order", order.market.as_ref(), order.owner.as_ref(), &order.nonce.to_le_bytes()],
+        bump = order.bump,
+        has_one = market @ DarkpoolError::MismatchedMarket,
+        constraint = order.owner == owner.key() @ DarkpoolError::Unauthorized,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"trader", order.market.as_ref(), owner.key().as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"order", order.market.as_ref(), order.owner.as_ref(), &order.nonce.to_le_bytes()],
+        bump = order.bump,
+        has_one = market @ DarkpoolError::MismatchedMarket,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+    /// Anyone may run the crank; refunds always go to the order's owner
+    pub cranker: Signer<'info>,
+    /// Receives the closed order's rent
+    #[account(mut, address = order.owner @ DarkpoolError::InvalidOrderOwner)]
+    pub owner: SystemAccount<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// The batch an immediate-or-cancel order traded in, to refund it once
+    /// that batch has settled
+    #[account(
+        seeds = [b"batch", market.key().as_ref(), order.batch_seq.to_le_bytes().as_ref()],
+        bump = batch.bump
+    )]
+    pub batch: Option<Account<'info, Batch>>,
+    #[account(
+        mut,
+        seeds = [b"trader", market.key().as_ref(), order.owner.as_ref()],
+        bump = trader_account.bump
+    )]
+    pub trader_account: Account<'info, TraderAccount>,
+    #[account(
+        mut,
+        constraint = owner_base_account.owner == order.owner @ DarkpoolError::InvalidTokenAccount,
+        constraint = owner_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub owner_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_quote_account.owner == order.owner @ DarkpoolError::InvalidTokenAccount,
+        constraint = owner_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub owner_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct VerifySolvency<'info> {
+    pub market: Account<'info, Market>,
+    #[account(address = market.base_vault @ DarkpoolError::InvalidTokenAccount)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = market.quote_vault @ DarkpoolError::InvalidTokenAccount)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct OpenBatch<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
+    #[account(
+        mut,
+        constraint = market.solver == Pubkey::default() || market.solver == solver.key()
+            @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = solver,
+        space = 8 + Batch::LEN,
+        seeds = [b"batch", market.key().as_ref(), market.next_batch_seq.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub batch: Account<'info, Batch>,
+    #[account(
+        init,
+        payer = solver,
+        space = 8 + BatchInputs::space(0),
+        seeds = [b"inputs", market.key().as_ref(), market.next_batch_seq.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub batch_inputs: Account<'info, BatchInputs>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CommitBatchInputs<'info> {
+    #[account(mut)]
+    pub solver: Signer<'info>,
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver,
+        constraint = !batch.inputs_committed @ DarkpoolError::BatchInputsCommitted
+    )]
+    pub batch: Account<'info, Batch>,
+    #[account(
+        mut,
+        seeds = [b"inputs", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch_inputs.bump
+    )]
+    pub batch_inputs: Account<'info, BatchInputs>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(fills: Vec<Fill>)]
+pub struct SettleBatch<'info> {
+    #[account(
         seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
+    #[account(
+        mut,
+        constraint = market.solver == Pubkey::default() || market.solver == solver.key()
+            @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = !batch.settled @ DarkpoolError::BatchAlreadySettled,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver,
+        constraint = !batch.is_claims() && !batch.chunked @ DarkpoolError::BatchModeMismatch,
+        constraint = batch.inputs_committed @ DarkpoolError::BatchInputsNotCommitted
+    )]
+    pub batch: Account<'info, Batch>,
+    /// Required once `config.min_bond` is set
+    #[account(
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Option<Account<'info, SolverBond>>,
+    #[account(
+        init,
+        payer = solver,
+        space = 8 + SettlementRecord::space(fills.len()),
+        seeds = [
+            b"settlement",
+            market.key().as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
         bump
     )]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    pub settlement_record: Account<'info, SettlementRecord>,
     pub system_program: Program<'info, System>,
+    // Remaining accounts: Expects:
+    // [base_vault, quote_vault, market, base_token_program, quote_token_program,
+    //  base_fee_vault, quote_fee_vault, base_mint, quote_mint,
+    //  then every order in the fills, each once, in order of first appearance,
+    //  then for each order owner:
+    //  owner, owner_base_account, owner_quote_account, owner_trader_account]
+    // The owner wallets receive the rent of orders that end the batch filled
+    // or cancelled, and the trader accounts track each owner's open orders
+    // and locked amounts
+    // Accounts are validated in instruction
 }
 
 #[derive(Accounts)]
-pub struct CreateMarket<'info> {
+#[instruction(fill_commitment: [u8; 32], fills_total: u32)]
+pub struct BeginSettlement<'info> {
     #[account(
         seeds = [b"config"],
         bump = config.bump,
-        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
     )]
     pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
     #[account(
-        init,
-        payer = admin,
-        space = 8 + Market::LEN,
-        seeds = [b"market", base_mint.key().as_ref(), quote_mint.key().as_ref()],
-        bump
+        mut,
+        constraint = market.solver == Pubkey::default() || market.solver == solver.key()
+            @ DarkpoolError::UnauthorizedSolver
     )]
     pub market: Account<'info, Market>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub base_mint: Account<'info, Mint>,
-    pub quote_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = !batch.settled @ DarkpoolError::BatchAlreadySettled,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver,
+        constraint = !batch.is_claims() @ DarkpoolError::BatchModeMismatch,
+        constraint = batch.inputs_committed @ DarkpoolError::BatchInputsNotCommitted
+    )]
+    pub batch: Account<'info, Batch>,
     #[account(
         init,
-        payer = admin,
-        token::mint = base_mint,
-        token::authority = market,
-        seeds = [b"vault", b"base".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        payer = solver,
+        space = 8 + SettlementSession::LEN,
+        seeds = [b"session", batch.key().as_ref()],
         bump
     )]
-    pub base_vault: Account<'info, TokenAccount>,
+    pub session: Account<'info, SettlementSession>,
     #[account(
         init,
-        payer = admin,
-        token::mint = quote_mint,
-        token::authority = market,
-        seeds = [b"vault", b"quote".as_ref(), base_mint.key().as_ref(), quote_mint.key().as_ref()],
+        payer = solver,
+        space = 8 + SettlementRecord::space(fills_total as usize),
+        seeds = [
+            b"settlement",
+            market.key().as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
         bump
     )]
-    pub quote_vault: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
-    pub rent: Sysvar<'info, Rent>,
+    pub settlement_record: Account<'info, SettlementRecord>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(side: OrderSide, amount_in: u64, min_amount_out: u64, nonce: u64)]
-pub struct PlaceOrder<'info> {
+pub struct SettleChunk<'info> {
     #[account(
-        init,
-        payer = owner,
-        space = 8 + Order::LEN,
-        seeds = [b"order", market.key().as_ref(), owner.key().as_ref(), &nonce.to_le_bytes()],
-        bump
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub config: Account<'info, Config>,
+    pub solver: Signer<'info>,
+    #[account(
+        mut,
+        constraint = market.solver == Pubkey::default() || market.solver == solver.key()
+            @ DarkpoolError::UnauthorizedSolver
     )]
-    pub order: Account<'info, Order>,
-    #[account(mut)]
-    pub owner: Signer<'info>,
     pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub batch: Account<'info, Batch>,
     #[account(
         mut,
-        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
-        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+        seeds = [b"session", batch.key().as_ref()],
+        bump = session.bump,
+        constraint = session.status == SessionStatus::Open @ DarkpoolError::SessionNotOpen
+    )]
+    pub session: Account<'info, SettlementSession>,
+    /// Required once `config.min_bond` is set
+    #[account(
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
     )]
-    pub user_base_account: Account<'info, TokenAccount>,
+    pub solver_bond: Option<Account<'info, SolverBond>>,
     #[account(
         mut,
-        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
-        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+        seeds = [
+            b"settlement",
+            market.key().as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+    // Remaining accounts: as for `SettleBatch`, for this chunk's fills
+}
+
+#[derive(Accounts)]
+pub struct FinalizeSettlement<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub config: Account<'info, Config>,
+    pub solver: Signer<'info>,
+    #[account(mut, address = batch.market @ DarkpoolError::MismatchedMarket)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"batch", batch.market.as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver
+    )]
+    pub batch: Account<'info, Batch>,
+    #[account(
+        mut,
+        seeds = [b"session", batch.key().as_ref()],
+        bump = session.bump,
+        constraint = session.status == SessionStatus::Open @ DarkpoolError::SessionNotOpen
+    )]
+    pub session: Account<'info, SettlementSession>,
+    #[account(
+        mut,
+        seeds = [
+            b"settlement",
+            batch.market.as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+}
+
+#[derive(Accounts)]
+pub struct AbortSettlement<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = authority.key() == session.solver || authority.key() == config.admin
+            @ DarkpoolError::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+    pub authority: Signer<'info>,
+    #[account(mut, address = batch.market @ DarkpoolError::MismatchedMarket)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"batch", batch.market.as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump
+    )]
+    pub batch: Account<'info, Batch>,
+    #[account(
+        mut,
+        seeds = [b"session", batch.key().as_ref()],
+        bump = session.bump,
+        constraint = session.status == SessionStatus::Open @ DarkpoolError::SessionNotOpen
+    )]
+    pub session: Account<'info, SettlementSession>,
+    #[account(
+        mut,
+        seeds = [
+            b"settlement",
+            batch.market.as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+}
+
+#[derive(Accounts)]
+pub struct ApplyClaimFills<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.is_solver(&solver.key()) @ DarkpoolError::UnauthorizedSolver
     )]
-    pub user_quote_account: Account<'info, TokenAccount>,
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
     #[account(
         mut,
-        constraint = base_vault.mint == market.base_mint @ DarkpoolError::InvalidMint
+        constraint = market.solver == Pubkey::default() || market.solver == solver.key()
+            @ DarkpoolError::UnauthorizedSolver
     )]
-    pub base_vault: Account<'info, TokenAccount>,
+    pub market: Account<'info, Market>,
     #[account(
         mut,
-        constraint = quote_vault.mint == market.quote_mint @ DarkpoolError::InvalidMint
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = !batch.settled @ DarkpoolError::BatchAlreadySettled,
+        constraint = batch.solver == solver.key() @ DarkpoolError::UnauthorizedSolver,
+        constraint = !batch.chunked @ DarkpoolError::BatchModeMismatch,
+        constraint = batch.inputs_committed @ DarkpoolError::BatchInputsNotCommitted
+    )]
+    pub batch: Account<'info, Batch>,
+    /// Required once `config.min_bond` is set
+    #[account(
+        seeds = [b"bond", solver.key().as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Option<Account<'info, SolverBond>>,
+    /// Created by the batch's first call and grown by each one after
+    #[account(
+        init_if_needed,
+        payer = solver,
+        space = 8 + SettlementRecord::space(0),
+        seeds = [
+            b"settlement",
+            market.key().as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
+        bump
     )]
-    pub quote_vault: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub settlement_record: Account<'info, SettlementRecord>,
     pub system_program: Program<'info, System>,
+    // Remaining accounts: every order in the fills, each once, in order of
+    // first appearance, then for each order owner: owner, owner_trader_account
 }
 
 #[derive(Accounts)]
-pub struct CancelOrder<'info> {
+pub struct FinalizeClaims<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    /// The batch's solver, or anyone once `CLAIMS_FINALIZE_TIMEOUT` has passed
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
     #[account(
         mut,
-        seeds = [b"order", order.market.as_ref(), order.owner.as_ref(), &order.nonce.to_le_bytes()],
-        bump = order.bump,
-        constraint = order.owner == owner.key() @ DarkpoolError::Unauthorized
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = !batch.settled @ DarkpoolError::BatchAlreadySettled,
+        constraint = batch.fills_applied > 0 @ DarkpoolError::BatchModeMismatch
     )]
-    pub order: Account<'info, Order>,
+    pub batch: Account<'info, Batch>,
+    #[account(
+        mut,
+        seeds = [
+            b"settlement",
+            market.key().as_ref(),
+            batch.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub base_fee_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_fee_vault @ DarkpoolError::InvalidFeeVault
+    )]
+    pub quote_fee_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(leaf_index: u32)]
+pub struct ClaimFill<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
+    #[account(mut)]
     pub market: Account<'info, Market>,
     #[account(
         mut,
-        constraint = user_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
-        constraint = user_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+        seeds = [b"batch", market.key().as_ref(), batch.seq.to_le_bytes().as_ref()],
+        bump = batch.bump,
+        constraint = batch.settled && batch.is_claims() @ DarkpoolError::BatchNotFinalized
+    )]
+    pub batch: Account<'info, Batch>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + ClaimReceipt::LEN,
+        seeds = [b"claim", batch.key().as_ref(), leaf_index.to_le_bytes().as_ref()],
+        bump
     )]
-    pub user_base_account: Account<'info, TokenAccount>,
+    pub claim_receipt: Account<'info, ClaimReceipt>,
     #[account(
         mut,
-        constraint = user_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
-        constraint = user_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+        constraint = owner_base_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = owner_base_account.mint == market.base_mint @ DarkpoolError::InvalidMint
+    )]
+    pub owner_base_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_quote_account.owner == owner.key() @ DarkpoolError::InvalidTokenAccount,
+        constraint = owner_quote_account.mint == market.quote_mint @ DarkpoolError::InvalidMint
+    )]
+    pub owner_quote_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = base_token_program,
+        address = market.base_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::token_program = quote_token_program,
+        address = market.quote_vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        address = market.base_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = base_token_program
+    )]
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        address = market.quote_mint @ DarkpoolError::InvalidMint,
+        mint::token_program = quote_token_program
+    )]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ChallengeSettlement<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [
+            b"settlement",
+            settlement_record.market.as_ref(),
+            settlement_record.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump
+    )]
+    pub settlement_record: Account<'info, SettlementRecord>,
+    /// The orders the settled batch was matched over
+    #[account(
+        seeds = [
+            b"inputs",
+            settlement_record.market.as_ref(),
+            settlement_record.seq.to_le_bytes().as_ref()
+        ],
+        bump = batch_inputs.bump
+    )]
+    pub batch_inputs: Account<'info, BatchInputs>,
+    #[account(
+        mut,
+        seeds = [b"bond", settlement_record.solver.as_ref()],
+        bump = solver_bond.bump
+    )]
+    pub solver_bond: Account<'info, SolverBond>,
+    #[account(
+        mut,
+        address = solver_bond.vault @ DarkpoolError::InvalidTokenAccount
+    )]
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
+    /// Bond-mint accounts of the fill's two owners; whichever the violation
+    /// hurt receive the slashed stake
+    #[account(
+        mut,
+        constraint = order_owner_account.mint == solver_bond.mint @ DarkpoolError::InvalidMint
+    )]
+    pub order_owner_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = counterparty_owner_account.mint == solver_bond.mint @ DarkpoolError::InvalidMint
     )]
-    pub user_quote_account: Account<'info, TokenAccount>,
+    pub counterparty_owner_account: InterfaceAccount<'info, TokenAccount>,
+    /// Anyone may challenge a settlement
+    pub challenger: Signer<'info>,
+    #[account(address = solver_bond.mint @ DarkpoolError::InvalidMint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CloseSettlementRecord<'info> {
     #[account(
         mut,
-        constraint = base_vault.mint == market.base_mint @ DarkpoolError::InvalidMint
+        seeds = [
+            b"settlement",
+            settlement_record.market.as_ref(),
+            settlement_record.seq.to_le_bytes().as_ref()
+        ],
+        bump = settlement_record.bump,
+        has_one = solver @ DarkpoolError::Unauthorized,
+        close = solver
     )]
-    pub base_vault: Account<'info, TokenAccount>,
+    pub settlement_record: Account<'info, SettlementRecord>,
     #[account(
         mut,
-        constraint = quote_vault.mint == market.quote_mint @ DarkpoolError::InvalidMint
+        seeds = [
+            b"inputs",
+            settlement_record.market.as_ref(),
+            settlement_record.seq.to_le_bytes().as_ref()
+        ],
+        bump = batch_inputs.bump,
+        close = solver
     )]
-    pub quote_vault: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub batch_inputs: Account<'info, BatchInputs>,
+    #[account(mut)]
+    pub solver: Signer<'info>,
+}
+
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub bump: u8,
+    /// Keys allowed to call `settle_batch`
+    pub solvers: Vec<Pubkey>,
+    /// Proposed next admin, the default pubkey when no transfer is pending
+    pub pending_admin: Pubkey,
+    /// Seconds a queued change must wait before it can be executed
+    pub timelock_delay: i64,
+    /// Id, and PDA seed, of the next queued change
+    pub next_change_id: u64,
+    /// Mint solver bonds are held in
+    pub bond_mint: Pubkey,
+    /// Active bond a solver needs to settle; 0 lets unbonded solvers settle
+    pub min_bond: u64,
+    /// Taken from a solver's bond for each successful challenge
+    pub slash_amount: u64,
+    /// Seconds unbonded stake stays slashable, and how long a settlement
+    /// can be challenged
+    pub unbonding_delay: i64,
+}
+
+impl Config {
+    pub const LEN: usize = 32 + 1 // admin + bump
+        + 4 + 32 * MAX_SOLVERS // solvers
+        + 32 + 8 + 8 // pending_admin + timelock_delay + next_change_id
+        + 32 + 8 + 8 + 8; // bond_mint + min_bond + slash_amount + unbonding_delay
+
+    pub fn is_solver(&self, key: &Pubkey) -> bool {
+        self.solvers.contains(key)
+    }
+}
+
+#[account]
+pub struct Market {
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub base_vault: Pubkey,
+    pub quote_vault: Pubkey,
+    pub bump: u8,
+    pub base_fee_vault: Pubkey,
+    pub quote_fee_vault: Pubkey,
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+    pub status: MarketStatus,
+    /// The only solver that may settle this market; the default pubkey lets
+    /// any registered solver settle it
+    pub solver: Pubkey,
+    /// Sequence number, and PDA seed, of the next batch opened
+    pub next_batch_seq: u64,
+    /// Proceeds of claim-settled batches that owners have yet to claim;
+    /// they stay in the vaults until then
+    pub unclaimed_base: u64,
+    pub unclaimed_quote: u64,
+    /// Unfilled remainders of open orders held in the vaults: base by asks
+    /// and quote by bids
+    pub locked_base: u64,
+    pub locked_quote: u64,
+    /// Everything the market has ever settled
+    pub base_volume: u128,
+    pub quote_volume: u128,
+    /// Token program owning each mint, SPL Token or Token-2022
+    pub base_token_program: Pubkey,
+    pub quote_token_program: Pubkey,
+    /// Batch being settled through an open `SettlementSession`; orders that
+    /// may be among its inputs can't change until the session ends
+    pub settling_batch: Option<u64>,
+}
+
+impl Market {
+    pub const LEN: usize = 32 + 32 + 32 + 32 + 1 // 4 pubkeys + bump
+        + 32 + 32 // fee vaults
+        + 2 + 2 // maker + taker fee bps
+        + 1 // status
+        + 32 // solver
+        + 8 // next_batch_seq
+        + 8 + 8 // unclaimed_base + unclaimed_quote
+        + 8 + 8 // locked_base + locked_quote
+        + 16 + 16 // base_volume + quote_volume
+        + 32 + 32 // base_token_program + quote_token_program
+        + 1 + 8; // settling_batch
+
+    /// Whether new orders may be placed (or grown) in this market
+    pub fn accepts_orders(&self) -> bool {
+        matches!(self.status, MarketStatus::Active | MarketStatus::PostOnly)
+    }
+
+    /// Whether `order` may be among the inputs of a batch being settled in
+    /// chunks, so its owner can't change it yet
+    pub fn is_settling(&self, order: &Order) -> bool {
+        self.settling_batch.is_some_and(|seq| order.batch_seq <= seq)
+    }
+
+    /// Record `amount` of an order's input token moving into the vault,
+    /// alongside the owner's `TraderAccount::lock`
+    pub fn lock(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let locked = self.locked_mut(side);
+        *locked = locked.checked_add(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Record `amount` of an order's input token no longer being held for
+    /// it, alongside the owner's `TraderAccount::unlock`
+    pub fn unlock(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let locked = self.locked_mut(side);
+        *locked = locked.checked_sub(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Add `fill` to the settled volume; `side` is the side of `fill.order`
+    pub fn record_volume(&mut self, side: OrderSide, fill: &Fill) -> Result<()> {
+        let (base, quote) = match side {
+            OrderSide::Bid => (fill.amount_out, fill.amount_in),
+            OrderSide::Ask => (fill.amount_in, fill.amount_out),
+        };
+        self.base_volume = self.base_volume
+            .checked_add(base as u128)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        self.quote_volume = self.quote_volume
+            .checked_add(quote as u128)
+            .ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    fn locked_mut(&mut self, side: OrderSide) -> &mut u64 {
+        match side {
+            OrderSide::Bid => &mut self.locked_quote,
+            OrderSide::Ask => &mut self.locked_base,
+        }
+    }
+}
+
+/// One round of matching for a market, settled at most once
+#[account]
+pub struct Batch {
+    pub market: Pubkey,
+    /// Position among the market's batches, counting from 0
+    pub seq: u64,
+    /// Solver that opened the batch and alone may settle it
+    pub solver: Pubkey,
+    /// `order_commitment` of the orders fed to the computation
+    pub input_commitment: [u8; 32],
+    /// Offset of the MPC computation that matched the batch
+    pub computation_offset: u64,
+    pub opened_at: i64,
+    pub settled: bool,
+    pub bump: u8,
+    /// Fills applied by `apply_claim_fills`; 0 for push settlement
+    pub fills_applied: u32,
+    /// Proceeds of the applied fills still to be claimed, after fees
+    pub claimable_base: u64,
+    pub claimable_quote: u64,
+    /// Fees of the applied fills, moved to the fee vaults on finalization
+    pub base_fees: u64,
+    pub quote_fees: u64,
+    /// Root of the `claim_leaf` tree owners claim their proceeds against;
+    /// all zeroes until the batch is finalized
+    pub claims_root: [u8; 32],
+    /// Whether the batch is being settled in chunks through a
+    /// `SettlementSession`
+    pub chunked: bool,
+    /// Left siblings of the path to the next leaf of the claims tree, enough
+    /// to compute its root without keeping every leaf
+    pub claims_frontier: [[u8; 32]; CLAIMS_TREE_DEPTH],
+    /// Leaves added to the claims tree
+    pub claims_count: u32,
+    /// Whether `commit_batch_inputs` has published every order of
+    /// `input_commitment`, which settlement requires
+    pub inputs_committed: bool,
+}
+
+impl Batch {
+    pub const LEN: usize = 32 + 8 + 32 // market + seq + solver
+        + 32 + 8 + 8 // input_commitment + computation_offset + opened_at
+        + 1 + 1 // settled + bump
+        + 4 + 8 + 8 + 8 + 8 // fills_applied + claimable + fees
+        + 32 // claims_root
+        + 1 // chunked
+        + 32 * CLAIMS_TREE_DEPTH + 4 // claims_frontier + claims_count
+        + 1; // inputs_committed
+
+    /// Whether the batch is being settled through claims
+    pub fn is_claims(&self) -> bool {
+        self.fills_applied > 0 || self.claims_root != [0; 32]
+    }
+
+    /// Add `leaf`, which is leaf `claims_count`, to the claims tree
+    pub fn push_claim(&mut self, leaf: [u8; 32]) -> Result<()> {
+        // A leaf in the last slot would fold the whole frontier away
+        let index = self.claims_count;
+        require!(
+            (index as usize) < (1 << CLAIMS_TREE_DEPTH) - 1,
+            DarkpoolError::ClaimsTreeFull
+        );
+
+        // Hash up through the levels where the new leaf is a right child,
+        // then park the node as the left sibling of the next one
+        let mut node = leaf;
+        for (level, sibling) in self.claims_frontier.iter_mut().enumerate() {
+            if index >> level & 1 == 0 {
+                *sibling = node;
+                break;
+            }
+            node = claim_node(*sibling, node);
+        }
+        self.claims_count = index + 1;
+        Ok(())
+    }
+
+    /// Root of the claims tree, with every leaf not yet added all zeroes
+    pub fn claims_tree_root(&self) -> [u8; 32] {
+        let mut node = [0; 32];
+        let mut empty = [0; 32];
+        for (level, sibling) in self.claims_frontier.iter().enumerate() {
+            node = if self.claims_count >> level & 1 == 1 {
+                claim_node(*sibling, node)
+            } else {
+                claim_node(node, empty)
+            };
+            empty = claim_node(empty, empty);
+        }
+        node
+    }
+}
+
+/// Progress of a batch being push settled over several transactions
+#[account]
+pub struct SettlementSession {
+    pub batch: Pubkey,
+    pub solver: Pubkey,
+    /// `chain_fill` hash of every fill of the batch, in order
+    pub fill_commitment: [u8; 32],
+    /// The same hash over the fills applied so far
+    pub applied_hash: [u8; 32],
+    pub fills_total: u32,
+    pub fills_applied: u32,
+    pub chunks_applied: u32,
+    /// Fees collected by the applied chunks
+    pub base_fees: u64,
+    pub quote_fees: u64,
+    pub status: SessionStatus,
+    pub bump: u8,
 }
 
-#[derive(Accounts)]
-pub struct SettleBatch<'info> {
-    #[account(
-        seeds = [b"config"],
-        bump = config.bump,
-        constraint = config.admin == admin.key() @ DarkpoolError::Unauthorized
-    )]
-    pub config: Account<'info, Config>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    pub market: Account<'info, Market>,
-    // Remaining accounts: Expects:
-    // [base_vault, quote_vault, market, token_program, then for each fill:
-    //  order_account, counterparty_order_account, order_owner_base_account, 
-    //  order_owner_quote_account, counterparty_owner_base_account, counterparty_owner_quote_account]
-    // First 4 accounts are: base_vault, quote_vault, market, token_program
-    // Then 6 accounts per fill
-    // Accounts are validated in instruction
+impl SettlementSession {
+    pub const LEN: usize = 32 + 32 // batch + solver
+        + 32 + 32 // fill_commitment + applied_hash
+        + 4 + 4 + 4 // fills_total + fills_applied + chunks_applied
+        + 8 + 8 // base_fees + quote_fees
+        + 1 + 1; // status + bump
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// Taking chunks
+    Open,
+    /// Every fill was applied and matched the commitment
+    Finalized,
+    /// Stopped early; applied chunks stay settled
+    Aborted,
 }
 
+/// Marks a leaf of a batch's claims tree as claimed
 #[account]
-pub struct Config {
-    pub admin: Pubkey,
+pub struct ClaimReceipt {
     pub bump: u8,
 }
 
-impl Config {
-    pub const LEN: usize = 32 + 1; // admin + bump
+impl ClaimReceipt {
+    pub const LEN: usize = 1;
 }
 
+/// Stake a solver puts up against provably bad settlements
 #[account]
-pub struct Market {
-    pub base_mint: Pubkey,
-    pub quote_mint: Pubkey,
-    pub base_vault: Pubkey,
-    pub quote_vault: Pubkey,
+pub struct SolverBond {
+    pub solver: Pubkey,
+    pub mint: Pubkey,
+    /// Token account holding the stake, owned by this PDA
+    pub vault: Pubkey,
+    /// Active stake, which counts towards `config.min_bond`
+    pub amount: u64,
+    /// Stake being unbonded; still slashable until `unbonding_at`
+    pub unbonding_amount: u64,
+    pub unbonding_at: i64,
     pub bump: u8,
 }
 
-impl Market {
-    pub const LEN: usize = 32 + 32 + 32 + 32 + 1; // 4 pubkeys + bump
+impl SolverBond {
+    pub const LEN: usize = 32 + 32 + 32 // solver + mint + vault
+        + 8 + 8 + 8 + 1; // amount + unbonding_amount + unbonding_at + bump
+}
+
+/// The orders a batch was matched over, in matching order, published by
+/// `commit_batch_inputs` so anyone can check its fills against them
+#[account]
+pub struct BatchInputs {
+    pub market: Pubkey,
+    /// Sequence number of the batch
+    pub seq: u64,
+    pub bump: u8,
+    pub orders: Vec<Pubkey>,
+}
+
+impl BatchInputs {
+    pub fn space(orders: usize) -> usize {
+        32 + 8 + 1 // market + seq + bump
+            + 4 + 32 * orders
+    }
+}
+
+/// What a settled batch did, kept until its challenge deadline so
+/// `challenge_settlement` can check it
+#[account]
+pub struct SettlementRecord {
+    pub market: Pubkey,
+    pub solver: Pubkey,
+    /// Sequence number of the settled batch
+    pub seq: u64,
+    /// Unix timestamp until which the settlement can be challenged
+    pub challenge_deadline: i64,
+    /// The batch's `input_commitment`
+    pub input_commitment: [u8; 32],
+    /// Set once the settlement has been successfully challenged
+    pub slashed: bool,
+    pub bump: u8,
+    pub fills: Vec<SettledFill>,
+}
+
+impl SettlementRecord {
+    pub fn space(fills: usize) -> usize {
+        32 + 32 + 8 + 8 // market + solver + seq + challenge_deadline
+            + 32 + 1 + 1 // input_commitment + slashed + bump
+            + 4 + SettledFill::LEN * fills
+    }
+}
+
+/// A fill as settled, with both orders' limits at the time
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SettledFill {
+    pub order: Pubkey,
+    pub counterparty: Pubkey,
+    pub order_owner: Pubkey,
+    pub counterparty_owner: Pubkey,
+    pub order_side: OrderSide,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub order_min_amount_out: u64,
+    pub counterparty_min_amount_out: u64,
+}
+
+impl SettledFill {
+    pub const LEN: usize = 32 * 4 + 1 + 8 * 4;
+
+    /// Snapshot `fill` with its orders as they were before it was applied
+    pub fn new(fill: &Fill, order: &Order, counterparty: &Order) -> Self {
+        Self {
+            order: fill.order,
+            counterparty: fill.counterparty,
+            order_owner: order.owner,
+            counterparty_owner: counterparty.owner,
+            order_side: order.side,
+            amount_in: fill.amount_in,
+            amount_out: fill.amount_out,
+            order_min_amount_out: order.min_amount_out,
+            counterparty_min_amount_out: counterparty.min_amount_out,
+        }
+    }
+}
+
+/// Evidence that a settled fill broke the rules
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum ChallengeProof {
+    /// A side's order wasn't among the batch's published inputs, so the
+    /// matcher was never given it
+    OrderOutsideCommitment,
+}
+
+/// A config change waiting out the timelock
+#[account]
+pub struct PendingChange {
+    pub id: u64,
+    pub change: ConfigChange,
+    /// Unix timestamp from which the change can be executed
+    pub eta: i64,
+    pub bump: u8,
+}
+
+impl PendingChange {
+    pub const LEN: usize = 8 // id
+        + 1 + 32 + 32 // change: tag + largest variant
+        + 8 + 1; // eta + bump
+}
+
+/// Sensitive parameter changes that must go through the timelock
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigChange {
+    AddSolver { solver: Pubkey },
+    RemoveSolver { solver: Pubkey },
+    SetTimelockDelay { delay: i64 },
+    SetMarketFees { market: Pubkey, maker_fee_bps: u16, taker_fee_bps: u16 },
+    SetMarketSolver { market: Pubkey, solver: Pubkey },
+    SetMarketStatus { market: Pubkey, status: MarketStatus },
+    SetBondParams { mint: Pubkey, min_bond: u64, slash_amount: u64, unbonding_delay: i64 },
+}
+
+impl ConfigChange {
+    /// Checks that don't depend on the current state
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ConfigChange::SetTimelockDelay { delay } => {
+                require!(*delay >= 0, DarkpoolError::InvalidTimelockDelay);
+            }
+            ConfigChange::SetMarketFees { maker_fee_bps, taker_fee_bps, .. } => {
+                require!(
+                    *maker_fee_bps <= MAX_FEE_BPS && *taker_fee_bps <= MAX_FEE_BPS,
+                    DarkpoolError::FeeTooHigh
+                );
+            }
+            ConfigChange::SetBondParams { unbonding_delay, .. } => {
+                require!(*unbonding_delay >= 0, DarkpoolError::InvalidTimelockDelay);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Trading state of a market
+///
+/// Cancels, reductions and expiry cranks work in every state, so traders can
+/// always get their funds back.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MarketStatus {
+    /// Orders can be placed and batches settled
+    Active,
+    /// Orders can be placed but no batches are settled
+    PostOnly,
+    /// Neither placing orders nor settlement is allowed
+    Paused,
+    /// Delisted: orders are being refunded and the market will be closed
+    Closed,
 }
 
 #[account]
@@ -605,10 +4666,106 @@ pub struct Order {
     pub created_at: i64,
     pub bump: u8,
     pub nonce: u64,
+    /// Unix timestamp after which the order can no longer match; 0 never expires
+    pub expires_at: i64,
+    pub time_in_force: TimeInForce,
+    /// Sequence number of the first batch opened after the order was placed,
+    /// the only batch an immediate-or-cancel order can trade in
+    pub batch_seq: u64,
 }
 
 impl Order {
-    pub const LEN: usize = 32 + 32 + 1 + 8 + 8 + 8 + 1 + 8 + 1 + 8; // All fields
+    pub const LEN: usize = 32 + 32 + 1 + 8 + 8 + 8 + 1 + 8 + 1 + 8 + 8 + 1 + 8; // All fields
+
+    /// Whether the order has expired as of `now`
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    /// Whether the order can no longer trade, so its account may be closed
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self.status,
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Expired
+        )
+    }
+
+    /// Final state of the order, emitted when its account is closed so it can
+    /// still be reconstructed afterwards
+    pub fn closed_event(&self, order: Pubkey, lamports: u64) -> OrderClosed {
+        OrderClosed {
+            version: EVENT_VERSION,
+            order,
+            owner: self.owner,
+            market: self.market,
+            side: self.side,
+            amount_in: self.amount_in,
+            filled_amount_in: self.filled_amount_in,
+            status: self.status,
+            lamports,
+        }
+    }
+}
+
+/// Per-(market, owner) index of a trader's orders
+///
+/// Hands out order nonces in sequence and tracks which orders are still open
+/// and how much of each token they hold in the market vaults, so clients can
+/// enumerate a trader's orders without scanning every program account.
+#[account]
+pub struct TraderAccount {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    /// Nonce the next `place_order` must use
+    pub next_nonce: u64,
+    /// Orders that have not been closed yet
+    pub open_orders: Vec<Pubkey>,
+    /// Base tokens held in the vault by open asks
+    pub locked_base: u64,
+    /// Quote tokens held in the vault by open bids
+    pub locked_quote: u64,
+    pub bump: u8,
+}
+
+impl TraderAccount {
+    pub const LEN: usize = 32 + 32 + 8 // owner + market + next_nonce
+        + 4 + 32 * MAX_OPEN_ORDERS // open_orders
+        + 8 + 8 + 1; // locked amounts + bump
+
+    pub fn track(&mut self, order: Pubkey) -> Result<()> {
+        require!(
+            self.open_orders.len() < MAX_OPEN_ORDERS,
+            DarkpoolError::TooManyOpenOrders
+        );
+        self.open_orders.push(order);
+        Ok(())
+    }
+
+    pub fn untrack(&mut self, order: &Pubkey) {
+        self.open_orders.retain(|key| key != order);
+    }
+
+    /// Record `amount` of the side's input token moving into the vault
+    pub fn lock(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let locked = self.locked_mut(side);
+        *locked = locked.checked_add(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Record `amount` of the side's input token leaving the vault, either
+    /// traded away or refunded
+    pub fn unlock(&mut self, side: OrderSide, amount: u64) -> Result<()> {
+        let locked = self.locked_mut(side);
+        *locked = locked.checked_sub(amount).ok_or(DarkpoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    fn locked_mut(&mut self, side: OrderSide) -> &mut u64 {
+        match side {
+            OrderSide::Bid => &mut self.locked_quote,
+            OrderSide::Ask => &mut self.locked_base,
+        }
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
@@ -617,12 +4774,28 @@ pub enum OrderSide {
     Ask,
 }
 
+/// How long an order stays eligible for matching
+///
+/// Immediate-or-cancel orders only trade in the batch numbered by their
+/// `batch_seq`. That batch's settlement refunds whatever they have left,
+/// filled or not, if the solver passes them; `expire_order` refunds any it
+/// left once the batch has settled. Fill-or-kill orders may only be filled for their
+/// whole `amount_in`, by as many fills as it takes within one settlement
+/// call.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    ImmediateOrCancel,
+    FillOrKill,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
 pub enum OrderStatus {
     Open,
     PartiallyFilled,
     Filled,
     Cancelled,
+    Expired,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -635,25 +4808,331 @@ pub struct Fill {
 
 #[event]
 pub struct OrderPlaced {
+    pub version: u8,
     pub order: Pubkey,
     pub owner: Pubkey,
     pub market: Pubkey,
     pub side: OrderSide,
     pub amount_in: u64,
     pub min_amount_out: u64,
+    pub expires_at: i64,
+    pub time_in_force: TimeInForce,
 }
 
 #[event]
 pub struct OrderCancelled {
+    pub version: u8,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub remaining: u64,
+}
+
+#[event]
+pub struct OrderAmended {
+    pub version: u8,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub amount_in: u64,
+    pub min_amount_out: u64,
+    /// Unchanged when the order was reduced, the amend time when increased
+    pub created_at: i64,
+}
+
+#[event]
+pub struct OrderReduced {
+    pub version: u8,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    /// Amount refunded to the owner
+    pub amount: u64,
+    /// The order's new `amount_in`
+    pub amount_in: u64,
+    /// Unfilled amount still resting in the pool
+    pub remaining: u64,
+}
+
+/// Summary of a `cancel_all_orders` call
+#[event]
+pub struct OrdersCancelled {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    /// Orders that were still open and got refunded
+    pub cancelled: u32,
+    /// Order accounts closed, including already finished ones
+    pub closed: u32,
+    pub base_refunded: u64,
+    pub quote_refunded: u64,
+    /// Orders left in the trader account's index
+    pub open_orders: u32,
+}
+
+#[event]
+pub struct OrderExpired {
+    pub version: u8,
     pub order: Pubkey,
     pub owner: Pubkey,
     pub remaining: u64,
 }
 
+#[event]
+pub struct OrderClosed {
+    pub version: u8,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub side: OrderSide,
+    pub amount_in: u64,
+    pub filled_amount_in: u64,
+    pub status: OrderStatus,
+    pub lamports: u64,
+}
+
+#[event]
+pub struct BatchOpened {
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub solver: Pubkey,
+    pub input_commitment: [u8; 32],
+    pub computation_offset: u64,
+}
+
+/// Orders published by `commit_batch_inputs`; `complete` once they make up
+/// the whole of the batch's input
+#[event]
+pub struct BatchInputsCommitted {
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub orders: Vec<Pubkey>,
+    pub complete: bool,
+}
+
+/// One fill of a settled batch, from the point of view of `order`: it gave
+/// `amount_in` and received `amount_out`, each side paying its fee out of
+/// what it received
+#[event]
+pub struct OrderFilled {
+    pub version: u8,
+    pub market: Pubkey,
+    pub batch_seq: u64,
+    pub order: Pubkey,
+    pub counterparty: Pubkey,
+    pub side: OrderSide,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub order_fee: u64,
+    pub counterparty_fee: u64,
+    pub order_status: OrderStatus,
+    pub counterparty_status: OrderStatus,
+}
+
 #[event]
 pub struct BatchSettled {
-    pub fills_count: u8,
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub fills_count: u32,
     pub arcium_signature: Vec<u8>,
+    pub base_fees: u64,
+    pub quote_fees: u64,
+}
+
+#[event]
+pub struct SettlementBegun {
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub fill_commitment: [u8; 32],
+    pub fills_total: u32,
+}
+
+#[event]
+pub struct ChunkSettled {
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub chunk_index: u32,
+    pub fills_count: u32,
+    pub fills_applied: u32,
+}
+
+#[event]
+pub struct SettlementAborted {
+    pub version: u8,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub fills_applied: u32,
+    pub fills_total: u32,
+}
+
+#[event]
+pub struct ClaimAdded {
+    pub version: u8,
+    pub market: Pubkey,
+    pub batch_seq: u64,
+    pub leaf_index: u32,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct FillClaimed {
+    pub version: u8,
+    pub market: Pubkey,
+    pub batch_seq: u64,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct AdminProposed {
+    pub version: u8,
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub version: u8,
+    pub previous_admin: Pubkey,
+    pub admin: Pubkey,
+}
+
+#[event]
+pub struct ChangeQueued {
+    pub version: u8,
+    pub id: u64,
+    pub change: ConfigChange,
+    pub eta: i64,
+}
+
+#[event]
+pub struct ChangeCancelled {
+    pub version: u8,
+    pub id: u64,
+}
+
+#[event]
+pub struct ChangeExecuted {
+    pub version: u8,
+    pub id: u64,
+    pub change: ConfigChange,
+}
+
+#[event]
+pub struct TimelockDelayUpdated {
+    pub version: u8,
+    pub delay: i64,
+}
+
+#[event]
+pub struct SolverAdded {
+    pub version: u8,
+    pub solver: Pubkey,
+}
+
+#[event]
+pub struct SolverRemoved {
+    pub version: u8,
+    pub solver: Pubkey,
+}
+
+#[event]
+pub struct MarketSolverUpdated {
+    pub version: u8,
+    pub market: Pubkey,
+    pub solver: Pubkey,
+}
+
+#[event]
+pub struct MarketStatusUpdated {
+    pub version: u8,
+    pub market: Pubkey,
+    pub status: MarketStatus,
+}
+
+#[event]
+pub struct MarketClosed {
+    pub version: u8,
+    pub market: Pubkey,
+}
+
+#[event]
+pub struct BondParamsUpdated {
+    pub version: u8,
+    pub mint: Pubkey,
+    pub min_bond: u64,
+    pub slash_amount: u64,
+    pub unbonding_delay: i64,
+}
+
+#[event]
+pub struct BondDeposited {
+    pub version: u8,
+    pub solver: Pubkey,
+    pub amount: u64,
+    pub bonded: u64,
+}
+
+#[event]
+pub struct UnbondRequested {
+    pub version: u8,
+    pub solver: Pubkey,
+    pub amount: u64,
+    pub unbonding_amount: u64,
+    pub unbonding_at: i64,
+}
+
+#[event]
+pub struct BondWithdrawn {
+    pub version: u8,
+    pub solver: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SolverSlashed {
+    pub version: u8,
+    pub solver: Pubkey,
+    pub market: Pubkey,
+    pub seq: u64,
+    pub fill_index: u32,
+    pub challenger: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct SolvencyVerified {
+    pub version: u8,
+    pub market: Pubkey,
+    pub base_vault_amount: u64,
+    pub quote_vault_amount: u64,
+    pub locked_base: u64,
+    pub locked_quote: u64,
+    pub unclaimed_base: u64,
+    pub unclaimed_quote: u64,
+}
+
+#[event]
+pub struct MarketFeesUpdated {
+    pub version: u8,
+    pub market: Pubkey,
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+}
+
+#[event]
+pub struct FeesWithdrawn {
+    pub version: u8,
+    pub market: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
 }
 
 #[error_code]
@@ -680,4 +5159,120 @@ pub enum DarkpoolError {
     NothingToCancel,
     #[msg("Orders have same side")]
     SameSideOrders,
+    #[msg("Fee exceeds maximum")]
+    FeeTooHigh,
+    #[msg("Invalid fee vault")]
+    InvalidFeeVault,
+    #[msg("Order has expired")]
+    OrderExpired,
+    #[msg("Order has not expired")]
+    OrderNotExpired,
+    #[msg("Expiry must be in the future")]
+    InvalidExpiry,
+    #[msg("Fill-or-kill order must be filled completely")]
+    FillOrKillNotFilled,
+    #[msg("Order is not filled, cancelled or expired")]
+    OrderNotTerminal,
+    #[msg("Account does not belong to the order owner")]
+    InvalidOrderOwner,
+    #[msg("Nonce does not match the trader account's next nonce")]
+    InvalidNonce,
+    #[msg("Trader has too many open orders")]
+    TooManyOpenOrders,
+    #[msg("Trader account does not match the order")]
+    InvalidTraderAccount,
+    #[msg("Account is not an order of this program")]
+    InvalidOrderAccount,
+    #[msg("Amended amount must be above the filled amount")]
+    AmendBelowFilled,
+    #[msg("Reduce amount must be positive and at most the unfilled remainder")]
+    InvalidReduceAmount,
+    #[msg("Market is not accepting orders")]
+    MarketNotAcceptingOrders,
+    #[msg("Market is not active")]
+    MarketNotActive,
+    #[msg("Market has been closed")]
+    MarketClosed,
+    #[msg("Market has not been closed")]
+    MarketNotClosed,
+    #[msg("Market still holds tokens owed to traders")]
+    VaultsNotEmpty,
+    #[msg("Signer is not an authorized solver for this market")]
+    UnauthorizedSolver,
+    #[msg("Solver is already registered")]
+    SolverAlreadyRegistered,
+    #[msg("Solver is not registered")]
+    SolverNotRegistered,
+    #[msg("Too many solvers registered")]
+    TooManySolvers,
+    #[msg("Timelock delay must not be negative")]
+    InvalidTimelockDelay,
+    #[msg("Timelock has not elapsed")]
+    TimelockNotElapsed,
+    #[msg("Invalid bond amount")]
+    InvalidBondAmount,
+    #[msg("Unbonding delay has not elapsed")]
+    UnbondingNotElapsed,
+    #[msg("Solver bond is below the minimum")]
+    InsufficientBond,
+    #[msg("Settlement has already been slashed")]
+    AlreadySlashed,
+    #[msg("Settlement can no longer be challenged")]
+    ChallengeWindowClosed,
+    #[msg("Settlement can still be challenged")]
+    ChallengeWindowOpen,
+    #[msg("Orders do not match the batch commitment")]
+    InvalidCommitmentPreimage,
+    #[msg("Challenge does not show a violation")]
+    InvalidChallenge,
+    #[msg("Batch has already been settled")]
+    BatchAlreadySettled,
+    #[msg("Owner accounts must come in complete groups")]
+    InvalidOwnerAccounts,
+    #[msg("Batch is being settled in the other mode")]
+    BatchModeMismatch,
+    #[msg("Immediate-or-cancel orders can't be claim settled")]
+    ImmediateOrCancelNotClaimable,
+    #[msg("Claims root does not match the batch's claims tree")]
+    InvalidClaimsRoot,
+    #[msg("Batch claims have not been finalized")]
+    BatchNotFinalized,
+    #[msg("Claim proof does not match the batch's claims root")]
+    InvalidClaimProof,
+    #[msg("Claim exceeds the batch's unclaimed proceeds")]
+    ClaimExceedsBatch,
+    #[msg("Chunk is empty or goes past the session's fills")]
+    InvalidChunk,
+    #[msg("Chunk is out of order")]
+    ChunkOutOfOrder,
+    #[msg("Settlement session is not open")]
+    SessionNotOpen,
+    #[msg("Not every fill of the session has been applied")]
+    SessionIncomplete,
+    #[msg("Applied fills do not match the session's commitment")]
+    FillCommitmentMismatch,
+    #[msg("Vault holds less than the market's accounting owes")]
+    VaultInsolvent,
+    #[msg("Mint has an extension the darkpool can't custody")]
+    UnsupportedMintExtension,
+    #[msg("Fill gives the bid less than its minimum output")]
+    MinAmountOutNotMet,
+    #[msg("Batch's claims tree is full")]
+    ClaimsTreeFull,
+    #[msg("Token program does not own the market's mint")]
+    InvalidTokenProgram,
+    #[msg("Batch input has not been fully published")]
+    BatchInputsNotCommitted,
+    #[msg("Batch input has already been fully published")]
+    BatchInputsCommitted,
+    #[msg("Order was placed after the batch opened")]
+    OrderPlacedAfterBatch,
+    #[msg("Order is already among the batch's inputs")]
+    DuplicateBatchInput,
+    #[msg("Batch input exceeds the largest matching circuit")]
+    TooManyBatchInputs,
+    #[msg("Only the batch's solver can finalize before the timeout")]
+    FinalizeTimeoutNotElapsed,
+    #[msg("Order may be in a batch that is being settled in chunks")]
+    SettlementInProgress,
 }
@@ -6,9 +6,17 @@ import {
   Keypair,
   SystemProgram,
   SYSVAR_RENT_PUBKEY,
+  Transaction,
+  TransactionInstruction,
+  sendAndConfirmTransaction,
 } from "@solana/web3.js";
 import {
   TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
+  ExtensionType,
+  getMintLen,
+  createInitializeTransferFeeConfigInstruction,
+  createInitializePermanentDelegateInstruction,
   MINT_SIZE,
   createInitializeMintInstruction,
   getMinimumBalanceForRentExemptMint,
@@ -16,8 +24,10 @@ import {
   createAccount,
   mintTo,
   getAccount,
+  transfer,
 } from "@solana/spl-token";
 import { expect } from "chai";
+import { createHash } from "crypto";
 
 describe("darkpool", () => {
   // Configure the client
@@ -132,17 +142,278 @@ describe("darkpool", () => {
     );
   });
 
+  // Queue a config change and execute it straight away; the tests run with
+  // a zero timelock delay
+  async function applyChange(change: any, market?: PublicKey) {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const { nextChangeId } = await program.account.config.fetch(config);
+    const [pendingChange] = PublicKey.findProgramAddressSync(
+      [Buffer.from("change"), nextChangeId.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+
+    await program.methods
+      .queueChange(change)
+      .accounts({
+        config,
+        pendingChange,
+        admin: admin.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .executeChange()
+      .accounts({
+        config,
+        pendingChange,
+        admin: admin.publicKey,
+        executor: admin.publicKey,
+        market: market ?? null,
+      })
+      .rpc();
+  }
+
+  // Commitment settle_batch takes to the orders a batch was matched over
+  function orderCommitment(orders: PublicKey[]): number[] {
+    const hash = createHash("sha256");
+    orders.forEach((order) => hash.update(order.toBuffer()));
+    return Array.from(hash.digest());
+  }
+
+  // Open a market's next batch over `orders` with the admin wallet as
+  // solver and publish its input, returning the batch, its inputs and the
+  // record its settlement is written to
+  async function openBatch(market: PublicKey, orders: PublicKey[]) {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const { nextBatchSeq } = await program.account.market.fetch(market);
+    const seq = nextBatchSeq.toArrayLike(Buffer, "le", 8);
+    const [batch] = PublicKey.findProgramAddressSync(
+      [Buffer.from("batch"), market.toBuffer(), seq],
+      program.programId
+    );
+    const [batchInputs] = PublicKey.findProgramAddressSync(
+      [Buffer.from("inputs"), market.toBuffer(), seq],
+      program.programId
+    );
+    const [settlementRecord] = PublicKey.findProgramAddressSync(
+      [Buffer.from("settlement"), market.toBuffer(), seq],
+      program.programId
+    );
+
+    await program.methods
+      .openBatch(orderCommitment(orders), new anchor.BN(0))
+      .accounts({
+        config,
+        solver: admin.publicKey,
+        market,
+        batch,
+        batchInputs,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .commitBatchInputs()
+      .accounts({
+        solver: admin.publicKey,
+        market,
+        batch,
+        batchInputs,
+        systemProgram: SystemProgram.programId,
+      })
+      .remainingAccounts(
+        orders.map((pubkey) => ({ pubkey, isSigner: false, isWritable: false }))
+      )
+      .rpc();
+
+    return { batch, batchInputs, settlementRecord };
+  }
+
+  // remaining_accounts for settle_batch: the market's vaults and mints, the
+  // orders being filled, then each of user1 and user2's wallet, token accounts and
+  // trader account
+  function settlementAccounts(market: PublicKey, orders: PublicKey[]) {
+    const pda = (...seeds: Buffer[]) =>
+      PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+    const mints = [baseMint.toBuffer(), quoteMint.toBuffer()];
+    const meta = (pubkey: PublicKey, isWritable = true) => ({
+      pubkey,
+      isSigner: false,
+      isWritable,
+    });
+
+    return [
+      meta(pda(Buffer.from("vault"), Buffer.from("base"), ...mints)),
+      meta(pda(Buffer.from("vault"), Buffer.from("quote"), ...mints)),
+      meta(market, false),
+      meta(TOKEN_PROGRAM_ID, false),
+      meta(TOKEN_PROGRAM_ID, false),
+      meta(pda(Buffer.from("fee_vault"), Buffer.from("base"), ...mints)),
+      meta(pda(Buffer.from("fee_vault"), Buffer.from("quote"), ...mints)),
+      meta(baseMint, false),
+      meta(quoteMint, false),
+      ...orders.map((order) => meta(order)),
+      ...[
+        [user1, user1BaseAccount, user1QuoteAccount],
+        [user2, user2BaseAccount, user2QuoteAccount],
+      ].flatMap(([owner, base, quote]: [Keypair, PublicKey, PublicKey]) => [
+        meta(owner.publicKey),
+        meta(base),
+        meta(quote),
+        meta(pda(Buffer.from("trader"), market.toBuffer(), owner.publicKey.toBuffer())),
+      ]),
+    ];
+  }
+
+  // Create a Token-2022 mint with `extension` initialized by `init`
+  async function createToken2022Mint(
+    extension: ExtensionType,
+    init: (mint: PublicKey) => TransactionInstruction
+  ): Promise<PublicKey> {
+    const mint = Keypair.generate();
+    const space = getMintLen([extension]);
+    const lamports = await provider.connection.getMinimumBalanceForRentExemption(space);
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: admin.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports,
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      init(mint.publicKey),
+      createInitializeMintInstruction(mint.publicKey, 9, admin.publicKey, null, TOKEN_2022_PROGRAM_ID)
+    );
+    await sendAndConfirmTransaction(provider.connection, tx, [admin.payer, mint]);
+    return mint.publicKey;
+  }
+
+  // Accounts for create_market on a pair of mints, each owned by its own
+  // token program
+  function createMarketAccounts(
+    base: PublicKey,
+    quote: PublicKey,
+    baseTokenProgram: PublicKey,
+    quoteTokenProgram: PublicKey
+  ) {
+    const pda = (...seeds: Buffer[]) =>
+      PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+    const mints = [base.toBuffer(), quote.toBuffer()];
+    return {
+      config: pda(Buffer.from("config")),
+      market: pda(Buffer.from("market"), ...mints),
+      admin: admin.publicKey,
+      baseMint: base,
+      quoteMint: quote,
+      baseVault: pda(Buffer.from("vault"), Buffer.from("base"), ...mints),
+      quoteVault: pda(Buffer.from("vault"), Buffer.from("quote"), ...mints),
+      baseFeeVault: pda(Buffer.from("fee_vault"), Buffer.from("base"), ...mints),
+      quoteFeeVault: pda(Buffer.from("fee_vault"), Buffer.from("quote"), ...mints),
+      baseTokenProgram,
+      quoteTokenProgram,
+      rent: SYSVAR_RENT_PUBKEY,
+      systemProgram: SystemProgram.programId,
+    };
+  }
+
+  // Running hash a settlement session commits to, folded over `fills`
+  function chainFills(
+    fills: {
+      order: PublicKey;
+      counterparty: PublicKey;
+      amountIn: anchor.BN;
+      amountOut: anchor.BN;
+    }[]
+  ): number[] {
+    const hash = fills.reduce(
+      (hash, fill) =>
+        createHash("sha256")
+          .update(hash)
+          .update(fill.order.toBuffer())
+          .update(fill.counterparty.toBuffer())
+          .update(fill.amountIn.toArrayLike(Buffer, "le", 8))
+          .update(fill.amountOut.toArrayLike(Buffer, "le", 8))
+          .digest(),
+      Buffer.alloc(32)
+    );
+    return Array.from(hash);
+  }
+
+  // Leaf of a claim-settled batch's claims tree, as claim_fill hashes it
+  function claimLeaf(
+    batch: PublicKey,
+    index: number,
+    order: PublicKey,
+    owner: PublicKey,
+    baseAmount: anchor.BN,
+    quoteAmount: anchor.BN
+  ): Buffer {
+    const indexBytes = Buffer.alloc(4);
+    indexBytes.writeUInt32LE(index);
+    return createHash("sha256")
+      .update(Buffer.from([0]))
+      .update(batch.toBuffer())
+      .update(indexBytes)
+      .update(order.toBuffer())
+      .update(owner.toBuffer())
+      .update(baseAmount.toArrayLike(Buffer, "le", 8))
+      .update(quoteAmount.toArrayLike(Buffer, "le", 8))
+      .digest();
+  }
+
+  // Parent of two claims tree nodes, hashed smaller first
+  function claimNode(a: Buffer, b: Buffer): Buffer {
+    const [left, right] = Buffer.compare(a, b) <= 0 ? [a, b] : [b, a];
+    return createHash("sha256")
+      .update(Buffer.from([1]))
+      .update(left)
+      .update(right)
+      .digest();
+  }
+
+  // Root of the full-depth claims tree over `leaves`, padded with zeroes as
+  // the batch builds it, and the proof for leaf `index`
+  function claimsTree(leaves: Buffer[], index: number): { root: Buffer; proof: Buffer[] } {
+    const CLAIMS_TREE_DEPTH = 16;
+    let level = leaves;
+    let empty = Buffer.alloc(32);
+    const proof: Buffer[] = [];
+    for (let depth = 0; depth < CLAIMS_TREE_DEPTH; depth++) {
+      proof.push(level[index ^ 1] ?? empty);
+      const next: Buffer[] = [];
+      for (let i = 0; i < level.length; i += 2) {
+        next.push(claimNode(level[i], level[i + 1] ?? empty));
+      }
+      level = next;
+      index >>= 1;
+      empty = claimNode(empty, empty);
+    }
+    return { root: level[0], proof };
+  }
+
   it("Initializes config", async () => {
     const [config] = PublicKey.findProgramAddressSync(
       [Buffer.from("config")],
       program.programId
     );
+    // Only the upgrade authority, the deploying wallet, may initialize
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    );
 
     await program.methods
-      .initializeConfig(admin.publicKey)
+      .initializeConfig(admin.publicKey, new anchor.BN(0))
       .accounts({
         config,
         payer: admin.publicKey,
+        program: program.programId,
+        programData,
         systemProgram: SystemProgram.programId,
       })
       .rpc();
@@ -184,6 +455,26 @@ describe("darkpool", () => {
       program.programId
     );
 
+    const [baseFeeVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("fee_vault"),
+        Buffer.from("base"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [quoteFeeVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("fee_vault"),
+        Buffer.from("quote"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
     const [config] = PublicKey.findProgramAddressSync(
       [Buffer.from("config")],
       program.programId
@@ -199,7 +490,10 @@ describe("darkpool", () => {
         quoteMint,
         baseVault,
         quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseFeeVault,
+        quoteFeeVault,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         rent: SYSVAR_RENT_PUBKEY,
         systemProgram: SystemProgram.programId,
       })
@@ -210,6 +504,101 @@ describe("darkpool", () => {
     expect(marketAccount.quoteMint.toString()).to.equal(quoteMint.toString());
   });
 
+  it("Registers a solver", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+
+    // The admin wallet doubles as the solver in these tests
+    await applyChange({ addSolver: { solver: admin.publicKey } });
+
+    const configAccount = await program.account.config.fetch(config);
+    expect(configAccount.solvers.map((k) => k.toString())).to.deep.equal([
+      admin.publicKey.toString(),
+    ]);
+
+    // Registering the same key twice is rejected
+    try {
+      await applyChange({ addSolver: { solver: admin.publicKey } });
+      expect.fail("registering a solver twice should fail");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("SolverAlreadyRegistered");
+    }
+  });
+
+  it("Transfers admin in two steps", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+
+    await program.methods
+      .proposeAdmin(user1.publicKey)
+      .accounts({ config, admin: admin.publicKey })
+      .rpc();
+
+    // The admin only changes once the proposed key accepts
+    let configAccount = await program.account.config.fetch(config);
+    expect(configAccount.admin.toString()).to.equal(admin.publicKey.toString());
+    expect(configAccount.pendingAdmin.toString()).to.equal(user1.publicKey.toString());
+
+    await program.methods
+      .acceptAdmin()
+      .accounts({ config, pendingAdmin: user1.publicKey })
+      .signers([user1])
+      .rpc();
+
+    configAccount = await program.account.config.fetch(config);
+    expect(configAccount.admin.toString()).to.equal(user1.publicKey.toString());
+    expect(configAccount.pendingAdmin.toString()).to.equal(PublicKey.default.toString());
+
+    // Hand it back for the remaining tests
+    await program.methods
+      .proposeAdmin(admin.publicKey)
+      .accounts({ config, admin: user1.publicKey })
+      .signers([user1])
+      .rpc();
+    await program.methods
+      .acceptAdmin()
+      .accounts({ config, pendingAdmin: admin.publicKey })
+      .rpc();
+  });
+
+  it("Initializes trader accounts", async () => {
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    for (const user of [user1, user2]) {
+      const [traderPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("trader"), marketPda.toBuffer(), user.publicKey.toBuffer()],
+        program.programId
+      );
+
+      await program.methods
+        .initTraderAccount()
+        .accounts({
+          traderAccount: traderPda,
+          owner: user.publicKey,
+          market: marketPda,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+      const trader = await program.account.traderAccount.fetch(traderPda);
+      expect(trader.owner.toString()).to.equal(user.publicKey.toString());
+      expect(trader.nextNonce.toNumber()).to.equal(0);
+      expect(trader.openOrders).to.be.empty;
+    }
+  });
+
   it("Places and cancels an order", async () => {
     const [config] = PublicKey.findProgramAddressSync(
       [Buffer.from("config")],
@@ -245,7 +634,11 @@ describe("darkpool", () => {
       program.programId
     );
 
-    const nonce = new anchor.BN(1);
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const nonce = (await program.account.traderAccount.fetch(traderPda)).nextNonce;
     const [orderPda] = PublicKey.findProgramAddressSync(
       [
         Buffer.from("order"),
@@ -269,27 +662,52 @@ describe("darkpool", () => {
       quoteVault
     ).catch(() => ({ amount: BigInt(0) }));
 
+    const placeAccounts = {
+      order: orderPda,
+      owner: user1.publicKey,
+      market: marketPda,
+      traderAccount: traderPda,
+      userBaseAccount: user1BaseAccount,
+      userQuoteAccount: user1QuoteAccount,
+      baseVault,
+      quoteVault,
+      baseMint,
+      quoteMint,
+      baseTokenProgram: TOKEN_PROGRAM_ID,
+      quoteTokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const placeBid = (accounts: typeof placeAccounts) =>
+      program.methods
+        .placeOrder(
+          { bid: {} },
+          amountIn,
+          minAmountOut,
+          nonce,
+          new anchor.BN(0), // never expires
+          { goodTillCancel: {} }
+        )
+        .accounts(accounts)
+        .signers([user1])
+        .rpc();
+
+    // A same-mint account the trader controls can't stand in for the vault
+    const foreignVault = await createAccount(
+      provider.connection,
+      admin.payer,
+      quoteMint,
+      user1.publicKey,
+      Keypair.generate()
+    );
+    try {
+      await placeBid({ ...placeAccounts, quoteVault: foreignVault });
+      expect.fail("Expected the foreign vault to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("InvalidTokenAccount");
+    }
+
     // Place a bid order (buying base with quote)
-    await program.methods
-      .placeOrder(
-        { bid: {} },
-        amountIn,
-        minAmountOut,
-        nonce
-      )
-      .accounts({
-        order: orderPda,
-        owner: user1.publicKey,
-        market: marketPda,
-        userBaseAccount: user1BaseAccount,
-        userQuoteAccount: user1QuoteAccount,
-        baseVault,
-        quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
-        systemProgram: SystemProgram.programId,
-      })
-      .signers([user1])
-      .rpc();
+    await placeBid(placeAccounts);
 
     // Check order was created
     const orderAccount = await program.account.order.fetch(orderPda);
@@ -298,31 +716,58 @@ describe("darkpool", () => {
     expect(orderAccount.amountIn.toNumber()).to.equal(amountIn.toNumber());
     expect(orderAccount.status.open).to.be.true;
 
+    // Check the trader account indexed the order
+    const traderAfterPlace = await program.account.traderAccount.fetch(traderPda);
+    expect(traderAfterPlace.nextNonce.toNumber()).to.equal(nonce.toNumber() + 1);
+    expect(traderAfterPlace.openOrders.map((k) => k.toString())).to.include(
+      orderPda.toString()
+    );
+    expect(traderAfterPlace.lockedQuote.toNumber()).to.equal(amountIn.toNumber());
+
     // Check tokens were transferred to vault
     const vaultBalanceAfter = await getAccount(provider.connection, quoteVault);
     expect(Number(vaultBalanceAfter.amount)).to.equal(
       Number(initialVaultBalance.amount) + amountIn.toNumber()
     );
 
-    // Cancel the order
+    // Cancel the order, which can't refund out of anything but the vault
+    const cancelAccounts = {
+      order: orderPda,
+      owner: user1.publicKey,
+      market: marketPda,
+      traderAccount: traderPda,
+      userBaseAccount: user1BaseAccount,
+      userQuoteAccount: user1QuoteAccount,
+      baseVault,
+      quoteVault,
+      baseMint,
+      quoteMint,
+      baseTokenProgram: TOKEN_PROGRAM_ID,
+      quoteTokenProgram: TOKEN_PROGRAM_ID,
+    };
+    try {
+      await program.methods
+        .cancelOrder()
+        .accounts({ ...cancelAccounts, quoteVault: foreignVault })
+        .signers([user1])
+        .rpc();
+      expect.fail("Expected the foreign vault to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("InvalidTokenAccount");
+    }
     await program.methods
       .cancelOrder()
-      .accounts({
-        order: orderPda,
-        owner: user1.publicKey,
-        market: marketPda,
-        userBaseAccount: user1BaseAccount,
-        userQuoteAccount: user1QuoteAccount,
-        baseVault,
-        quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
-      })
+      .accounts(cancelAccounts)
       .signers([user1])
       .rpc();
 
-    // Check order was cancelled
-    const cancelledOrder = await program.account.order.fetch(orderPda);
-    expect(cancelledOrder.status.cancelled).to.be.true;
+    // Check order was cancelled and its account closed
+    const cancelledOrder = await provider.connection.getAccountInfo(orderPda);
+    expect(cancelledOrder).to.be.null;
+
+    const traderAfterCancel = await program.account.traderAccount.fetch(traderPda);
+    expect(traderAfterCancel.openOrders).to.be.empty;
+    expect(traderAfterCancel.lockedQuote.toNumber()).to.equal(0);
 
     // Check tokens were returned
     const finalVaultBalance = await getAccount(provider.connection, quoteVault);
@@ -366,8 +811,17 @@ describe("darkpool", () => {
       program.programId
     );
 
+    const [user1Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const [user2Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+
     // Place bid order (user1 buying base with quote)
-    const bidNonce = new anchor.BN(2);
+    const bidNonce = (await program.account.traderAccount.fetch(user1Trader)).nextNonce;
     const [bidOrderPda] = PublicKey.findProgramAddressSync(
       [
         Buffer.from("order"),
@@ -382,23 +836,27 @@ describe("darkpool", () => {
     const bidMinAmountOut = new anchor.BN(90 * 10 ** 9); // 90 base tokens
 
     await program.methods
-      .placeOrder({ bid: {} }, bidAmountIn, bidMinAmountOut, bidNonce)
+      .placeOrder({ bid: {} }, bidAmountIn, bidMinAmountOut, bidNonce, new anchor.BN(0), { goodTillCancel: {} })
       .accounts({
         order: bidOrderPda,
         owner: user1.publicKey,
         market: marketPda,
+        traderAccount: user1Trader,
         userBaseAccount: user1BaseAccount,
         userQuoteAccount: user1QuoteAccount,
         baseVault,
         quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .signers([user1])
       .rpc();
 
     // Place ask order (user2 selling base for quote)
-    const askNonce = new anchor.BN(1);
+    const askNonce = (await program.account.traderAccount.fetch(user2Trader)).nextNonce;
     const [askOrderPda] = PublicKey.findProgramAddressSync(
       [
         Buffer.from("order"),
@@ -413,16 +871,20 @@ describe("darkpool", () => {
     const askMinAmountOut = new anchor.BN(95 * 10 ** 9); // 95 quote tokens
 
     await program.methods
-      .placeOrder({ ask: {} }, askAmountIn, askMinAmountOut, askNonce)
+      .placeOrder({ ask: {} }, askAmountIn, askMinAmountOut, askNonce, new anchor.BN(0), { goodTillCancel: {} })
       .accounts({
         order: askOrderPda,
         owner: user2.publicKey,
         market: marketPda,
+        traderAccount: user2Trader,
         userBaseAccount: user2BaseAccount,
         userQuoteAccount: user2QuoteAccount,
         baseVault,
         quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .signers([user2])
@@ -456,34 +918,47 @@ describe("darkpool", () => {
     };
 
     // Settle batch
+    const { batch, settlementRecord } = await openBatch(marketPda, [bidOrderPda, askOrderPda]);
+    const filled: any[] = [];
+    const listener = program.addEventListener("orderFilled", (event) => {
+      filled.push(event);
+    });
     await program.methods
       .settleBatch([fill], Buffer.from("arcium-signature"))
       .accounts({
         config,
-        admin: admin.publicKey,
+        solver: admin.publicKey,
         market: marketPda,
-        baseVault,
-        quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        batch,
+        solverBond: null,
+        settlementRecord,
       })
-      .remainingAccounts([
-        { pubkey: bidOrderPda, isSigner: false, isWritable: true },
-        { pubkey: askOrderPda, isSigner: false, isWritable: true },
-        { pubkey: user1BaseAccount, isSigner: false, isWritable: true },
-        { pubkey: user1QuoteAccount, isSigner: false, isWritable: true },
-        { pubkey: user2BaseAccount, isSigner: false, isWritable: true },
-        { pubkey: user2QuoteAccount, isSigner: false, isWritable: true },
-      ])
+      .remainingAccounts(settlementAccounts(marketPda, [bidOrderPda, askOrderPda]))
       .rpc();
 
-    // Check orders were updated
-    const bidOrder = await program.account.order.fetch(bidOrderPda);
-    const askOrder = await program.account.order.fetch(askOrderPda);
+    // One OrderFilled event per fill, tagged with the batch it settled in
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.removeEventListener(listener);
+    expect(filled).to.have.lengthOf(1);
+    expect(filled[0].version).to.equal(1);
+    expect(filled[0].order.toString()).to.equal(bidOrderPda.toString());
+    expect(filled[0].batchSeq.toString()).to.equal(
+      (await program.account.batch.fetch(batch)).seq.toString()
+    );
+    expect(filled[0].amountOut.toString()).to.equal(askAmountIn.toString());
+    expect(filled[0].orderStatus).to.deep.equal({ filled: {} });
+
+    // Both orders were filled completely, so settlement closed them
+    expect(await provider.connection.getAccountInfo(bidOrderPda)).to.be.null;
+    expect(await provider.connection.getAccountInfo(askOrderPda)).to.be.null;
 
-    expect(bidOrder.filledAmountIn.toNumber()).to.equal(bidAmountIn.toNumber());
-    expect(askOrder.filledAmountIn.toNumber()).to.equal(askAmountIn.toNumber());
-    expect(bidOrder.status.filled || bidOrder.status.partiallyFilled).to.be.true;
-    expect(askOrder.status.filled || askOrder.status.partiallyFilled).to.be.true;
+    // ...and released them from both trader accounts
+    for (const traderPda of [user1Trader, user2Trader]) {
+      const trader = await program.account.traderAccount.fetch(traderPda);
+      expect(trader.openOrders).to.be.empty;
+      expect(trader.lockedBase.toNumber()).to.equal(0);
+      expect(trader.lockedQuote.toNumber()).to.equal(0);
+    }
 
     // Check vault balances decreased
     const finalBaseVault = await getAccount(provider.connection, baseVault);
@@ -506,6 +981,1556 @@ describe("darkpool", () => {
     expect(Number(finalUser2Quote.amount)).to.equal(
       Number(initialUser2Quote.amount) + bidAmountIn.toNumber()
     );
+
+    // The settlement is on record for challenges
+    const record = await program.account.settlementRecord.fetch(settlementRecord);
+    expect(record.solver.toString()).to.equal(admin.publicKey.toString());
+    expect(record.fills).to.have.lengthOf(1);
+    expect(record.fills[0].orderMinAmountOut.toString()).to.equal(bidMinAmountOut.toString());
+
+    // The batch is consumed, so the same fills can't be settled again
+    expect((await program.account.batch.fetch(batch)).settled).to.be.true;
+    try {
+      await program.methods
+        .settleBatch([fill], Buffer.from("arcium-signature"))
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          solverBond: null,
+          settlementRecord,
+        })
+        .rpc();
+      expect.fail("Expected the batch to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("BatchAlreadySettled");
+    }
+  });
+
+  it("Cancels a partially filled order", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("base"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("quote"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [user1Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // A bid for 20 that an ask of 8 only partly fills
+    const orders: PublicKey[] = [];
+    for (const [owner, side, amount] of [
+      [user1, { bid: {} }, 20],
+      [user2, { ask: {} }, 8],
+    ] as const) {
+      const [trader] = PublicKey.findProgramAddressSync(
+        [Buffer.from("trader"), marketPda.toBuffer(), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const nonce = (await program.account.traderAccount.fetch(trader)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          owner.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .placeOrder(side, new anchor.BN(amount * 10 ** 9), new anchor.BN(1), nonce, new anchor.BN(0), { goodTillCancel: {} })
+        .accounts({
+          order: orderPda,
+          owner: owner.publicKey,
+          market: marketPda,
+          traderAccount: trader,
+          userBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          userQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      orders.push(orderPda);
+    }
+    const [bid, ask] = orders;
+
+    const eight = new anchor.BN(8 * 10 ** 9);
+    const { batch, settlementRecord } = await openBatch(marketPda, orders);
+    await program.methods
+      .settleBatch(
+        [{ order: bid, counterparty: ask, amountIn: eight, amountOut: eight }],
+        Buffer.from("arcium-signature")
+      )
+      .accounts({
+        config,
+        solver: admin.publicKey,
+        market: marketPda,
+        batch,
+        solverBond: null,
+        settlementRecord,
+      })
+      .remainingAccounts(settlementAccounts(marketPda, orders))
+      .rpc();
+    expect((await program.account.order.fetch(bid)).status).to.deep.equal({
+      partiallyFilled: {},
+    });
+
+    // Cancelling refunds what the bid has left
+    const before = await getAccount(provider.connection, user1QuoteAccount);
+    await program.methods
+      .cancelOrder()
+      .accounts({
+        order: bid,
+        owner: user1.publicKey,
+        market: marketPda,
+        traderAccount: user1Trader,
+        userBaseAccount: user1BaseAccount,
+        userQuoteAccount: user1QuoteAccount,
+        baseVault,
+        quoteVault,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([user1])
+      .rpc();
+    const after = await getAccount(provider.connection, user1QuoteAccount);
+    expect((after.amount - before.amount).toString()).to.equal((12 * 10 ** 9).toString());
+    expect(await provider.connection.getAccountInfo(bid)).to.be.null;
+  });
+
+  it("Slashes a bonded solver for a fill below an order's limit", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("base"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("quote"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [solverBond] = PublicKey.findProgramAddressSync(
+      [Buffer.from("bond"), admin.publicKey.toBuffer()],
+      program.programId
+    );
+    const [bondVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("bond_vault"), admin.publicKey.toBuffer()],
+      program.programId
+    );
+    const [user1Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const [user2Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // Bonds are held in the quote mint; settling now needs 50 of them
+    const minBond = new anchor.BN(50 * 10 ** 9);
+    const slashAmount = new anchor.BN(20 * 10 ** 9);
+    await applyChange({
+      setBondParams: {
+        mint: quoteMint,
+        minBond,
+        slashAmount,
+        unbondingDelay: new anchor.BN(3600),
+      },
+    });
+
+    const adminQuoteAccount = await createAccount(
+      provider.connection,
+      admin.payer,
+      quoteMint,
+      admin.publicKey
+    );
+    await mintTo(
+      provider.connection,
+      admin.payer,
+      quoteMint,
+      adminQuoteAccount,
+      admin.publicKey,
+      100 * 10 ** 9
+    );
+
+    await program.methods
+      .initSolverBond()
+      .accounts({
+        config,
+        solverBond,
+        solver: admin.publicKey,
+        bondMint: quoteMint,
+        bondVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .depositBond(minBond)
+      .accounts({
+        solverBond,
+        solver: admin.publicKey,
+        bondVault,
+        solverTokenAccount: adminQuoteAccount,
+        bondMint: quoteMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    // The bid wants at least 85 base from each fill
+    const orders: PublicKey[] = [];
+    for (const [owner, trader, side, amountIn, minAmountOut] of [
+      [user1, user1Trader, { bid: {} }, 90, 85],
+      [user2, user2Trader, { ask: {} }, 90, 0],
+    ] as const) {
+      const nonce = (await program.account.traderAccount.fetch(trader)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          owner.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .placeOrder(
+          side,
+          new anchor.BN(amountIn * 10 ** 9),
+          new anchor.BN(minAmountOut * 10 ** 9),
+          nonce,
+          new anchor.BN(0),
+          { goodTillCancel: {} }
+        )
+        .accounts({
+          order: orderPda,
+          owner: owner.publicKey,
+          market: marketPda,
+          traderAccount: trader,
+          userBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          userQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      orders.push(orderPda);
+    }
+    const [bidOrderPda, askOrderPda] = orders;
+
+    // The batch only commits to the bid, but the solver fills it against the
+    // ask anyway
+    const { batch, batchInputs, settlementRecord } = await openBatch(marketPda, [bidOrderPda]);
+    const inputs = await program.account.batchInputs.fetch(batchInputs);
+    expect(inputs.orders.map((o) => o.toBase58())).to.deep.equal([bidOrderPda.toBase58()]);
+    expect((await program.account.batch.fetch(batch)).inputsCommitted).to.be.true;
+
+    const settle = (amount: number) =>
+      program.methods
+        .settleBatch(
+          [
+            {
+              order: bidOrderPda,
+              counterparty: askOrderPda,
+              amountIn: new anchor.BN(amount * 10 ** 9),
+              amountOut: new anchor.BN(amount * 10 ** 9),
+            },
+          ],
+          Buffer.from("arcium-signature")
+        )
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          solverBond,
+          settlementRecord,
+        })
+        .remainingAccounts(settlementAccounts(marketPda, [bidOrderPda, askOrderPda]))
+        .rpc();
+
+    // A fill giving the bid less than its minimum never settles
+    try {
+      await settle(80);
+      expect.fail("Expected the fill to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("MinAmountOutNotMet");
+    }
+    await settle(90);
+
+    // The published inputs show the ask was never matched over, and only
+    // the ask's owner was hurt
+    const challengeAccounts = {
+      config,
+      settlementRecord,
+      batchInputs,
+      solverBond,
+      bondVault,
+      orderOwnerAccount: user1QuoteAccount,
+      counterpartyOwnerAccount: user2QuoteAccount,
+      challenger: user1.publicKey,
+      bondMint: quoteMint,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    const before = await getAccount(provider.connection, user2QuoteAccount);
+    await program.methods
+      .challengeSettlement(0, { orderOutsideCommitment: {} })
+      .accounts(challengeAccounts)
+      .signers([user1])
+      .rpc();
+
+    const after = await getAccount(provider.connection, user2QuoteAccount);
+    expect(Number(after.amount) - Number(before.amount)).to.equal(slashAmount.toNumber());
+
+    const bond = await program.account.solverBond.fetch(solverBond);
+    expect(bond.amount.toString()).to.equal(minBond.sub(slashAmount).toString());
+    expect((await program.account.settlementRecord.fetch(settlementRecord)).slashed).to.be.true;
+
+    // The slashed bond is below the minimum, so the solver can't settle
+    // again until it tops up; drop the requirement for the remaining tests
+    await applyChange({
+      setBondParams: {
+        mint: quoteMint,
+        minBond: new anchor.BN(0),
+        slashAmount,
+        unbondingDelay: new anchor.BN(3600),
+      },
+    });
+  });
+
+  it("Settles a batch through claims", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("base"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("quote"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [user1Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const [user2Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const marketAccount = await program.account.market.fetch(marketPda);
+
+    const amount = new anchor.BN(40 * 10 ** 9);
+    const orders: PublicKey[] = [];
+    for (const [owner, trader, side] of [
+      [user1, user1Trader, { bid: {} }],
+      [user2, user2Trader, { ask: {} }],
+    ] as const) {
+      const nonce = (await program.account.traderAccount.fetch(trader)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          owner.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .placeOrder(side, amount, amount, nonce, new anchor.BN(0), { goodTillCancel: {} })
+        .accounts({
+          order: orderPda,
+          owner: owner.publicKey,
+          market: marketPda,
+          traderAccount: trader,
+          userBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          userQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      orders.push(orderPda);
+    }
+    const [bidOrderPda, askOrderPda] = orders;
+
+    // Apply the fill without paying anyone out; both orders are filled and
+    // closed straight away
+    const { batch, settlementRecord } = await openBatch(marketPda, orders);
+    const meta = (pubkey: PublicKey) => ({ pubkey, isSigner: false, isWritable: true });
+    await program.methods
+      .applyClaimFills([
+        {
+          order: bidOrderPda,
+          counterparty: askOrderPda,
+          amountIn: amount,
+          amountOut: amount,
+        },
+      ])
+      .accounts({
+        config,
+        solver: admin.publicKey,
+        market: marketPda,
+        batch,
+        solverBond: null,
+        settlementRecord,
+        systemProgram: SystemProgram.programId,
+      })
+      .remainingAccounts([
+        meta(bidOrderPda),
+        meta(askOrderPda),
+        meta(user1.publicKey),
+        meta(user1Trader),
+        meta(user2.publicKey),
+        meta(user2Trader),
+      ])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(bidOrderPda)).to.be.null;
+
+    const batchAccount = await program.account.batch.fetch(batch);
+    expect(batchAccount.fillsApplied).to.equal(1);
+    expect(batchAccount.claimableBase.toString()).to.equal(amount.toString());
+    expect(batchAccount.claimableQuote.toString()).to.equal(amount.toString());
+    expect(batchAccount.claimsCount).to.equal(2);
+
+    // The fill is on record to be challenged, but not until finalization
+    // starts the clock
+    const record = await program.account.settlementRecord.fetch(settlementRecord);
+    expect(record.fills.length).to.equal(1);
+    expect(record.fills[0].order.toBase58()).to.equal(bidOrderPda.toBase58());
+
+    // A batch being claim settled can't be push settled as well
+    try {
+      await program.methods
+        .settleBatch([], Buffer.from("arcium-signature"))
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          solverBond: null,
+          settlementRecord,
+        })
+        .remainingAccounts(settlementAccounts(marketPda, []))
+        .rpc();
+      expect.fail("Expected the push settlement to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("BatchModeMismatch");
+    }
+
+    // The batch added a leaf per order: the bid is owed base and the ask
+    // quote
+    const zero = new anchor.BN(0);
+    const leaves = [
+      claimLeaf(batch, 0, bidOrderPda, user1.publicKey, amount, zero),
+      claimLeaf(batch, 1, askOrderPda, user2.publicKey, zero, amount),
+    ];
+    const bidClaim = claimsTree(leaves, 0);
+    const askClaim = claimsTree(leaves, 1);
+    const finalize = (authority: PublicKey, signers: Keypair[] = []) =>
+      program.methods
+        .finalizeClaims(Buffer.from("arcium-signature"))
+        .accounts({
+          config,
+          authority,
+          market: marketPda,
+          batch,
+          settlementRecord,
+          baseVault,
+          quoteVault,
+          baseFeeVault: marketAccount.baseFeeVault,
+          quoteFeeVault: marketAccount.quoteFeeVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers(signers)
+        .rpc();
+
+    // Only the solver can finalize before the timeout
+    try {
+      await finalize(user1.publicKey, [user1]);
+      expect.fail("Expected the early finalize to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("FinalizeTimeoutNotElapsed");
+    }
+
+    await finalize(admin.publicKey);
+    const finalizedBatch = await program.account.batch.fetch(batch);
+    expect(Buffer.from(finalizedBatch.claimsRoot).equals(bidClaim.root)).to.be.true;
+    const finalized = await program.account.settlementRecord.fetch(settlementRecord);
+    expect(finalized.challengeDeadline.toString()).to.not.equal("9223372036854775807");
+
+    const unclaimed = await program.account.market.fetch(marketPda);
+    expect(unclaimed.unclaimedBase.sub(marketAccount.unclaimedBase).toString()).to.equal(
+      amount.toString()
+    );
+
+    const claim = (
+      owner: Keypair,
+      leafIndex: number,
+      order: PublicKey,
+      baseAmount: anchor.BN,
+      quoteAmount: anchor.BN,
+      proof: Buffer[]
+    ) => {
+      const indexBytes = Buffer.alloc(4);
+      indexBytes.writeUInt32LE(leafIndex);
+      const [claimReceipt] = PublicKey.findProgramAddressSync(
+        [Buffer.from("claim"), batch.toBuffer(), indexBytes],
+        program.programId
+      );
+      return program.methods
+        .claimFill(
+          leafIndex,
+          order,
+          baseAmount,
+          quoteAmount,
+          proof.map((node) => Array.from(node))
+        )
+        .accounts({
+          owner: owner.publicKey,
+          market: marketPda,
+          batch,
+          claimReceipt,
+          ownerBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          ownerQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+    };
+
+    // Claiming more than the leaf says doesn't prove
+    try {
+      await claim(user2, 1, askOrderPda, zero, amount.muln(2), askClaim.proof);
+      expect.fail("Expected the claim to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("InvalidClaimProof");
+    }
+
+    const before = await getAccount(provider.connection, user1BaseAccount);
+    await claim(user1, 0, bidOrderPda, amount, zero, bidClaim.proof);
+    const after = await getAccount(provider.connection, user1BaseAccount);
+    expect((after.amount - before.amount).toString()).to.equal(amount.toString());
+
+    // Each leaf is claimed once
+    try {
+      await claim(user1, 0, bidOrderPda, amount, zero, bidClaim.proof);
+      expect.fail("Expected the second claim to be rejected");
+    } catch (err: any) {
+      expect(err).to.exist;
+    }
+
+    await claim(user2, 1, askOrderPda, zero, amount, askClaim.proof);
+    const settled = await program.account.batch.fetch(batch);
+    expect(settled.settled).to.be.true;
+    expect(settled.claimableBase.toNumber()).to.equal(0);
+    expect(settled.claimableQuote.toNumber()).to.equal(0);
+  });
+
+  it("Settles a batch in chunks", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("base"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("quote"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+
+    const amount = new anchor.BN(60 * 10 ** 9);
+    const orders: PublicKey[] = [];
+    for (const [owner, side] of [
+      [user1, { bid: {} }],
+      [user2, { ask: {} }],
+    ] as const) {
+      const [trader] = PublicKey.findProgramAddressSync(
+        [Buffer.from("trader"), marketPda.toBuffer(), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const nonce = (await program.account.traderAccount.fetch(trader)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          owner.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .placeOrder(side, amount, amount.divn(2), nonce, new anchor.BN(0), { goodTillCancel: {} })
+        .accounts({
+          order: orderPda,
+          owner: owner.publicKey,
+          market: marketPda,
+          traderAccount: trader,
+          userBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          userQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      orders.push(orderPda);
+    }
+    const [bidOrderPda, askOrderPda] = orders;
+
+    // The orders cross in two halves, one per chunk
+    const half = amount.divn(2);
+    const fill = {
+      order: bidOrderPda,
+      counterparty: askOrderPda,
+      amountIn: half,
+      amountOut: half,
+    };
+    const { batch, settlementRecord } = await openBatch(marketPda, orders);
+    const [session] = PublicKey.findProgramAddressSync(
+      [Buffer.from("session"), batch.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .beginSettlement(chainFills([fill, fill]), 2)
+      .accounts({
+        config,
+        solver: admin.publicKey,
+        market: marketPda,
+        batch,
+        session,
+        settlementRecord,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const settleChunk = (chunkIndex: number) =>
+      program.methods
+        .settleChunk(chunkIndex, [fill])
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          session,
+          solverBond: null,
+          settlementRecord,
+        })
+        .remainingAccounts(settlementAccounts(marketPda, orders))
+        .rpc();
+
+    try {
+      await settleChunk(1);
+      expect.fail("Expected the out-of-order chunk to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("ChunkOutOfOrder");
+    }
+
+    const before = await getAccount(provider.connection, user1BaseAccount);
+    await settleChunk(0);
+    const after = await getAccount(provider.connection, user1BaseAccount);
+    expect((after.amount - before.amount).toString()).to.equal(half.toString());
+
+    // The session settles the rest of the ask later, so its owner can't pull
+    // it back in between
+    const [user2Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    expect((await program.account.market.fetch(marketPda)).settlingBatch).to.not.be.null;
+    try {
+      await program.methods
+        .reduceOrder(half)
+        .accounts({
+          order: askOrderPda,
+          owner: user2.publicKey,
+          market: marketPda,
+          traderAccount: user2Trader,
+          userBaseAccount: user2BaseAccount,
+          userQuoteAccount: user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user2])
+        .rpc();
+      expect.fail("Expected the reduce to wait for the session");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("SettlementInProgress");
+    }
+
+    // Half the fills are in, so the session can't finish yet
+    const finalize = () =>
+      program.methods
+        .finalizeSettlement(Buffer.from("arcium-signature"))
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          session,
+          settlementRecord,
+        })
+        .rpc();
+    try {
+      await finalize();
+      expect.fail("Expected finalizing early to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("SessionIncomplete");
+    }
+
+    await settleChunk(1);
+    await finalize();
+
+    const sessionAccount = await program.account.settlementSession.fetch(session);
+    expect(sessionAccount.fillsApplied).to.equal(2);
+    expect(sessionAccount.status).to.deep.equal({ finalized: {} });
+    expect((await program.account.batch.fetch(batch)).settled).to.be.true;
+    expect((await program.account.settlementRecord.fetch(settlementRecord)).fills.length).to.equal(2);
+    expect((await program.account.market.fetch(marketPda)).settlingBatch).to.be.null;
+
+    // Both orders were filled and closed by the last chunk
+    expect(await provider.connection.getAccountInfo(bidOrderPda)).to.be.null;
+    expect(await provider.connection.getAccountInfo(askOrderPda)).to.be.null;
+  });
+
+  it("Fills fill-or-kill orders in full and refunds immediate-or-cancel ones", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("base"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), Buffer.from("quote"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+
+    // A fill-or-kill bid for 20 against two asks of 10, and two
+    // immediate-or-cancel asks nothing crosses
+    const orders: PublicKey[] = [];
+    for (const [owner, side, amount, timeInForce] of [
+      [user1, { bid: {} }, 20, { fillOrKill: {} }],
+      [user2, { ask: {} }, 10, { goodTillCancel: {} }],
+      [user2, { ask: {} }, 10, { goodTillCancel: {} }],
+      [user2, { ask: {} }, 5, { immediateOrCancel: {} }],
+      [user2, { ask: {} }, 3, { immediateOrCancel: {} }],
+    ] as const) {
+      const [trader] = PublicKey.findProgramAddressSync(
+        [Buffer.from("trader"), marketPda.toBuffer(), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const nonce = (await program.account.traderAccount.fetch(trader)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          owner.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      await program.methods
+        .placeOrder(side, new anchor.BN(amount * 10 ** 9), new anchor.BN(1), nonce, new anchor.BN(0), timeInForce)
+        .accounts({
+          order: orderPda,
+          owner: owner.publicKey,
+          market: marketPda,
+          traderAccount: trader,
+          userBaseAccount: owner === user1 ? user1BaseAccount : user2BaseAccount,
+          userQuoteAccount: owner === user1 ? user1QuoteAccount : user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      orders.push(orderPda);
+    }
+    const [fokBid, firstAsk, secondAsk, iocAsk, skippedIocAsk] = orders;
+
+    const ten = new anchor.BN(10 * 10 ** 9);
+    const fills = [firstAsk, secondAsk].map((counterparty) => ({
+      order: fokBid,
+      counterparty,
+      amountIn: ten,
+      amountOut: ten,
+    }));
+    const { batch, settlementRecord } = await openBatch(marketPda, orders);
+    const settle = (fills: any[]) =>
+      program.methods
+        .settleBatch(fills, Buffer.from("arcium-signature"))
+        .accounts({
+          config,
+          solver: admin.publicKey,
+          market: marketPda,
+          batch,
+          solverBond: null,
+          settlementRecord,
+        })
+        .remainingAccounts(settlementAccounts(marketPda, orders.slice(0, 4)))
+        .rpc();
+
+    // Refunds an immediate-or-cancel ask the settlement left behind
+    const [user2Trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const refundIoc = () =>
+      program.methods
+        .expireOrder()
+        .accounts({
+          order: skippedIocAsk,
+          cranker: user1.publicKey,
+          owner: user2.publicKey,
+          market: marketPda,
+          batch,
+          traderAccount: user2Trader,
+          ownerBaseAccount: user2BaseAccount,
+          ownerQuoteAccount: user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+    // Not while its batch is still open
+    try {
+      await refundIoc();
+      expect.fail("Expected the refund to wait for the batch");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("OrderNotExpired");
+    }
+
+    // Half of the fill-or-kill bid isn't enough
+    try {
+      await settle(fills.slice(0, 1));
+      expect.fail("Expected the partial fill to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("FillOrKillNotFilled");
+    }
+
+    const before = await getAccount(provider.connection, user2BaseAccount);
+    await settle(fills);
+    const after = await getAccount(provider.connection, user2BaseAccount);
+
+    // The bid filled across both asks, and the unfilled IOC ask was refunded
+    // and closed by the same settlement
+    expect(await provider.connection.getAccountInfo(fokBid)).to.be.null;
+    expect(await provider.connection.getAccountInfo(iocAsk)).to.be.null;
+    expect((after.amount - before.amount).toString()).to.equal((5 * 10 ** 9).toString());
+
+    // The solver left the other IOC ask out, so anyone can refund it now its
+    // batch has settled
+    expect(await provider.connection.getAccountInfo(skippedIocAsk)).to.not.be.null;
+    await refundIoc();
+    const refunded = await getAccount(provider.connection, user2BaseAccount);
+    expect(await provider.connection.getAccountInfo(skippedIocAsk)).to.be.null;
+    expect((refunded.amount - after.amount).toString()).to.equal((3 * 10 ** 9).toString());
+  });
+
+  it("Accounts for everything the vaults hold", async () => {
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+      program.programId
+    );
+    const marketAccount = await program.account.market.fetch(marketPda);
+    const baseVault = await getAccount(provider.connection, marketAccount.baseVault);
+    const quoteVault = await getAccount(provider.connection, marketAccount.quoteVault);
+
+    // Every open order's remainder and every unclaimed proceed is covered
+    const owedBase = marketAccount.lockedBase.add(marketAccount.unclaimedBase);
+    const owedQuote = marketAccount.lockedQuote.add(marketAccount.unclaimedQuote);
+    expect(new anchor.BN(baseVault.amount.toString()).gte(owedBase)).to.be.true;
+    expect(new anchor.BN(quoteVault.amount.toString()).gte(owedQuote)).to.be.true;
+    expect(marketAccount.baseVolume.gtn(0)).to.be.true;
+
+    let verified: any;
+    const listener = program.addEventListener("solvencyVerified", (event) => {
+      verified = event;
+    });
+    await program.methods
+      .verifySolvency()
+      .accounts({
+        market: marketPda,
+        baseVault: marketAccount.baseVault,
+        quoteVault: marketAccount.quoteVault,
+      })
+      .rpc();
+    await program.removeEventListener(listener);
+
+    expect(verified.lockedBase.toString()).to.equal(marketAccount.lockedBase.toString());
+    expect(verified.baseVaultAmount.toString()).to.equal(baseVault.amount.toString());
+  });
+
+  it("Cancels all of an owner's orders", async () => {
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("base"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("quote"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user1.publicKey.toBuffer()],
+      program.programId
+    );
+
+    const initialBase = await getAccount(provider.connection, user1BaseAccount);
+    const initialQuote = await getAccount(provider.connection, user1QuoteAccount);
+
+    // Place a bid and an ask so both mints need refunding
+    const orders: PublicKey[] = [];
+    for (const side of [{ bid: {} }, { ask: {} }]) {
+      const nonce = (await program.account.traderAccount.fetch(traderPda)).nextNonce;
+      const [orderPda] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("order"),
+          marketPda.toBuffer(),
+          user1.publicKey.toBuffer(),
+          nonce.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+
+      await program.methods
+        .placeOrder(side, new anchor.BN(10 * 10 ** 9), new anchor.BN(1), nonce, new anchor.BN(0), { goodTillCancel: {} })
+        .accounts({
+          order: orderPda,
+          owner: user1.publicKey,
+          market: marketPda,
+          traderAccount: traderPda,
+          userBaseAccount: user1BaseAccount,
+          userQuoteAccount: user1QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+      orders.push(orderPda);
+    }
+
+    const trader = await program.account.traderAccount.fetch(traderPda);
+    expect(trader.openOrders).to.have.lengthOf(2);
+
+    await program.methods
+      .cancelAllOrders()
+      .accounts({
+        owner: user1.publicKey,
+        market: marketPda,
+        traderAccount: traderPda,
+        userBaseAccount: user1BaseAccount,
+        userQuoteAccount: user1QuoteAccount,
+        baseVault,
+        quoteVault,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(
+        trader.openOrders.map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }))
+      )
+      .signers([user1])
+      .rpc();
+
+    // Every order was refunded and closed
+    for (const orderPda of orders) {
+      expect(await provider.connection.getAccountInfo(orderPda)).to.be.null;
+    }
+    const traderAfter = await program.account.traderAccount.fetch(traderPda);
+    expect(traderAfter.openOrders).to.be.empty;
+    expect(traderAfter.lockedBase.toNumber()).to.equal(0);
+    expect(traderAfter.lockedQuote.toNumber()).to.equal(0);
+
+    const finalBase = await getAccount(provider.connection, user1BaseAccount);
+    const finalQuote = await getAccount(provider.connection, user1QuoteAccount);
+    expect(finalBase.amount).to.equal(initialBase.amount);
+    expect(finalQuote.amount).to.equal(initialQuote.amount);
+  });
+
+  it("Amends an order in place", async () => {
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("base"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("quote"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const nonce = (await program.account.traderAccount.fetch(traderPda)).nextNonce;
+    const [orderPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("order"),
+        marketPda.toBuffer(),
+        user2.publicKey.toBuffer(),
+        nonce.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+
+    const accounts = {
+      order: orderPda,
+      owner: user2.publicKey,
+      market: marketPda,
+      traderAccount: traderPda,
+      userBaseAccount: user2BaseAccount,
+      userQuoteAccount: user2QuoteAccount,
+      baseVault,
+      quoteVault,
+      baseMint,
+      quoteMint,
+      baseTokenProgram: TOKEN_PROGRAM_ID,
+      quoteTokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    await program.methods
+      .placeOrder({ ask: {} }, new anchor.BN(50 * 10 ** 9), new anchor.BN(1), nonce, new anchor.BN(0), { goodTillCancel: {} })
+      .accounts({ ...accounts, systemProgram: SystemProgram.programId })
+      .signers([user2])
+      .rpc();
+    const placed = await program.account.order.fetch(orderPda);
+    const baseAfterPlace = await getAccount(provider.connection, user2BaseAccount);
+
+    // Reducing refunds the difference and keeps the order's priority
+    await program.methods
+      .amendOrder(new anchor.BN(30 * 10 ** 9), new anchor.BN(2))
+      .accounts(accounts)
+      .signers([user2])
+      .rpc();
+
+    const reduced = await program.account.order.fetch(orderPda);
+    expect(reduced.amountIn.toNumber()).to.equal(30 * 10 ** 9);
+    expect(reduced.minAmountOut.toNumber()).to.equal(2);
+    expect(reduced.createdAt.toNumber()).to.equal(placed.createdAt.toNumber());
+    const baseAfterReduce = await getAccount(provider.connection, user2BaseAccount);
+    expect(baseAfterReduce.amount - baseAfterPlace.amount).to.equal(BigInt(20 * 10 ** 9));
+
+    // Increasing deposits the difference and resets the priority
+    await new Promise((resolve) => setTimeout(resolve, 1500));
+    await program.methods
+      .amendOrder(new anchor.BN(80 * 10 ** 9), new anchor.BN(2))
+      .accounts(accounts)
+      .signers([user2])
+      .rpc();
+
+    const increased = await program.account.order.fetch(orderPda);
+    expect(increased.amountIn.toNumber()).to.equal(80 * 10 ** 9);
+    expect(increased.createdAt.toNumber()).to.be.greaterThan(placed.createdAt.toNumber());
+    const baseAfterIncrease = await getAccount(provider.connection, user2BaseAccount);
+    expect(baseAfterReduce.amount - baseAfterIncrease.amount).to.equal(BigInt(50 * 10 ** 9));
+
+    const trader = await program.account.traderAccount.fetch(traderPda);
+    expect(trader.lockedBase.toNumber()).to.equal(80 * 10 ** 9);
+  });
+
+  it("Reduces part of an open order", async () => {
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("base"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("quote"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    // Reuse the 80 base ask left open by the amend test
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const [orderPda] = (await program.account.traderAccount.fetch(traderPda)).openOrders;
+    const before = await program.account.order.fetch(orderPda);
+    const baseBefore = await getAccount(provider.connection, user2BaseAccount);
+
+    await program.methods
+      .reduceOrder(new anchor.BN(30 * 10 ** 9))
+      .accounts({
+        order: orderPda,
+        owner: user2.publicKey,
+        market: marketPda,
+        traderAccount: traderPda,
+        userBaseAccount: user2BaseAccount,
+        userQuoteAccount: user2QuoteAccount,
+        baseVault,
+        quoteVault,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([user2])
+      .rpc();
+
+    // The rest stays in the pool with its priority
+    const after = await program.account.order.fetch(orderPda);
+    expect(after.status.open).to.be.true;
+    expect(after.amountIn.toNumber()).to.equal(before.amountIn.toNumber() - 30 * 10 ** 9);
+    expect(after.createdAt.toNumber()).to.equal(before.createdAt.toNumber());
+
+    const baseAfter = await getAccount(provider.connection, user2BaseAccount);
+    expect(baseAfter.amount - baseBefore.amount).to.equal(BigInt(30 * 10 ** 9));
+
+    const trader = await program.account.traderAccount.fetch(traderPda);
+    expect(trader.lockedBase.toNumber()).to.equal(after.amountIn.toNumber());
+  });
+
+  it("Pauses a market but still lets owners cancel", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [baseVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("base"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [quoteVault] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("vault"),
+        Buffer.from("quote"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), marketPda.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // Pausing skips the timelock
+    await program.methods
+      .pauseMarket()
+      .accounts({ config, admin: admin.publicKey, market: marketPda })
+      .rpc();
+
+    // New orders are rejected
+    const nonce = (await program.account.traderAccount.fetch(traderPda)).nextNonce;
+    const [orderPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("order"),
+        marketPda.toBuffer(),
+        user2.publicKey.toBuffer(),
+        nonce.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    try {
+      await program.methods
+        .placeOrder({ ask: {} }, new anchor.BN(10), new anchor.BN(1), nonce, new anchor.BN(0), { goodTillCancel: {} })
+        .accounts({
+          order: orderPda,
+          owner: user2.publicKey,
+          market: marketPda,
+          traderAccount: traderPda,
+          userBaseAccount: user2BaseAccount,
+          userQuoteAccount: user2QuoteAccount,
+          baseVault,
+          quoteVault,
+          baseMint,
+          quoteMint,
+          baseTokenProgram: TOKEN_PROGRAM_ID,
+          quoteTokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user2])
+        .rpc();
+      expect.fail("placing an order in a paused market should fail");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("MarketNotAcceptingOrders");
+    }
+
+    // The order left open by the reduce test can still be cancelled
+    const [openOrder] = (await program.account.traderAccount.fetch(traderPda)).openOrders;
+    await program.methods
+      .cancelOrder()
+      .accounts({
+        order: openOrder,
+        owner: user2.publicKey,
+        market: marketPda,
+        traderAccount: traderPda,
+        userBaseAccount: user2BaseAccount,
+        userQuoteAccount: user2QuoteAccount,
+        baseVault,
+        quoteVault,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([user2])
+      .rpc();
+    expect(await provider.connection.getAccountInfo(openOrder)).to.be.null;
+
+    await applyChange({ setMarketStatus: { market: marketPda, status: { active: {} } } }, marketPda);
+    const market = await program.account.market.fetch(marketPda);
+    expect(market.status.active).to.not.be.undefined;
+  });
+
+  it("Delists and closes a market that owes nothing", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+
+    const [marketPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("market"),
+        baseMint.toBuffer(),
+        quoteMint.toBuffer(),
+      ],
+      program.programId
+    );
+
+    const marketAccount = await program.account.market.fetch(marketPda);
+
+    await applyChange({ setMarketStatus: { market: marketPda, status: { closed: {} } } }, marketPda);
+
+    // Tokens sent to a vault directly belong to no order
+    const donated = 3 * 10 ** 9;
+    await transfer(
+      provider.connection,
+      user1,
+      user1BaseAccount,
+      marketAccount.baseVault,
+      user1,
+      donated
+    );
+    const [adminBaseAccount, adminQuoteAccount] = await Promise.all(
+      [baseMint, quoteMint].map((mint) =>
+        createAccount(provider.connection, admin.payer, mint, admin.publicKey, Keypair.generate())
+      )
+    );
+
+    // Every order has been refunded and every claim paid, so anyone can
+    // close the market, sweeping what's left in the vaults to the admin
+    const cranker = Keypair.generate();
+    await program.methods
+      .closeMarket()
+      .accounts({
+        config,
+        admin: admin.publicKey,
+        cranker: cranker.publicKey,
+        market: marketPda,
+        baseVault: marketAccount.baseVault,
+        quoteVault: marketAccount.quoteVault,
+        baseFeeVault: marketAccount.baseFeeVault,
+        quoteFeeVault: marketAccount.quoteFeeVault,
+        adminBaseAccount,
+        adminQuoteAccount,
+        baseMint,
+        quoteMint,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([cranker])
+      .rpc();
+
+    const swept = await getAccount(provider.connection, adminBaseAccount);
+    expect(Number(swept.amount)).to.be.at.least(donated);
+
+    expect(await provider.connection.getAccountInfo(marketPda)).to.be.null;
+    expect(await provider.connection.getAccountInfo(marketAccount.baseVault)).to.be.null;
+    expect(await provider.connection.getAccountInfo(marketAccount.quoteVault)).to.be.null;
+  });
+
+  it("Credits what reaches the vault for a transfer-fee mint", async () => {
+    // 1% fee on the base token, quoted in a legacy SPL Token mint
+    const feeMint = await createToken2022Mint(ExtensionType.TransferFeeConfig, (mint) =>
+      createInitializeTransferFeeConfigInstruction(
+        mint,
+        admin.publicKey,
+        admin.publicKey,
+        100,
+        BigInt(10 ** 18),
+        TOKEN_2022_PROGRAM_ID
+      )
+    );
+    const plainMint = await createMint(
+      provider.connection,
+      admin.payer,
+      admin.publicKey,
+      null,
+      9
+    );
+
+    const accounts = createMarketAccounts(
+      feeMint,
+      plainMint,
+      TOKEN_2022_PROGRAM_ID,
+      TOKEN_PROGRAM_ID
+    );
+    await program.methods.createMarket(feeMint, plainMint).accounts(accounts).rpc();
+    const created = await program.account.market.fetch(accounts.market);
+    expect(created.baseTokenProgram.toBase58()).to.equal(TOKEN_2022_PROGRAM_ID.toBase58());
+    expect(created.quoteTokenProgram.toBase58()).to.equal(TOKEN_PROGRAM_ID.toBase58());
+
+    const [traderPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader"), accounts.market.toBuffer(), user2.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // No init_trader_account: the first order creates the trader account
+    const userBase = await createAccount(
+      provider.connection,
+      admin.payer,
+      feeMint,
+      user2.publicKey,
+      undefined,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+    const userQuote = await createAccount(provider.connection, admin.payer, plainMint, user2.publicKey);
+    await mintTo(
+      provider.connection,
+      admin.payer,
+      feeMint,
+      userBase,
+      admin.publicKey,
+      10 ** 9,
+      [],
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+
+    const [orderPda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("order"),
+        accounts.market.toBuffer(),
+        user2.publicKey.toBuffer(),
+        new anchor.BN(0).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    await program.methods
+      .placeOrder({ ask: {} }, new anchor.BN(10 ** 9), new anchor.BN(500), new anchor.BN(0), new anchor.BN(0), { goodTillCancel: {} })
+      .accounts({
+        order: orderPda,
+        owner: user2.publicKey,
+        market: accounts.market,
+        traderAccount: traderPda,
+        userBaseAccount: userBase,
+        userQuoteAccount: userQuote,
+        baseVault: accounts.baseVault,
+        quoteVault: accounts.quoteVault,
+        baseMint: feeMint,
+        quoteMint: plainMint,
+        baseTokenProgram: TOKEN_2022_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user2])
+      .rpc();
+
+    // The order is for what arrived, with its limit price kept
+    const order = await program.account.order.fetch(orderPda);
+    expect(order.amountIn.toNumber()).to.equal(99 * 10 ** 7);
+    expect(order.minAmountOut.toNumber()).to.equal(495);
+
+    const vault = await getAccount(provider.connection, accounts.baseVault, undefined, TOKEN_2022_PROGRAM_ID);
+    expect(vault.amount).to.equal(BigInt(99 * 10 ** 7));
+    const market = await program.account.market.fetch(accounts.market);
+    expect(market.lockedBase.toNumber()).to.equal(99 * 10 ** 7);
+    const trader = await program.account.traderAccount.fetch(traderPda);
+    expect(trader.owner.toBase58()).to.equal(user2.publicKey.toBase58());
+    expect(trader.nextNonce.toNumber()).to.equal(1);
+    expect(trader.openOrders.map((o) => o.toBase58())).to.deep.equal([orderPda.toBase58()]);
+  });
+
+  it("Rejects a mint with a permanent delegate", async () => {
+    const delegateMint = await createToken2022Mint(ExtensionType.PermanentDelegate, (mint) =>
+      createInitializePermanentDelegateInstruction(mint, admin.publicKey, TOKEN_2022_PROGRAM_ID)
+    );
+    const plainMint = await createMint(
+      provider.connection,
+      admin.payer,
+      admin.publicKey,
+      null,
+      9,
+      undefined,
+      undefined,
+      TOKEN_2022_PROGRAM_ID
+    );
+
+    try {
+      await program.methods
+        .createMarket(delegateMint, plainMint)
+        .accounts(
+          createMarketAccounts(delegateMint, plainMint, TOKEN_2022_PROGRAM_ID, TOKEN_2022_PROGRAM_ID)
+        )
+        .rpc();
+      expect.fail("Expected the market to be rejected");
+    } catch (err: any) {
+      expect(err.error?.errorCode?.code).to.equal("UnsupportedMintExtension");
+    }
   });
 });
 
@@ -8,6 +8,8 @@
  *   export DARKPOOL_ADMIN_KEYPAIR="~/.config/solana/id.json"
  *   export BASE_MINT="<base-mint-address>"
  *   export QUOTE_MINT="<quote-mint-address>"
+ *   export SOLVER_PUBKEY="<solver-pubkey>"  # optional, registers the relayer's key
+ *   export TIMELOCK_DELAY="86400"          # optional, seconds; defaults to 0
  *   ts-node scripts/bootstrap-market.ts
  */
 
@@ -21,6 +23,8 @@ const programId = process.env.DARKPOOL_PROGRAM_ID;
 const adminKeypairPath = process.env.DARKPOOL_ADMIN_KEYPAIR;
 const baseMintStr = process.env.BASE_MINT;
 const quoteMintStr = process.env.QUOTE_MINT;
+const solverPubkeyStr = process.env.SOLVER_PUBKEY;
+const timelockDelay = parseInt(process.env.TIMELOCK_DELAY ?? '0', 10);
 
 if (!programId || !adminKeypairPath || !baseMintStr || !quoteMintStr) {
   console.error('Missing required environment variables:');
@@ -64,15 +68,24 @@ async function bootstrap() {
     program.programId
   );
 
+  // Only the program's upgrade authority may initialize the config
+  const [programData] = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111')
+  );
+
   console.log('📋 Step 1: Initialize config');
   console.log(`   Config PDA: ${configPda.toString()}`);
+  console.log(`   Timelock delay: ${timelockDelay}s`);
 
   try {
     const tx = await program.methods
-      .initializeConfig(adminKeypair.publicKey)
+      .initializeConfig(adminKeypair.publicKey, new anchor.BN(timelockDelay))
       .accounts({
         config: configPda,
         payer: adminKeypair.publicKey,
+        program: program.programId,
+        programData,
         systemProgram: SystemProgram.programId,
       })
       .rpc();
@@ -135,7 +148,8 @@ async function bootstrap() {
         quoteMint,
         baseVault,
         quoteVault,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         rent: SYSVAR_RENT_PUBKEY,
         systemProgram: SystemProgram.programId,
       })
@@ -152,6 +166,59 @@ async function bootstrap() {
     }
   }
 
+  // 3. Register the relayer's solver key; solver changes go through the
+  // config timelock, so this only queues it unless the delay has passed
+  if (solverPubkeyStr) {
+    const solver = new PublicKey(solverPubkeyStr);
+
+    console.log('📋 Step 3: Register solver');
+    console.log(`   Solver: ${solver.toString()}`);
+
+    const configAccount = await (program.account as any).config.fetch(configPda);
+    if (configAccount.solvers.some((s: PublicKey) => s.equals(solver))) {
+      console.log(`   ⚠️  Solver already registered (skipping)\n`);
+    } else {
+      const [pendingChange] = PublicKey.findProgramAddressSync(
+        [Buffer.from('change'), configAccount.nextChangeId.toArrayLike(Buffer, 'le', 8)],
+        program.programId
+      );
+
+      try {
+        const queueTx = await program.methods
+          .queueChange({ addSolver: { solver } })
+          .accounts({
+            config: configPda,
+            pendingChange,
+            admin: adminKeypair.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+        console.log(`   ✅ Solver change queued: ${pendingChange.toString()}`);
+        console.log(`   Transaction: https://explorer.solana.com/tx/${queueTx}?cluster=devnet`);
+
+        if (configAccount.timelockDelay.toNumber() === 0) {
+          const executeTx = await program.methods
+            .executeChange()
+            .accounts({
+              config: configPda,
+              pendingChange,
+              admin: adminKeypair.publicKey,
+              executor: adminKeypair.publicKey,
+              market: null,
+            })
+            .rpc();
+          console.log(`   ✅ Solver registered`);
+          console.log(`   Transaction: https://explorer.solana.com/tx/${executeTx}?cluster=devnet\n`);
+        } else {
+          console.log(`   ⏳ Run execute_change on it in ${configAccount.timelockDelay.toString()}s\n`);
+        }
+      } catch (err: any) {
+        console.error('   ❌ Failed to register solver:', err);
+        throw err;
+      }
+    }
+  }
+
   console.log('✅ Bootstrap complete!\n');
   console.log('📝 Save these addresses:');
   console.log(`   Market: ${marketPda.toString()}`);
@@ -133,6 +133,8 @@ export class RealArciumClient implements ArciumClient {
       filled_amount_in: bigint;
       min_amount_out: bigint;
       created_at: bigint;
+      expires_at: bigint;
+      time_in_force: bigint;
       status: bigint;
     }> = [];
 
@@ -147,12 +149,14 @@ export class RealArciumClient implements ArciumClient {
         filled_amount_in: order.filledAmountIn,
         min_amount_out: order.minAmountOut,
         created_at: BigInt(order.createdAt),
-        status: 0n, // OPEN
+        expires_at: BigInt(order.expiresAt),
+        time_in_force: order.timeInForce === 'IOC' ? 1n : order.timeInForce === 'FOK' ? 2n : 0n,
+        status: order.status === 'PARTIALLY_FILLED' ? 1n : 0n,
       });
     }
 
     // 4. Flatten order data into plaintext array for encryption
-    // Each order is 7 fields (index, side, amount_in, filled_amount_in, min_amount_out, created_at, status)
+    // Each order is 9 fields (index, side, amount_in, filled_amount_in, min_amount_out, created_at, expires_at, time_in_force, status)
     const plaintext: bigint[] = [];
     for (const order of ordersArray) {
       plaintext.push(
@@ -162,6 +166,8 @@ export class RealArciumClient implements ArciumClient {
         order.filled_amount_in,
         order.min_amount_out,
         order.created_at,
+        order.expires_at,
+        order.time_in_force,
         order.status
       );
     }
@@ -284,6 +290,7 @@ export class RealArciumClient implements ArciumClient {
       return {
         ...plan,
         arciumSignature: finalizeTx,
+        computationOffset: computationOffset.toString(),
       };
     } catch (error) {
       console.error('[Arcium] MPC computation failed:', error);
@@ -1,7 +1,7 @@
 export interface AppConfig {
   rpcUrl: string;
   programId: string;
-  adminKeypairPath: string;
+  solverKeypairPath: string;
   port: number;
   arcium: {
     useReal: boolean;
@@ -35,7 +35,7 @@ function getBooleanEnvVar(name: string, defaultValue: boolean): boolean {
 export const config: AppConfig = {
   rpcUrl: getEnvVar('SOLANA_RPC_URL'),
   programId: getEnvVar('DARKPOOL_PROGRAM_ID'),
-  adminKeypairPath: getEnvVar('DARKPOOL_ADMIN_KEYPAIR'),
+  solverKeypairPath: getEnvVar('DARKPOOL_SOLVER_KEYPAIR'),
   port: parseInt(getEnvVarOptional('PORT', '8080'), 10),
   arcium: {
     useReal: getBooleanEnvVar('ARCIUM_USE_REAL', false),
@@ -1,10 +1,15 @@
 export type OrderSide = 'BID' | 'ASK';
 
+export type TimeInForce = 'GTC' | 'IOC' | 'FOK';
+
 export type OrderStatus =
   | 'OPEN'
   | 'PARTIALLY_FILLED'
   | 'FILLED'
-  | 'CANCELLED';
+  | 'CANCELLED'
+  | 'EXPIRED';
+
+export type MarketStatus = 'ACTIVE' | 'POST_ONLY' | 'PAUSED' | 'CLOSED';
 
 export interface Order {
   pubkey: string;
@@ -16,6 +21,9 @@ export interface Order {
   minAmountOut: bigint;
   status: OrderStatus;
   createdAt: bigint;
+  expiresAt: bigint; // 0 = never expires
+  timeInForce: TimeInForce;
+  batchSeq: bigint; // the only batch an IOC order can trade in
 }
 
 export interface Fill {
@@ -32,5 +40,6 @@ export interface ExecutionPlan {
   fills: Fill[];
   createdAt: string;
   arciumSignature: string;
+  computationOffset?: string; // MPC computation that matched the batch, if any
 }
 
@@ -4,12 +4,15 @@ import { ExecutionPlan, Fill, Order } from './domain.js';
  * Order matching algorithm
  *
  * This matcher must stay semantically identical to encrypted-ixs::match_orders in Rust.
- * See encrypted-ixs/MATCHING_SPEC.md for the canonical algorithm specification.
+ * The canonical algorithm is specified in the doc comment on `match_orders`.
  *
  * The Rust implementation in encrypted-ixs/src/matching.rs is the source of truth.
  * Any changes to matching logic must be made there first, then ported here.
  */
-export function matchOrders(orders: Order[]): ExecutionPlan {
+export function matchOrders(
+  orders: Order[],
+  batchTimestamp: bigint = BigInt(Math.floor(Date.now() / 1000))
+): ExecutionPlan {
   if (orders.length === 0) {
     throw new Error('No orders provided');
   }
@@ -22,8 +25,13 @@ export function matchOrders(orders: Order[]): ExecutionPlan {
     }
   }
 
-  // Filter to OPEN orders only
-  const openOrders = orders.filter((o) => o.status === 'OPEN');
+  // Filter to OPEN and PARTIALLY_FILLED orders that have not expired at the
+  // batch timestamp
+  const openOrders = orders.filter(
+    (o) =>
+      (o.status === 'OPEN' || o.status === 'PARTIALLY_FILLED') &&
+      (o.expiresAt === 0n || o.expiresAt > batchTimestamp)
+  );
 
   if (openOrders.length === 0) {
     return {
@@ -61,6 +69,45 @@ export function matchOrders(orders: Order[]): ExecutionPlan {
     remaining.set(order.pubkey, order.amountIn - order.filledAmountIn);
   }
 
+  // Orders that have had a fill; a fill-or-kill order that hasn't is
+  // pending and is only filled if it can be filled completely
+  const committed = new Set<string>();
+  const pending = (order: Order) =>
+    order.timeInForce === 'FOK' && !committed.has(order.pubkey);
+
+  // Whether the greedy steps from here would fill `need` of `bid` against
+  // `asks`, which pass over empty asks and pending FOK asks that don't
+  // exactly match what is left, and skip the bid at the first fill below
+  // its minimum
+  const bidFillable = (bid: Order, need: bigint, asks: Order[]): boolean => {
+    for (const ask of asks) {
+      const available = remaining.get(ask.pubkey) || 0n;
+      if (available === 0n || (pending(ask) && available !== need)) continue;
+      const amount = need < available ? need : available;
+      if (amount < bid.minAmountOut) return false;
+      need -= amount;
+      if (need === 0n) return true;
+    }
+    return false;
+  };
+
+  // Whether the greedy steps from here would fill `need` of an ask against
+  // `bids`, which pass over empty bids and pending FOK bids that don't
+  // exactly match what is left, and skip bids whose minimum the fill
+  // wouldn't meet
+  const askFillable = (need: bigint, bids: Order[]): boolean => {
+    for (const bid of bids) {
+      const available = remaining.get(bid.pubkey) || 0n;
+      if (available === 0n || (pending(bid) && available !== need)) continue;
+      const amount = need < available ? need : available;
+      if (amount >= bid.minAmountOut) {
+        need -= amount;
+        if (need === 0n) return true;
+      }
+    }
+    return false;
+  };
+
   let bidIdx = 0;
   let askIdx = 0;
 
@@ -92,12 +139,38 @@ export function matchOrders(orders: Order[]): ExecutionPlan {
       continue;
     }
 
+    // Unless the two exactly fill each other, a pending FOK ask yields to
+    // any FOK bid, and is passed over unless the bids from here fill it
+    // completely
+    const exact = remainingBid === remainingAsk;
+    if (
+      pending(ask) &&
+      !exact &&
+      (bid.timeInForce === 'FOK' || !askFillable(remainingAsk, bids.slice(bidIdx)))
+    ) {
+      askIdx++;
+      continue;
+    }
+
+    // Likewise a pending FOK bid yields to a committed FOK ask, and is passed
+    // over unless the asks from here fill it completely
+    if (
+      pending(bid) &&
+      !exact &&
+      (ask.timeInForce === 'FOK' || !bidFillable(bid, remainingBid, asks.slice(askIdx)))
+    ) {
+      bidIdx++;
+      continue;
+    }
+
     // Ensure matchAmount meets bid's minimum output requirement
     if (matchAmount < bid.minAmountOut) {
       // This bid can't be filled with current ask, skip to next bid
       bidIdx++;
       continue;
     }
+    committed.add(bid.pubkey);
+    committed.add(ask.pubkey);
 
     // Create fills
     // Bid is buying base with quote, so:
@@ -1,6 +1,7 @@
 import express from 'express';
 import { createArciumClient } from './arciumClient.js';
 import {
+  fetchMarketStatus,
   fetchOpenOrdersForMarket,
   submitExecutionPlan,
   cleanupFilledOrders,
@@ -24,6 +25,16 @@ export function createServer() {
         return res.status(400).json({ error: 'marketPubkey is required' });
       }
 
+      // settle_batch only runs on active markets
+      const marketStatus = await fetchMarketStatus(marketPubkey);
+      if (marketStatus !== 'ACTIVE') {
+        return res.status(200).json({
+          txSignature: null,
+          plan: null,
+          message: `Market is ${marketStatus}, not settling`,
+        });
+      }
+
       const orders = await fetchOpenOrdersForMarket(marketPubkey);
 
       if (!orders.length) {
@@ -61,7 +72,7 @@ export function createServer() {
       })), null, 2));
 
       const plan = await arcium.computeExecutionPlan(orderBatch);
-      const txSignature = await submitExecutionPlan(plan);
+      const txSignature = await submitExecutionPlan(plan, orderBatch);
 
       // Cleanup filled orders
       console.log('Settlement successful, cleaning up filled orders...');
@@ -1,10 +1,11 @@
 import { AnchorProvider, Program, Idl } from '@coral-xyz/anchor';
 import BN from 'bn.js';
 import { Connection, Keypair, PublicKey, AccountMeta } from '@solana/web3.js';
-import { TOKEN_PROGRAM_ID, getAssociatedTokenAddress } from '@solana/spl-token';
+import { getAssociatedTokenAddress } from '@solana/spl-token';
+import { createHash } from 'crypto';
 import fs from 'fs';
 import { config } from './config.js';
-import { ExecutionPlan, Order, OrderStatus, OrderSide } from './domain.js';
+import { ExecutionPlan, MarketStatus, Order, OrderStatus, OrderSide, TimeInForce } from './domain.js';
 
 // Load the generated IDL
 import darkpoolIdl from './idl/darkpool.json' with { type: 'json' };
@@ -15,17 +16,17 @@ export function getProgram(): Program<Idl> {
   if (programSingleton) return programSingleton;
 
   const connection = new Connection(config.rpcUrl, 'confirmed');
-  const secret = JSON.parse(fs.readFileSync(config.adminKeypairPath, 'utf8'));
-  const adminKeypair = Keypair.fromSecretKey(Uint8Array.from(secret));
+  const secret = JSON.parse(fs.readFileSync(config.solverKeypairPath, 'utf8'));
+  const solverKeypair = Keypair.fromSecretKey(Uint8Array.from(secret));
 
   const wallet = {
-    publicKey: adminKeypair.publicKey,
+    publicKey: solverKeypair.publicKey,
     signTransaction: async (tx: any) => {
-      tx.partialSign(adminKeypair);
+      tx.partialSign(solverKeypair);
       return tx;
     },
     signAllTransactions: async (txs: any[]) => {
-      txs.forEach((tx) => tx.partialSign(adminKeypair));
+      txs.forEach((tx) => tx.partialSign(solverKeypair));
       return txs;
     },
   };
@@ -50,20 +51,53 @@ function mapOrderSide(side: any): OrderSide {
   throw new Error(`Unknown order side: ${JSON.stringify(side)}`);
 }
 
+// Helper to map Anchor TimeInForce enum to domain type
+function mapTimeInForce(timeInForce: any): TimeInForce {
+  if (timeInForce.goodTillCancel !== undefined) return 'GTC';
+  if (timeInForce.immediateOrCancel !== undefined) return 'IOC';
+  if (timeInForce.fillOrKill !== undefined) return 'FOK';
+  throw new Error(`Unknown time in force: ${JSON.stringify(timeInForce)}`);
+}
+
 // Helper to map Anchor OrderStatus enum to domain type
 function mapOrderStatus(status: any): OrderStatus {
   if (status.open !== undefined) return 'OPEN';
   if (status.partiallyFilled !== undefined) return 'PARTIALLY_FILLED';
   if (status.filled !== undefined) return 'FILLED';
   if (status.cancelled !== undefined) return 'CANCELLED';
+  if (status.expired !== undefined) return 'EXPIRED';
   throw new Error(`Unknown order status: ${JSON.stringify(status)}`);
 }
 
+// Helper to map Anchor MarketStatus enum to domain type
+function mapMarketStatus(status: any): MarketStatus {
+  if (status.active !== undefined) return 'ACTIVE';
+  if (status.postOnly !== undefined) return 'POST_ONLY';
+  if (status.paused !== undefined) return 'PAUSED';
+  if (status.closed !== undefined) return 'CLOSED';
+  throw new Error(`Unknown market status: ${JSON.stringify(status)}`);
+}
+
+/**
+ * The token program a market's vaults belong to: SPL Token or Token-2022,
+ * whichever owns its mints
+ */
+export async function fetchMarketStatus(
+  marketPubkey: string
+): Promise<MarketStatus> {
+  const program = getProgram();
+  const marketAccount = await (program.account as any).market.fetch(
+    new PublicKey(marketPubkey)
+  );
+  return mapMarketStatus(marketAccount.status);
+}
+
 export async function fetchOpenOrdersForMarket(
   marketPubkey: string
 ): Promise<Order[]> {
   const program = getProgram();
   const marketPk = new PublicKey(marketPubkey);
+  const { nextBatchSeq } = await (program.account as any).market.fetch(marketPk);
 
   let ordersRaw: any[];
   try {
@@ -81,7 +115,15 @@ export async function fetchOpenOrdersForMarket(
       const order = acc.account as any;
       const orderMarket = new PublicKey(order.market);
 
-      if (orderMarket.equals(marketPk) && mapOrderStatus(order.status) === 'OPEN') {
+      // IOC orders can only trade in the first batch opened after they were
+      // placed; once that has passed, only their owner can cancel them
+      const timeInForce = mapTimeInForce(order.timeInForce);
+      const staleIoc = timeInForce === 'IOC' && order.batchSeq.lt(nextBatchSeq);
+
+      const status = mapOrderStatus(order.status);
+      const live = status === 'OPEN' || status === 'PARTIALLY_FILLED';
+
+      if (orderMarket.equals(marketPk) && live && !staleIoc) {
         openOrders.push({
           pubkey: acc.publicKey.toString(),
           owner: new PublicKey(order.owner).toString(),
@@ -90,8 +132,11 @@ export async function fetchOpenOrdersForMarket(
           amountIn: BigInt(order.amountIn.toString()),
           filledAmountIn: BigInt(order.filledAmountIn.toString()),
           minAmountOut: BigInt(order.minAmountOut.toString()),
-          status: mapOrderStatus(order.status),
+          status,
           createdAt: BigInt(order.createdAt.toString()),
+          expiresAt: BigInt(order.expiresAt.toString()),
+          timeInForce,
+          batchSeq: BigInt(order.batchSeq.toString()),
         });
       }
     } catch (error: any) {
@@ -104,8 +149,28 @@ export async function fetchOpenOrdersForMarket(
   return openOrders;
 }
 
+/**
+ * Orders published per commit_batch_inputs transaction, which keeps each
+ * one well under the transaction size limit
+ */
+const BATCH_INPUTS_PER_TX = 20;
+
+/**
+ * Commitment to the orders a batch was matched over: SHA-256 of their
+ * pubkeys in matching order. commit_batch_inputs must publish exactly these
+ * orders before the batch can settle
+ */
+export function orderCommitment(orderPubkeys: string[]): Buffer {
+  const hash = createHash('sha256');
+  for (const pubkey of orderPubkeys) {
+    hash.update(new PublicKey(pubkey).toBuffer());
+  }
+  return hash.digest();
+}
+
 export async function submitExecutionPlan(
-  plan: ExecutionPlan
+  plan: ExecutionPlan,
+  orders: Order[]
 ): Promise<string> {
   const program = getProgram();
   const marketPk = new PublicKey(plan.market);
@@ -114,8 +179,12 @@ export async function submitExecutionPlan(
   const marketAccount = await (program.account as any).market.fetch(marketPk);
   const baseVault = new PublicKey(marketAccount.baseVault);
   const quoteVault = new PublicKey(marketAccount.quoteVault);
+  const baseFeeVault = new PublicKey(marketAccount.baseFeeVault);
+  const quoteFeeVault = new PublicKey(marketAccount.quoteFeeVault);
   const baseMint = new PublicKey(marketAccount.baseMint);
   const quoteMint = new PublicKey(marketAccount.quoteMint);
+  const baseTokenProgram = new PublicKey(marketAccount.baseTokenProgram);
+  const quoteTokenProgram = new PublicKey(marketAccount.quoteTokenProgram);
 
   // Get config PDA
   const [configPda] = PublicKey.findProgramAddressSync(
@@ -132,11 +201,14 @@ export async function submitExecutionPlan(
   }));
 
   // Build remaining accounts array
-  // Order: [base_vault, quote_vault, market, token_program, then for each fill:
-  //  order_account, counterparty_order_account, order_owner_base_account, 
-  //  order_owner_quote_account, counterparty_owner_base_account, counterparty_owner_quote_account]
+  // Order: [base_vault, quote_vault, market, base_token_program,
+  //  quote_token_program, base_fee_vault, quote_fee_vault, base_mint, quote_mint, then every order in the fills
+  //  once, in order of first appearance, then the batch's IOC orders that
+  //  no fill touches, then for each order owner:
+  //  owner, owner_base_account, owner_quote_account, owner_trader_account]
+  // Settlement nets transfers per owner, so each account appears only once
   const remainingAccounts: AccountMeta[] = [
-    // First 4 accounts: vaults, market, token_program
+    // First 9 accounts: vaults, market, token programs, fee vaults, mints
     {
       pubkey: baseVault,
       isSigner: false,
@@ -153,79 +225,171 @@ export async function submitExecutionPlan(
       isWritable: false,
     },
     {
-      pubkey: TOKEN_PROGRAM_ID,
+      pubkey: baseTokenProgram,
+      isSigner: false,
+      isWritable: false,
+    },
+    {
+      pubkey: quoteTokenProgram,
+      isSigner: false,
+      isWritable: false,
+    },
+    {
+      pubkey: baseFeeVault,
+      isSigner: false,
+      isWritable: true,
+    },
+    {
+      pubkey: quoteFeeVault,
+      isSigner: false,
+      isWritable: true,
+    },
+    {
+      pubkey: baseMint,
+      isSigner: false,
+      isWritable: false,
+    },
+    {
+      pubkey: quoteMint,
       isSigner: false,
       isWritable: false,
     },
   ];
 
+  // Use pre-populated owner addresses from the execution plan
+  // This avoids fetching potentially incompatible order accounts
+  const orderKeys: string[] = [];
+  const owners: string[] = [];
   for (const fill of plan.fills) {
-    // Use pre-populated owner addresses from the execution plan
-    // This avoids fetching potentially incompatible order accounts
-    const orderOwner = new PublicKey(fill.orderOwner);
-    const counterpartyOwner = new PublicKey(fill.counterpartyOwner);
-
-    // Derive owner token accounts
-    const orderOwnerBase = await getAssociatedTokenAddress(
-      baseMint,
-      orderOwner
-    );
-    const orderOwnerQuote = await getAssociatedTokenAddress(
-      quoteMint,
-      orderOwner
-    );
-    const counterpartyOwnerBase = await getAssociatedTokenAddress(
-      baseMint,
-      counterpartyOwner
-    );
-    const counterpartyOwnerQuote = await getAssociatedTokenAddress(
-      quoteMint,
-      counterpartyOwner
+    for (const order of [fill.order, fill.counterparty]) {
+      if (!orderKeys.includes(order)) orderKeys.push(order);
+    }
+    for (const owner of [fill.orderOwner, fill.counterpartyOwner]) {
+      if (!owners.includes(owner)) owners.push(owner);
+    }
+  }
+
+  // settle_batch refunds every IOC order of the batch, filled or not
+  for (const order of orders) {
+    if (order.timeInForce === 'IOC' && !orderKeys.includes(order.pubkey)) {
+      orderKeys.push(order.pubkey);
+      if (!owners.includes(order.owner)) owners.push(order.owner);
+    }
+  }
+
+  for (const order of orderKeys) {
+    remainingAccounts.push({
+      pubkey: new PublicKey(order),
+      isSigner: false,
+      isWritable: true,
+    });
+  }
+
+  for (const ownerStr of owners) {
+    const owner = new PublicKey(ownerStr);
+
+    // Trader accounts track each owner's open orders and locked amounts
+    const [trader] = PublicKey.findProgramAddressSync(
+      [Buffer.from('trader'), marketPk.toBuffer(), owner.toBuffer()],
+      program.programId
     );
 
-    // Add accounts in the order expected by settle_batch
     remainingAccounts.push(
+      // Owner wallets receive the rent of orders closed by settlement
       {
-        pubkey: new PublicKey(fill.order),
-        isSigner: false,
-        isWritable: true,
-      },
-      {
-        pubkey: new PublicKey(fill.counterparty),
-        isSigner: false,
-        isWritable: true,
-      },
-      {
-        pubkey: orderOwnerBase,
+        pubkey: owner,
         isSigner: false,
         isWritable: true,
       },
       {
-        pubkey: orderOwnerQuote,
+        pubkey: await getAssociatedTokenAddress(baseMint, owner, false, baseTokenProgram),
         isSigner: false,
         isWritable: true,
       },
       {
-        pubkey: counterpartyOwnerBase,
+        pubkey: await getAssociatedTokenAddress(quoteMint, owner, false, quoteTokenProgram),
         isSigner: false,
         isWritable: true,
       },
       {
-        pubkey: counterpartyOwnerQuote,
+        pubkey: trader,
         isSigner: false,
         isWritable: true,
       }
     );
   }
 
+  const solver = (program.provider as AnchorProvider).wallet.publicKey;
+
+  // The bond is only required once the config sets a minimum bond
+  const [solverBond] = PublicKey.findProgramAddressSync(
+    [Buffer.from('bond'), solver.toBuffer()],
+    program.programId
+  );
+  const bondInfo = await program.provider.connection.getAccountInfo(solverBond);
+
+  // Open the market's next batch; settlement consumes it exactly once
+  const seqBytes = marketAccount.nextBatchSeq.toArrayLike(Buffer, 'le', 8);
+  const [batch] = PublicKey.findProgramAddressSync(
+    [Buffer.from('batch'), marketPk.toBuffer(), seqBytes],
+    program.programId
+  );
+  const [batchInputs] = PublicKey.findProgramAddressSync(
+    [Buffer.from('inputs'), marketPk.toBuffer(), seqBytes],
+    program.programId
+  );
+  await program.methods
+    .openBatch(
+      Array.from(orderCommitment(orders.map((o) => o.pubkey))),
+      new BN(plan.computationOffset ?? '0')
+    )
+    .accounts({
+      config: configPda,
+      solver,
+      market: marketPk,
+      batch,
+      batchInputs,
+    })
+    .rpc();
+
+  // Publish the orders the batch was matched over, which settlement requires
+  // and challenges are checked against
+  for (let i = 0; i < orders.length; i += BATCH_INPUTS_PER_TX) {
+    await program.methods
+      .commitBatchInputs()
+      .accounts({
+        solver,
+        market: marketPk,
+        batch,
+        batchInputs,
+      })
+      .remainingAccounts(
+        orders.slice(i, i + BATCH_INPUTS_PER_TX).map((o) => ({
+          pubkey: new PublicKey(o.pubkey),
+          isSigner: false,
+          isWritable: false,
+        }))
+      )
+      .rpc();
+  }
+
+  // Every settlement is recorded so it can be challenged
+  const [settlementRecord] = PublicKey.findProgramAddressSync(
+    [Buffer.from('settlement'), marketPk.toBuffer(), seqBytes],
+    program.programId
+  );
+
   // Call settle_batch
-  // Note: vaults, market, and token_program are now in remaining_accounts
+  // Note: vaults, market, and token programs are now in remaining_accounts
   const txSig = await program.methods
     .settleBatch(anchorFills, Buffer.from(plan.arciumSignature, 'utf8'))
     .accounts({
       config: configPda,
-      admin: (program.provider as AnchorProvider).wallet.publicKey,
+      solver,
       market: marketPk, // Still needed for validation, but also in remaining_accounts
+      batch,
+      solverBond: bondInfo ? solverBond : null,
+      settlementRecord,
     })
     .remainingAccounts(remainingAccounts)
     .rpc();
@@ -249,6 +413,8 @@ export async function cleanupFilledOrders(
   const quoteMint = new PublicKey(marketAccount.quoteMint);
   const baseVault = new PublicKey(marketAccount.baseVault);
   const quoteVault = new PublicKey(marketAccount.quoteVault);
+  const baseTokenProgram = new PublicKey(marketAccount.baseTokenProgram);
+  const quoteTokenProgram = new PublicKey(marketAccount.quoteTokenProgram);
 
   console.log(`🧹 Cleaning up ${plan.fills.length} filled orders...`);
 
@@ -276,14 +442,23 @@ export async function cleanupFilledOrders(
       // Order is fully filled, close it
       const ownerPubkey = new PublicKey(orderAccount.owner);
 
+      const [traderAccount] = PublicKey.findProgramAddressSync(
+        [Buffer.from('trader'), marketPk.toBuffer(), ownerPubkey.toBuffer()],
+        program.programId
+      );
+
       // Get user token accounts
       const userBaseAccount = await getAssociatedTokenAddress(
         baseMint,
-        ownerPubkey
+        ownerPubkey,
+        false,
+        baseTokenProgram
       );
       const userQuoteAccount = await getAssociatedTokenAddress(
         quoteMint,
-        ownerPubkey
+        ownerPubkey,
+        false,
+        quoteTokenProgram
       );
 
       await program.methods
@@ -292,11 +467,15 @@ export async function cleanupFilledOrders(
           order: orderPubkey,
           owner: ownerPubkey,
           market: marketPk,
+          traderAccount,
           userBaseAccount,
           userQuoteAccount,
           baseVault,
           quoteVault,
-          tokenProgram: TOKEN_PROGRAM_ID,
+          baseMint,
+          quoteMint,
+          baseTokenProgram,
+          quoteTokenProgram,
         })
         .rpc();
 
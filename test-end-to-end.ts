@@ -110,9 +110,30 @@ async function testEndToEnd() {
     process.exit(1);
   }
 
+  // Order nonces come from the wallet's trader account for this market
+  const [traderPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from('trader'), marketPDA.toBuffer(), wallet.publicKey.toBuffer()],
+    program.programId
+  );
+  let trader = await (program.account as any).traderAccount.fetchNullable(traderPDA);
+  if (!trader) {
+    console.log('   Creating trader account...');
+    await program.methods
+      .initTraderAccount()
+      .accounts({
+        traderAccount: traderPDA,
+        owner: wallet.publicKey,
+        market: marketPDA,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    trader = await (program.account as any).traderAccount.fetch(traderPDA);
+  }
+  const nextNonce = new BN(trader.nextNonce.toString());
+
   // Place BID order (buy 10 TOKEN1 with 10 TOKEN2 at 1:1 price)
   console.log('3️⃣  Placing BID order (buying TOKEN1 with TOKEN2)...');
-  const bidNonce = Date.now();
+  const bidNonce = nextNonce;
   const bidAmountIn = new BN(10); // 10 TOKEN2 to spend
   const bidMinOut = new BN(10); // Expect at least 10 TOKEN1 in return (1:1 price)
 
@@ -121,7 +142,7 @@ async function testEndToEnd() {
       Buffer.from('order'),
       marketPDA.toBuffer(),
       wallet.publicKey.toBuffer(),
-      Buffer.from(new BN(bidNonce).toArray('le', 8))
+      bidNonce.toArrayLike(Buffer, 'le', 8)
     ],
     program.programId
   );
@@ -132,17 +153,21 @@ async function testEndToEnd() {
         { bid: {} }, // OrderSide::Bid
         bidAmountIn,
         bidMinOut,
-        new BN(bidNonce)
+        bidNonce,
+        new BN(0), // never expires
+        { goodTillCancel: {} }
       )
       .accounts({
         order: bidOrderPDA,
         owner: wallet.publicKey,
         market: marketPDA,
+        traderAccount: traderPDA,
         userBaseAccount: userBaseAccount,
         userQuoteAccount: userQuoteAccount,
         baseVault: baseVaultPDA,
         quoteVault: quoteVaultPDA,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .rpc();
@@ -161,7 +186,7 @@ async function testEndToEnd() {
 
   // Place ASK order (sell 10 TOKEN1 for 10 TOKEN2 at 1:1 price)
   console.log('4️⃣  Placing ASK order (selling TOKEN1 for TOKEN2)...');
-  const askNonce = Date.now() + 1;
+  const askNonce = nextNonce.addn(1);
   const askAmountIn = new BN(10); // 10 TOKEN1 to sell
   const askMinOut = new BN(10); // Expect at least 10 TOKEN2 in return (1:1 price)
 
@@ -170,7 +195,7 @@ async function testEndToEnd() {
       Buffer.from('order'),
       marketPDA.toBuffer(),
       wallet.publicKey.toBuffer(),
-      Buffer.from(new BN(askNonce).toArray('le', 8))
+      askNonce.toArrayLike(Buffer, 'le', 8)
     ],
     program.programId
   );
@@ -181,17 +206,21 @@ async function testEndToEnd() {
         { ask: {} }, // OrderSide::Ask
         askAmountIn,
         askMinOut,
-        new BN(askNonce)
+        askNonce,
+        new BN(0), // never expires
+        { goodTillCancel: {} }
       )
       .accounts({
         order: askOrderPDA,
         owner: wallet.publicKey,
         market: marketPDA,
+        traderAccount: traderPDA,
         userBaseAccount: userBaseAccount,
         userQuoteAccount: userQuoteAccount,
         baseVault: baseVaultPDA,
         quoteVault: quoteVaultPDA,
-        tokenProgram: TOKEN_PROGRAM_ID,
+        baseTokenProgram: TOKEN_PROGRAM_ID,
+        quoteTokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .rpc();